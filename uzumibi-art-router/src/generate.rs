@@ -0,0 +1,131 @@
+//! Path generation from route patterns.
+//!
+//! This is the reverse of matching in [`crate::store`]: `:name` segments
+//! and the trailing `*` wildcard are filled in from parameters, so a
+//! generated path always matches the pattern it was built from.
+
+/// Returns the parameter names of a pattern in order.
+/// The wildcard is reported as `"*"`.
+///
+/// # Example
+/// ```
+/// use uzumibi_art_router::generate::param_names;
+///
+/// assert_eq!(param_names("/users/:id/files/*"), vec!["id", "*"]);
+/// ```
+pub fn param_names(pattern: &str) -> Vec<String> {
+    pattern
+        .split('/')
+        .filter_map(|segment| {
+            if segment == "*" {
+                Some("*".to_string())
+            } else {
+                segment.strip_prefix(':').map(|name| name.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Builds a path from a pattern, looking parameters up with `param`.
+/// Parameter values are percent-encoded; a wildcard value keeps its `/`
+/// separators. Returns the name of the first missing parameter as the error.
+///
+/// # Example
+/// ```
+/// use uzumibi_art_router::generate::build_path;
+///
+/// let path = build_path("/users/:id/files/*", |name| match name {
+///     "id" => Some("42".to_string()),
+///     "*" => Some("docs/a b.txt".to_string()),
+///     _ => None,
+/// });
+/// assert_eq!(path, Ok("/users/42/files/docs/a%20b.txt".to_string()));
+/// ```
+pub fn build_path<F>(pattern: &str, mut param: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut segments = Vec::new();
+    for segment in pattern.split('/').filter(|s| !s.is_empty()) {
+        if segment == "*" {
+            let value = param("*").ok_or_else(|| "*".to_string())?;
+            for part in value.split('/').filter(|s| !s.is_empty()) {
+                segments.push(percent_encode(part));
+            }
+            // Nothing after a wildcard can match
+            break;
+        } else if let Some(name) = segment.strip_prefix(':') {
+            let value = param(name).ok_or_else(|| name.to_string())?;
+            segments.push(percent_encode(&value));
+        } else {
+            segments.push(segment.to_string());
+        }
+    }
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// Percent-encodes everything except RFC 3986 unreserved characters.
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Route, RouteStore};
+
+    fn lookup<'a>(params: &'a [(&'a str, &'a str)]) -> impl FnMut(&str) -> Option<String> + 'a {
+        move |name| {
+            params
+                .iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_build_static_path() {
+        assert_eq!(build_path("/about", lookup(&[])), Ok("/about".to_string()));
+        assert_eq!(build_path("/", lookup(&[])), Ok("/".to_string()));
+    }
+
+    #[test]
+    fn test_build_path_with_params() {
+        let path = build_path(
+            "/users/:id/posts/:post_id",
+            lookup(&[("id", "1"), ("post_id", "a/b")]),
+        );
+        assert_eq!(path, Ok("/users/1/posts/a%2Fb".to_string()));
+    }
+
+    #[test]
+    fn test_build_path_missing_param() {
+        assert_eq!(build_path("/users/:id", lookup(&[])), Err("id".to_string()));
+    }
+
+    #[test]
+    fn test_generated_path_matches_pattern() {
+        let store = RouteStore::new();
+        store.insert("/users/:id", Route::new("user"));
+        store.insert("/static/*", Route::new("static"));
+
+        let path = build_path("/users/:id", lookup(&[("id", "42")])).unwrap();
+        let (handler, params) = store.get_with_params(&path);
+        assert_eq!(handler, Some("user"));
+        assert_eq!(params.get("id").unwrap(), "42");
+
+        let path = build_path("/static/*", lookup(&[("*", "css/site.css")])).unwrap();
+        let (handler, params) = store.get_with_params(&path);
+        assert_eq!(handler, Some("static"));
+        assert_eq!(params.get("*").unwrap(), "css/site.css");
+    }
+}
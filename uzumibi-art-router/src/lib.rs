@@ -1,3 +1,4 @@
+pub mod generate;
 pub mod store;
 pub mod vendor_art_tree;
 
@@ -169,21 +170,15 @@ fn uzumibi_art_router_get_route(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<
     let store = store_any
         .downcast_ref::<store::RouteStore<Rc<RObject>>>()
         .ok_or_else(|| Error::RuntimeError("Failed to downcast RouteStore".to_string()))?;
+    // The stored value is returned as given to set_route, Proc or not
     if let (Some(route), params) = store.get_with_params(&path) {
-        match &route.value {
-            RValue::Proc(_) => {
-                let hash = mrb_hash_new(vm, &[])?;
-                for (k, v) in params.iter() {
-                    let key = RObject::symbol(RSym::new(k.to_owned())).to_refcount_assigned();
-                    let value = RObject::string(v.to_owned()).to_refcount_assigned();
-                    mrb_hash_set_index(hash.clone(), key, value)?;
-                }
-                Ok(RObject::array(vec![route.clone(), hash]).to_refcount_assigned())
-            }
-            _ => Err(Error::RuntimeError(
-                "Route is not a callable Proc".to_string(),
-            )),
+        let hash = mrb_hash_new(vm, &[])?;
+        for (k, v) in params.iter() {
+            let key = RObject::symbol(RSym::new(k.to_owned())).to_refcount_assigned();
+            let value = RObject::string(v.to_owned()).to_refcount_assigned();
+            mrb_hash_set_index(hash.clone(), key, value)?;
         }
+        Ok(RObject::array(vec![route.clone(), hash]).to_refcount_assigned())
     } else {
         Ok(RObject::array(vec![]).to_refcount_assigned())
     }
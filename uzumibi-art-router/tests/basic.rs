@@ -58,6 +58,23 @@ fn test_art_router_match_simple() -> Result<(), mrubyedge::Error> {
     Ok(())
 }
 
+#[test]
+fn test_art_router_match_value() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    art = Uzumibi::ArtRouter.new
+    art.set_route "/items/:id", [:show, :edit]
+    route, params = *art.get_route("/items/7")
+    "#{route.size} #{params[:id]}"
+    "##;
+    let mut vm = compile_vm(code)?;
+    let ret = vm
+        .run()
+        .map_err(|e| mrubyedge::Error::RuntimeError(format!("Failed to run script: {}", e)))?;
+    let ret: String = ret.as_ref().try_into()?;
+    assert_eq!(ret, "2 7");
+    Ok(())
+}
+
 #[test]
 fn test_art_router_match_params() -> Result<(), mrubyedge::Error> {
     let code = r#"
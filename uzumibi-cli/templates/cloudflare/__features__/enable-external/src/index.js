@@ -19,6 +19,115 @@ export class UzumibiKVObject extends DurableObject {
     }
 }
 
+/**
+ * Takes the chunks of a streamed body out of wasm memory with repeated
+ * `uzumibi_next_chunk` calls. Cloudflare buffers streamed bodies: the
+ * block has already run to completion, so the chunks are copied into
+ * one Blob before another request can reuse the wasm instance.
+ */
+async function takeBufferedBody(exports) {
+    const chunks = [];
+    for (;;) {
+        const next = await exports.uzumibi_next_chunk();
+        if (next === 0n) {
+            break;
+        }
+        const ptr = Number(next & 0xFFFFFFFFn);
+        const size = Number((next >> 32n) & 0xFFFFFFFFn);
+        chunks.push(new Uint8Array(exports.memory.buffer, ptr, size).slice());
+    }
+    return new Blob(chunks);
+}
+
+/**
+ * Accepts the WebSocket the app accepted as `id`. Its events are fed into
+ * wasm one at a time through `uzumibi_websocket_event`, and the messages
+ * the app sends in reply are written to the socket.
+ */
+function acceptWebSocket(exports, id) {
+    const [client, server] = Object.values(new WebSocketPair());
+    server.accept();
+
+    const encoder = new TextEncoder();
+    const decoder = new TextDecoder();
+    let closed = false;
+    let pending = Promise.resolve();
+
+    // Close payload: u16 little-endian code, then the reason
+    const closePayload = (code, reason) => {
+        const reasonBytes = encoder.encode(reason ?? "");
+        const payload = new Uint8Array(2 + reasonBytes.length);
+        new DataView(payload.buffer).setUint16(0, code, true);
+        payload.set(reasonBytes, 2);
+        return payload;
+    };
+
+    // Outgoing messages: u16 count, then (u8 kind, u32 size, bytes) * count,
+    // all little-endian. Kinds are 1 text, 2 binary and 3 close.
+    const sendMessages = (ptr) => {
+        const view = new DataView(exports.memory.buffer, ptr);
+        const count = view.getUint16(0, true);
+        let pos = 2;
+        for (let i = 0; i < count; i++) {
+            const kind = view.getUint8(pos);
+            const size = view.getUint32(pos + 1, true);
+            pos += 5;
+            const bytes = new Uint8Array(exports.memory.buffer, ptr + pos, size).slice();
+            pos += size;
+            if (kind === 1) {
+                server.send(decoder.decode(bytes));
+            } else if (kind === 2) {
+                server.send(bytes);
+            } else if (kind === 3) {
+                closed = true;
+                const code = size >= 2 ? bytes[0] | (bytes[1] << 8) : 1000;
+                server.close(code, decoder.decode(bytes.subarray(2)));
+            }
+        }
+    };
+
+    const deliver = (kind, payload) => {
+        pending = pending.then(async () => {
+            if (closed) {
+                return;
+            }
+            closed = kind === 3;
+            const ptr = await exports.uzumibi_websocket_buffer(payload.length);
+            new Uint8Array(exports.memory.buffer, ptr, payload.length).set(payload);
+            const result = await exports.uzumibi_websocket_event(id, kind);
+            const outPtr = Number(result & 0xFFFFFFFFn);
+            const errPtr = Number((result >> 32n) & 0xFFFFFFFFn);
+            if (errPtr !== 0) {
+                const buffer = new Uint8Array(exports.memory.buffer, errPtr);
+                let errStr = "";
+                for (let i = 0; buffer[i] !== 0; i++) {
+                    errStr += String.fromCharCode(buffer[i]);
+                }
+                console.error(`WebSocket ${id}: ${errStr}`);
+                if (!closed) {
+                    closed = true;
+                    server.close(1011, "Internal Error");
+                }
+                return;
+            }
+            sendMessages(outPtr);
+        });
+    };
+
+    server.addEventListener("message", (event) => {
+        if (typeof event.data === "string") {
+            deliver(1, encoder.encode(event.data));
+        } else {
+            deliver(2, new Uint8Array(event.data));
+        }
+    });
+    server.addEventListener("close", (event) => deliver(3, closePayload(event.code, event.reason)));
+    server.addEventListener("error", () => deliver(3, closePayload(1006, "")));
+    deliver(0, new Uint8Array(0));
+
+    return new Response(null, { status: 101, webSocket: client });
+}
+
 export default {
     async fetch(request, env, ctx) {
         const path = new URL(request.url).pathname;
@@ -58,7 +167,7 @@ export default {
                     const url = decoder.decode(new Uint8Array(memory.buffer, urlPtr, urlSize));
                     const method = decoder.decode(new Uint8Array(memory.buffer, methodPtr, methodSize));
                     const body = bodySize > 0
-                        ? decoder.decode(new Uint8Array(memory.buffer, bodyPtr, bodySize))
+                        ? new Uint8Array(memory.buffer, bodyPtr, bodySize).slice()
                         : null;
 
                     const fetchOptions = { method };
@@ -71,7 +180,7 @@ export default {
                         const hView = new DataView(memory.buffer, headersPtr, headersSize);
                         const hCount = hView.getUint16(0, true);
                         if (hCount > 0) {
-                            const reqHeaders = {};
+                            const reqHeaders = new Headers();
                             let hPos = 2;
                             for (let i = 0; i < hCount; i++) {
                                 const kLen = hView.getUint16(hPos, true);
@@ -82,14 +191,14 @@ export default {
                                 hPos += 2;
                                 const v = decoder.decode(new Uint8Array(memory.buffer, headersPtr + hPos, vLen));
                                 hPos += vLen;
-                                reqHeaders[k] = v;
+                                reqHeaders.append(k, v);
                             }
                             fetchOptions.headers = reqHeaders;
                         }
                     }
 
                     const response = await fetch(url, fetchOptions);
-                    const responseBody = await response.text();
+                    const bodyBytes = new Uint8Array(await response.arrayBuffer());
 
                     // Collect response headers
                     const respHeaders = [];
@@ -126,14 +235,15 @@ export default {
                     }
 
                     // Body size (u32 LE)
-                    const bodyBytes = encoder.encode(responseBody);
+                    if (pos + 4 + bodyBytes.length > resultMaxSize) {
+                        return -2;
+                    }
                     resultView.setUint32(pos, bodyBytes.length, true);
                     pos += 4;
 
                     // Body
-                    const bodyLen = Math.min(bodyBytes.length, resultMaxSize - pos);
-                    resultBuffer.set(bodyBytes.slice(0, bodyLen), pos);
-                    pos += bodyLen;
+                    resultBuffer.set(bodyBytes, pos);
+                    pos += bodyBytes.length;
 
                     return pos;
                 },
@@ -227,6 +337,7 @@ export default {
 
         const instance = await instantiate(wasmModule, importObject);
         const exports = instance.exports;
+        await exports.uzumibi_seed(...crypto.getRandomValues(new Uint32Array(2)));
 
         try {
             await writeRequestToWasm(exports, request);
@@ -241,7 +352,14 @@ export default {
         const resOffset = Number(resResult & 0xFFFFFFFFn);
         const upperBits = Number((resResult >> 32n) & 0xFFFFFFFFn);
 
-        if (upperBits !== 0) {
+        // The route accepted a WebSocket; the lower bits carry its id
+        if (upperBits === 0xFEFF0002) {
+            return acceptWebSocket(exports, resOffset);
+        }
+
+        // Streamed body: the response below carries only status and headers
+        const streamed = upperBits === 0xFEFF0001;
+        if (upperBits !== 0 && !streamed) {
             const upperTag = (upperBits >> 16) & 0xFFFF;
             if (upperTag === 0xFEFF) {
                 // Special route
@@ -295,7 +413,11 @@ export default {
             resPos += valueSize;
 
             console.log(`[Response Header] ${key}: ${value}`);
-            responseHeaders.set(key, value);
+            responseHeaders.append(key, value);
+        }
+
+        if (streamed) {
+            return new Response(await takeBufferedBody(exports), { status: statusCode, headers: responseHeaders });
         }
 
         // Body size (u32 little-endian)
@@ -304,8 +426,9 @@ export default {
 
         // Body
         const bodyBuffer = new Uint8Array(exports.memory.buffer, resOffset + resPos, bodySize);
-        const responseText = decoder.decode(bodyBuffer);
+        // Copy the bytes out: the WASM memory is reused by the next request
+        const responseBody = bodyBuffer.slice();
 
-        return new Response(responseText, { status: statusCode, headers: responseHeaders });
+        return new Response(responseBody, { status: statusCode, headers: responseHeaders });
     }
 };
@@ -62,7 +62,7 @@ export default {
                     const url = decoder.decode(new Uint8Array(memory.buffer, urlPtr, urlSize));
                     const method = decoder.decode(new Uint8Array(memory.buffer, methodPtr, methodSize));
                     const body = bodySize > 0
-                        ? decoder.decode(new Uint8Array(memory.buffer, bodyPtr, bodySize))
+                        ? new Uint8Array(memory.buffer, bodyPtr, bodySize).slice()
                         : null;
 
                     const fetchOptions = { method };
@@ -75,7 +75,7 @@ export default {
                         const hView = new DataView(memory.buffer, headersPtr, headersSize);
                         const hCount = hView.getUint16(0, true);
                         if (hCount > 0) {
-                            const reqHeaders = {};
+                            const reqHeaders = new Headers();
                             let hPos = 2;
                             for (let i = 0; i < hCount; i++) {
                                 const kLen = hView.getUint16(hPos, true);
@@ -86,14 +86,14 @@ export default {
                                 hPos += 2;
                                 const v = decoder.decode(new Uint8Array(memory.buffer, headersPtr + hPos, vLen));
                                 hPos += vLen;
-                                reqHeaders[k] = v;
+                                reqHeaders.append(k, v);
                             }
                             fetchOptions.headers = reqHeaders;
                         }
                     }
 
                     const response = await fetch(url, fetchOptions);
-                    const responseBody = await response.text();
+                    const bodyBytes = new Uint8Array(await response.arrayBuffer());
 
                     const respHeaders = [];
                     response.headers.forEach((value, key) => {
@@ -124,13 +124,14 @@ export default {
                         pos += valueBytes.length;
                     }
 
-                    const bodyBytes = encoder.encode(responseBody);
+                    if (pos + 4 + bodyBytes.length > resultMaxSize) {
+                        return -2;
+                    }
                     resultView.setUint32(pos, bodyBytes.length, true);
                     pos += 4;
 
-                    const bodyLen = Math.min(bodyBytes.length, resultMaxSize - pos);
-                    resultBuffer.set(bodyBytes.slice(0, bodyLen), pos);
-                    pos += bodyLen;
+                    resultBuffer.set(bodyBytes, pos);
+                    pos += bodyBytes.length;
 
                     return pos;
                 },
@@ -234,6 +235,7 @@ export default {
 
         const instance = await instantiate(wasmModule, importObject);
         const exports = instance.exports;
+        await exports.uzumibi_seed(...crypto.getRandomValues(new Uint32Array(2)));
 
         for (const message of batch.messages) {
             const idBytes = encoder.encode(message.id);
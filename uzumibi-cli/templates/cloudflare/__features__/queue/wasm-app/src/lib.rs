@@ -3,7 +3,11 @@ extern crate mrubyedge;
 extern crate uzumibi_cloudflare_ext;
 extern crate uzumibi_gem;
 
-use std::mem::MaybeUninit;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem::MaybeUninit,
+    rc::Rc,
+};
 
 use mrubyedge::{
     rite::rite,
@@ -13,6 +17,14 @@ use mrubyedge::{
         vm::VM,
     },
 };
+use uzumibi_gem::{
+    isolation::VmSnapshot,
+    streaming::{uzumibi_response_is_streaming, uzumibi_response_stream},
+    websocket::{
+        WebSocketMessage, uzumibi_response_websocket, uzumibi_websocket_open,
+        uzumibi_websocket_receive,
+    },
+};
 
 include!(concat!(env!("OUT_DIR"), "/uzumibi_config.rs"));
 
@@ -23,9 +35,44 @@ static MRB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/consumer.mrb"));
 
 static mut MRUBY_VM: MaybeUninit<VM> = MaybeUninit::uninit();
 static mut MRUBY_VM_LOADED: bool = false;
+static mut MRUBY_VM_SNAPSHOT: Option<VmSnapshot> = None;
 
 static mut ERROR_BUF: [u8; 4096] = [0; 4096];
 
+/// Routing bits telling the Worker to read the body with `uzumibi_next_chunk`.
+const STREAMED_BODY: u64 = 0xFEFF0001;
+
+/// Chunks of the last streamed body not yet taken by the Worker.
+static mut STREAM_CHUNKS: VecDeque<Vec<u8>> = VecDeque::new();
+/// The chunk handed out last, kept alive until the next call.
+static mut CURRENT_CHUNK: Vec<u8> = Vec::new();
+
+/// Routing bits telling the Worker that the route accepted a WebSocket;
+/// the lower bits carry its id.
+const WEBSOCKET_ACCEPTED: u64 = 0xFEFF0002;
+/// Event kinds of `uzumibi_websocket_event` for an opened and a closed
+/// connection; messages use the kinds of `WebSocketMessage::unpack`.
+const WEBSOCKET_OPEN: u32 = 0;
+const WEBSOCKET_CLOSE: u32 = 3;
+
+/// Open WebSockets by id. They outlive the request that accepted them and
+/// the VM resets between events.
+static mut WEBSOCKETS: BTreeMap<u32, Rc<RObject>> = BTreeMap::new();
+static mut NEXT_WEBSOCKET_ID: u32 = 1;
+/// The payload of the next WebSocket event, written by the Worker.
+static mut WEBSOCKET_INBOX: Vec<u8> = Vec::new();
+/// The messages packed for the Worker by the last event.
+static mut WEBSOCKET_OUTBOX: Vec<u8> = Vec::new();
+
+/// What `start_request` produced.
+enum Started {
+    Response(*mut u8),
+    /// A packed response whose body is queued for `uzumibi_next_chunk`.
+    Streamed(*mut u8),
+    /// The id of an accepted WebSocket.
+    WebSocket(u32),
+}
+
 fn set_error_to_buf(message: impl AsRef<str>) -> *const u8 {
     unsafe {
         let bytes = message.as_ref().as_bytes();
@@ -57,11 +104,26 @@ fn assume_init_vm() -> Result<&'static mut VM, mrubyedge::Error> {
         if !MRUBY_VM_LOADED {
             MRUBY_VM = MaybeUninit::new(init_vm()?);
             MRUBY_VM_LOADED = true;
+            // `isolate_requests false` in the app leaves this unset
+            MRUBY_VM_SNAPSHOT = VmSnapshot::capture(MRUBY_VM.assume_init_mut())?;
         }
         Ok(MRUBY_VM.assume_init_mut())
     }
 }
 
+/// Puts the VM back into its state right after `init_vm`, so that nothing
+/// a request leaves in globals, constants or ivars reaches the next one.
+fn reset_vm_state() {
+    unsafe {
+        if !MRUBY_VM_LOADED {
+            return;
+        }
+        if let Some(snapshot) = MRUBY_VM_SNAPSHOT.as_ref() {
+            snapshot.restore(MRUBY_VM.assume_init_mut());
+        }
+    }
+}
+
 fn do_uzumibi_initialize_request(size: i32) -> Result<*mut u8, mrubyedge::Error> {
     if size <= 0 || size as u32 > HTTP_MAX_BYTES {
         return Err(mrubyedge::Error::RuntimeError(format!(
@@ -83,27 +145,60 @@ extern "C" fn uzumibi_http_max_bytes() -> u32 {
     HTTP_MAX_BYTES
 }
 
-fn do_uzumibi_start_request() -> Result<*mut u8, mrubyedge::Error> {
+/// Returns the packed response, or the id of the WebSocket the route
+/// accepted. The chunks of a streamed body are queued for
+/// `uzumibi_next_chunk` and the packed response carries an empty body.
+/// Cloudflare buffers streamed bodies: the block runs to completion
+/// here, before the Worker sends any of the body.
+fn do_uzumibi_start_request() -> Result<Started, mrubyedge::Error> {
     uzumibi_cloudflare_ext::debug_console_log_internal("uzumibi_start_request called");
     let vm = assume_init_vm()?;
     let app = vm
         .globals
         .get("$APP")
         .ok_or_else(|| mrubyedge::Error::RuntimeError("$APP is not defined".to_string()))?;
-    let ret = mrb_funcall(
-        vm,
-        app.clone().into(),
-        "start_request_and_return_shared_memory",
-        &[],
-    )?;
+    let response = mrb_funcall(vm, app.clone().into(), "start_request", &[])?;
+    if let Some(websocket) = uzumibi_response_websocket(&response) {
+        unsafe {
+            let id = NEXT_WEBSOCKET_ID;
+            NEXT_WEBSOCKET_ID = NEXT_WEBSOCKET_ID.wrapping_add(1).max(1);
+            WEBSOCKETS.insert(id, websocket);
+            return Ok(Started::WebSocket(id));
+        }
+    }
+    let streamed = uzumibi_response_is_streaming(&response);
+    if streamed {
+        unsafe {
+            STREAM_CHUNKS.clear();
+        }
+        uzumibi_response_stream(
+            vm,
+            &response,
+            Box::new(|chunk| {
+                unsafe {
+                    STREAM_CHUNKS.push_back(chunk.to_vec());
+                }
+                Ok(())
+            }),
+        )?;
+    }
+    let ret = mrb_funcall(vm, Some(response), "to_shared_memory", &[])?;
     match &ret.as_ref().value {
-        RValue::SharedMemory(sm) => Ok(sm.borrow_mut().leak()),
+        RValue::SharedMemory(sm) if streamed => Ok(Started::Streamed(sm.borrow_mut().leak())),
+        RValue::SharedMemory(sm) => Ok(Started::Response(sm.borrow_mut().leak())),
         _ => Err(mrubyedge::Error::RuntimeError(
             "Returned value is not SharedMemory".to_string(),
         )),
     }
 }
 
+/// Seeds request ids with randomness from the host; `RandomState` is
+/// fixed on wasm32-unknown-unknown.
+#[unsafe(export_name = "uzumibi_seed")]
+extern "C" fn uzumibi_seed(lo: u32, hi: u32) {
+    uzumibi_gem::error_handlers::uzumibi_seed_request_ids(((hi as u64) << 32) | lo as u64);
+}
+
 #[unsafe(export_name = "uzumibi_initialize_request")]
 unsafe extern "C" fn uzumibi_initialize_request(size: i32) -> u64 {
     match do_uzumibi_initialize_request(size) {
@@ -117,8 +212,13 @@ unsafe extern "C" fn uzumibi_initialize_request(size: i32) -> u64 {
 
 #[unsafe(export_name = "uzumibi_start_request")]
 unsafe extern "C" fn uzumibi_start_request() -> u64 {
-    match do_uzumibi_start_request() {
-        Ok(ptr) => (ptr as u32) as u64,
+    // The response buffer is leaked, so it outlives the reset
+    let result = do_uzumibi_start_request();
+    reset_vm_state();
+    match result {
+        Ok(Started::Response(ptr)) => (ptr as u32) as u64,
+        Ok(Started::Streamed(ptr)) => (STREAMED_BODY << 32) | (ptr as u32) as u64,
+        Ok(Started::WebSocket(id)) => (WEBSOCKET_ACCEPTED << 32) | id as u64,
         Err(mrubyedge::Error::TaggedError("UzumibiPassAssets", _)) => {
             uzumibi_cloudflare_ext::PASS_ASSETS << 32
         }
@@ -129,6 +229,79 @@ unsafe extern "C" fn uzumibi_start_request() -> u64 {
     }
 }
 
+/// Hands the next chunk of a streamed body to the Worker as
+/// `(size << 32) | ptr`, or 0 once the body is complete.
+#[unsafe(export_name = "uzumibi_next_chunk")]
+unsafe extern "C" fn uzumibi_next_chunk() -> u64 {
+    unsafe {
+        CURRENT_CHUNK = STREAM_CHUNKS.pop_front().unwrap_or_default();
+        if CURRENT_CHUNK.is_empty() {
+            return 0;
+        }
+        ((CURRENT_CHUNK.len() as u64) << 32) | (CURRENT_CHUNK.as_ptr() as u32) as u64
+    }
+}
+
+/// Runs one event of a WebSocket and returns the messages the app sent,
+/// packed by `WebSocketMessage::pack_all`. The WebSocket is forgotten once
+/// either side closes it.
+fn do_uzumibi_websocket_event(id: u32, kind: u32) -> Result<*const u8, mrubyedge::Error> {
+    let vm = assume_init_vm()?;
+    let websocket = unsafe { WEBSOCKETS.get(&id).cloned() }
+        .ok_or_else(|| mrubyedge::Error::RuntimeError(format!("WebSocket {id} is closed")))?;
+    let payload = unsafe { std::mem::take(&mut WEBSOCKET_INBOX) };
+    let outgoing = if kind == WEBSOCKET_OPEN {
+        uzumibi_websocket_open(vm, &websocket)
+    } else {
+        WebSocketMessage::unpack(kind as u8, &payload)
+            .and_then(|message| uzumibi_websocket_receive(vm, &websocket, message))
+    };
+    let closed = kind == WEBSOCKET_CLOSE
+        || match &outgoing {
+            Ok(outgoing) => outgoing
+                .iter()
+                .any(|message| matches!(message, WebSocketMessage::Close(..))),
+            Err(_) => true,
+        };
+    if closed {
+        unsafe {
+            WEBSOCKETS.remove(&id);
+        }
+    }
+    unsafe {
+        WEBSOCKET_OUTBOX = WebSocketMessage::pack_all(&outgoing?);
+        Ok(WEBSOCKET_OUTBOX.as_ptr())
+    }
+}
+
+/// Returns a buffer of `size` bytes for the payload of the next WebSocket
+/// event.
+#[unsafe(export_name = "uzumibi_websocket_buffer")]
+unsafe extern "C" fn uzumibi_websocket_buffer(size: u32) -> *mut u8 {
+    unsafe {
+        WEBSOCKET_INBOX = vec![0; size as usize];
+        WEBSOCKET_INBOX.as_mut_ptr()
+    }
+}
+
+/// Delivers an event of WebSocket `id`: 0 when it opened, then 1 for a
+/// text message, 2 for a binary message and 3 for a close, with the
+/// payload written to `uzumibi_websocket_buffer`. Returns a pointer to the
+/// packed outgoing messages, or an error pointer in the upper bits, after
+/// which the Worker should close the socket with 1011.
+#[unsafe(export_name = "uzumibi_websocket_event")]
+unsafe extern "C" fn uzumibi_websocket_event(id: u32, kind: u32) -> u64 {
+    let result = do_uzumibi_websocket_event(id, kind);
+    reset_vm_state();
+    match result {
+        Ok(ptr) => (ptr as u32) as u64,
+        Err(e) => {
+            let err_buf = set_error_to_buf(format!("Error in websocket event: {}", e));
+            ((err_buf as u32) as u64) << 32
+        }
+    }
+}
+
 // ---- Queue message handling (only when queue feature is active) ----
 
 #[cfg(feature = "queue")]
@@ -172,7 +345,9 @@ unsafe extern "C" fn uzumibi_initialize_message(size: i32) -> u64 {
 #[cfg(feature = "queue")]
 #[unsafe(export_name = "uzumibi_start_message")]
 unsafe extern "C" fn uzumibi_start_message() -> u32 {
-    match do_uzumibi_start_message() {
+    let result = do_uzumibi_start_message();
+    reset_vm_state();
+    match result {
         Ok(()) => 0,
         Err(e) => set_error_to_buf(format!("Error in start_message: {}", e)) as u32,
     }
@@ -10,12 +10,37 @@ use time::format_description::BorrowedFormatItem;
 use time::macros::format_description;
 use tokio::net::TcpListener;
 
+#[cfg(not(feature = "queue"))]
+use futures_util::{SinkExt, StreamExt};
+#[cfg(not(feature = "queue"))]
+use hyper::{
+    StatusCode,
+    header::{HeaderValue, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    upgrade::Upgraded,
+};
+#[cfg(not(feature = "queue"))]
+use tokio::sync::mpsc;
+#[cfg(not(feature = "queue"))]
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Message,
+        handshake::derive_accept_key,
+        protocol::{CloseFrame, Role},
+    },
+};
+#[cfg(not(feature = "queue"))]
+use uzumibi::UzumibiBody;
+#[cfg(not(feature = "queue"))]
+use uzumibi_gem::websocket::WebSocketMessage;
 #[cfg(feature = "queue")]
 use uzumibi_google::QueueDispatchResult;
 
 pub mod uzumibi;
 
 const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// How long an idle blocking thread, and the VM cached on it, is kept.
+const VM_KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(600);
 const NGINX_TIME_FORMAT: &[BorrowedFormatItem<'static>] = format_description!(
     "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
 );
@@ -31,9 +56,12 @@ fn now_for_nginx_log() -> String {
         .unwrap_or_else(|_| "-".to_string())
 }
 
+#[cfg(feature = "queue")]
+type UzumibiBody = Full<Bytes>;
+
 async fn uzumibi_request(
     request: Request<IncomingBody>,
-) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+) -> Result<Response<UzumibiBody>, Box<dyn std::error::Error + Send + Sync + 'static>> {
     let method = request.method().clone();
     let uri = request.uri().clone();
     let version = request.version();
@@ -41,10 +69,12 @@ async fn uzumibi_request(
     #[cfg(feature = "queue")]
     {
         let body_bytes: Vec<u8> = request.into_body().collect().await?.to_bytes().to_vec();
+        let slot = uzumibi::acquire_vm_slot().await;
         let result = tokio::task::spawn_blocking(move || {
             uzumibi::uzumibi_dispatch_queue_message(&body_bytes)
         })
         .await;
+        drop(slot);
 
         let (status_code, body_bytes) = match result {
             Ok(QueueDispatchResult::Ack) => (200, Bytes::from_static(b"ok")),
@@ -73,6 +103,11 @@ async fn uzumibi_request(
         Ok(response)
     }
 
+    #[cfg(not(feature = "queue"))]
+    if is_websocket_upgrade(&request) {
+        return uzumibi_websocket_request(request).await;
+    }
+
     #[cfg(not(feature = "queue"))]
     {
         use hyper::body::Body;
@@ -83,10 +118,31 @@ async fn uzumibi_request(
         let body_bytes: Vec<u8> = request.into_body().collect().await?.to_bytes().to_vec();
         uzumibi_request.body = body_bytes;
 
-        let result = tokio::task::spawn_blocking(move || {
-            uzumibi::uzumibi_handle_request(uzumibi_request).map_err(|e| e.to_string())
-        })
-        .await;
+        let slot = uzumibi::acquire_vm_slot().await;
+        let (respond, responded) = tokio::sync::oneshot::channel();
+        let task = tokio::task::spawn_blocking(move || {
+            uzumibi::uzumibi_handle_request(uzumibi_request, respond).map_err(|e| e.to_string())
+        });
+        // A streamed body is still being written when the response
+        // arrives, so the task is awaited only when none was sent. The
+        // slot is held until the thread is free again
+        let result = match responded.await {
+            Ok(response) => {
+                let slot = if matches!(response.body(), UzumibiBody::Right(_)) {
+                    uzumibi::uzumibi_stream_slot(slot)
+                } else {
+                    slot
+                };
+                tokio::task::spawn(async move {
+                    let _ = task.await;
+                    drop(slot);
+                });
+                Ok(Ok(response))
+            }
+            Err(_) => task
+                .await
+                .map(|result| result.and_then(|_| Err("No response was sent".to_string()))),
+        };
 
         let (status_code, body_bytes) = match result {
             Ok(Ok(response)) => {
@@ -99,7 +155,7 @@ async fn uzumibi_request(
                 let status_code = 500;
                 let response = Response::builder()
                     .status(status_code)
-                    .body(Full::new(message.clone()))?;
+                    .body(UzumibiBody::Left(Full::new(message.clone())))?;
                 let (parts, body_full) = response.into_parts();
                 (status_code, (parts, body_full))
             }
@@ -108,7 +164,7 @@ async fn uzumibi_request(
                 let status_code = 500;
                 let response = Response::builder()
                     .status(status_code)
-                    .body(Full::new(message.clone()))?;
+                    .body(UzumibiBody::Left(Full::new(message.clone())))?;
                 let (parts, body_full) = response.into_parts();
                 (status_code, (parts, body_full))
             }
@@ -131,8 +187,157 @@ async fn uzumibi_request(
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+#[cfg(not(feature = "queue"))]
+fn is_websocket_upgrade(request: &Request<IncomingBody>) -> bool {
+    let upgrade = request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    upgrade && request.headers().contains_key(SEC_WEBSOCKET_KEY)
+}
+
+/// Handles a WebSocket upgrade request. The app runs on a thread of its
+/// own for as long as the connection is open; when the route accepts the
+/// upgrade, the connection is handed over to `pump_websocket`.
+#[cfg(not(feature = "queue"))]
+async fn uzumibi_websocket_request(
+    mut request: Request<IncomingBody>,
+) -> Result<Response<UzumibiBody>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let version = request.version();
+    let accept = request
+        .headers()
+        .get(SEC_WEBSOCKET_KEY)
+        .map(|key| derive_accept_key(key.as_bytes()))
+        .unwrap_or_default();
+
+    let uzumibi_request = uzumibi::build_uzumibi_request(&request);
+    let upgrade = hyper::upgrade::on(&mut request);
+    let (respond, responded) = tokio::sync::oneshot::channel();
+    let (events, events_rx) = mpsc::unbounded_channel();
+    let (frames_tx, frames) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        if let Err(e) =
+            uzumibi::uzumibi_handle_websocket(uzumibi_request, respond, events_rx, frames_tx)
+        {
+            eprintln!("[uzumibi] websocket error: {}", e);
+        }
+    });
+
+    let mut response = match responded.await {
+        Ok(response) => response,
+        Err(_) => Response::builder()
+            .status(500)
+            .body(UzumibiBody::Left(Full::new(Bytes::from(
+                "Internal Server Error",
+            ))))?,
+    };
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept)?);
+        tokio::task::spawn(async move {
+            match upgrade.await {
+                Ok(upgraded) => {
+                    let socket = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        None,
+                    )
+                    .await;
+                    pump_websocket(socket, events, frames).await;
+                }
+                Err(e) => eprintln!("[uzumibi] websocket upgrade failed: {:?}", e),
+            }
+        });
+    }
+
+    let now = now_for_nginx_log();
+    eprintln!(
+        "- - - [{}] \"{} {} {:?}\" {} -",
+        now,
+        method,
+        uri,
+        version,
+        response.status().as_u16()
+    );
+    Ok(response)
+}
+
+/// Moves messages between the connection and the app until either side
+/// closes. Messages from the client go to `events`, one at a time; those
+/// the app sends come from `frames`. Pings are answered by tungstenite.
+#[cfg(not(feature = "queue"))]
+async fn pump_websocket(
+    socket: WebSocketStream<TokioIo<Upgraded>>,
+    events: mpsc::UnboundedSender<WebSocketMessage>,
+    mut frames: mpsc::UnboundedReceiver<WebSocketMessage>,
+) {
+    let (mut sink, mut stream) = socket.split();
+    loop {
+        tokio::select! {
+            incoming = stream.next() => {
+                let message = match incoming {
+                    Some(Ok(Message::Text(text))) => WebSocketMessage::Text(text.as_str().to_string()),
+                    Some(Ok(Message::Binary(data))) => WebSocketMessage::Binary(data.to_vec()),
+                    Some(Ok(Message::Close(frame))) => match frame {
+                        Some(frame) => {
+                            WebSocketMessage::Close(frame.code.into(), frame.reason.as_str().to_string())
+                        }
+                        None => WebSocketMessage::Close(1005, String::new()),
+                    },
+                    Some(Ok(_)) => continue,
+                    Some(Err(_)) | None => WebSocketMessage::Close(1006, String::new()),
+                };
+                let closed = matches!(message, WebSocketMessage::Close(..));
+                let _ = events.send(message);
+                if closed {
+                    // Sends the reply to the client's close frame
+                    let _ = sink.close().await;
+                    break;
+                }
+            }
+            outgoing = frames.recv() => {
+                let message = match outgoing {
+                    Some(WebSocketMessage::Text(text)) => Message::text(text),
+                    Some(WebSocketMessage::Binary(data)) => Message::binary(data),
+                    Some(WebSocketMessage::Close(code, reason)) => Message::Close(Some(CloseFrame {
+                        code: code.into(),
+                        reason: reason.into(),
+                    })),
+                    // The app stopped, for example after an error
+                    None => {
+                        let _ = sink.close().await;
+                        break;
+                    }
+                };
+                let closing = matches!(message, Message::Close(_));
+                if sink.send(message).await.is_err() || closing {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    // Requests run on blocking threads that each cache one VM. The VM pool
+    // bounds the requests running the app, and open streams get threads
+    // of their own on top of it
+    let pool_size = uzumibi::vm_pool_size();
+    if pool_size > 0 {
+        runtime
+            .max_blocking_threads(pool_size + uzumibi::max_streams())
+            .thread_keep_alive(VM_KEEP_ALIVE);
+    }
+    runtime.build()?.block_on(serve())
+}
+
+async fn serve() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let addr: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
 
@@ -152,6 +357,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 tokio::task::spawn(async move {
                     if let Err(err) = http1::Builder::new()
                         .serve_connection(io, service_fn(uzumibi_request))
+                        .with_upgrades()
                         .await
                     {
                         eprintln!("error serving connection: {:?}", err);
@@ -3,15 +3,25 @@ extern crate uzumibi_gem;
 #[cfg(feature = "enable-external")]
 extern crate uzumibi_google;
 
-use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{Arc, LazyLock},
+};
 
 #[cfg(not(feature = "queue"))]
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 #[cfg(not(feature = "queue"))]
-use http_body_util::Full;
+use http_body_util::{Either, Full};
 #[cfg(not(feature = "queue"))]
-use hyper::body::Bytes;
+use hyper::body::{Body, Bytes, Frame};
 #[cfg(not(feature = "queue"))]
 use hyper::{Request, Response, body::Incoming as IncomingBody};
 #[cfg(not(feature = "queue"))]
@@ -24,12 +34,158 @@ use mrubyedge::{
         vm::VM,
     },
 };
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+#[cfg(not(feature = "queue"))]
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Sleep,
+};
+use uzumibi_gem::isolation::VmSnapshot;
+#[cfg(not(feature = "queue"))]
+use uzumibi_gem::{
+    response::{uzumibi_response_body_bytes, uzumibi_response_header_pairs},
+    sse::{HEARTBEAT, uzumibi_response_heartbeat_interval},
+    streaming::{uzumibi_response_is_streaming, uzumibi_response_stream},
+    websocket::{
+        WebSocketMessage, uzumibi_response_websocket, uzumibi_websocket_open,
+        uzumibi_websocket_receive,
+    },
+};
 
 #[cfg(not(feature = "queue"))]
 static MRB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/app.mrb"));
 #[cfg(feature = "queue")]
 static MRB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/consumer.mrb"));
 
+/// Environment variable for the number of cached VMs. `0` builds a new
+/// VM for every request.
+pub(crate) const VM_POOL_SIZE_ENV: &str = "UZUMIBI_VM_POOL_SIZE";
+
+/// Environment variable for the number of streamed responses that may stay
+/// open without holding a VM of the pool.
+pub(crate) const MAX_STREAMS_ENV: &str = "UZUMIBI_MAX_STREAMS";
+
+/// Smallest default pool. Cloud Run instances often have a single CPU, and
+/// a request waiting on an upstream service keeps its VM meanwhile.
+const MIN_VM_POOL_SIZE: usize = 4;
+
+/// Default number of streamed responses kept off the pool.
+const DEFAULT_MAX_STREAMS: usize = 32;
+
+/// Permits to run the app, one per VM of the pool.
+static VM_SLOTS: LazyLock<Arc<Semaphore>> =
+    LazyLock::new(|| Arc::new(Semaphore::new(vm_pool_size())));
+
+/// Permits for streamed responses that gave their VM slot back.
+#[cfg(not(feature = "queue"))]
+static STREAM_SLOTS: LazyLock<Arc<Semaphore>> =
+    LazyLock::new(|| Arc::new(Semaphore::new(max_streams())));
+
+/// Held for as long as a request keeps its blocking thread. `None` when
+/// the pool is disabled.
+pub(crate) type VmSlot = Option<OwnedSemaphorePermit>;
+
+/// Number of chunks of a streamed body buffered ahead of the client.
+#[cfg(not(feature = "queue"))]
+const STREAM_CHANNEL_CAPACITY: usize = 16;
+
+/// A response body given in one piece, or streamed chunk by chunk.
+#[cfg(not(feature = "queue"))]
+pub(crate) type UzumibiBody = Either<Full<Bytes>, ChunkBody>;
+
+/// Body of a streamed response. The chunks come from the blocking thread
+/// running the app; an error ends the body and aborts the response.
+/// For Server-Sent Events, a heartbeat comment is sent whenever no chunk
+/// arrived for the heartbeat interval. It keeps the connection open but
+/// does not reach the app, which holds its thread until the block returns.
+#[cfg(not(feature = "queue"))]
+pub(crate) struct ChunkBody {
+    chunks: mpsc::Receiver<Result<Bytes, String>>,
+    heartbeat: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+#[cfg(not(feature = "queue"))]
+impl Body for ChunkBody {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let body = self.get_mut();
+        if let Poll::Ready(chunk) = body.chunks.poll_recv(cx) {
+            body.idle = None;
+            return Poll::Ready(chunk.map(|chunk| chunk.map(Frame::data).map_err(Into::into)));
+        }
+        let Some(interval) = body.heartbeat else {
+            return Poll::Pending;
+        };
+        let idle = body
+            .idle
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(interval)));
+        if idle.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        body.idle = None;
+        Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(HEARTBEAT)))))
+    }
+}
+
+thread_local! {
+    // VM is not `Send`, so each blocking thread keeps its own VM together
+    // with the state to restore after every request.
+    static CACHED_VM: RefCell<Option<(VM, Option<VmSnapshot>)>> = const { RefCell::new(None) };
+}
+
+/// Number of requests that run the app at once, each on a blocking thread
+/// with its cached VM. Defaults to the number of available CPUs, but at
+/// least [`MIN_VM_POOL_SIZE`].
+pub(crate) fn vm_pool_size() -> usize {
+    std::env::var(VM_POOL_SIZE_ENV)
+        .ok()
+        .and_then(|size| size.trim().parse().ok())
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+                .max(MIN_VM_POOL_SIZE)
+        })
+}
+
+/// Number of streamed responses that may stay open beside the pool.
+pub(crate) fn max_streams() -> usize {
+    std::env::var(MAX_STREAMS_ENV)
+        .ok()
+        .and_then(|size| size.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_STREAMS)
+}
+
+/// Waits until the pool has a free VM. Without a pool every request builds
+/// its own VM, so nothing is waited for.
+pub(crate) async fn acquire_vm_slot() -> VmSlot {
+    if vm_pool_size() == 0 {
+        return None;
+    }
+    VM_SLOTS.clone().acquire_owned().await.ok()
+}
+
+/// Trades the VM slot of a streamed response for a stream slot. The block
+/// keeps its thread until the body ends, but no longer counts against the
+/// pool, so open streams do not keep other requests waiting. Once
+/// `UZUMIBI_MAX_STREAMS` streams are open, further ones keep their VM slot.
+#[cfg(not(feature = "queue"))]
+pub(crate) fn uzumibi_stream_slot(slot: VmSlot) -> VmSlot {
+    if slot.is_none() {
+        return None;
+    }
+    match STREAM_SLOTS.clone().try_acquire_owned() {
+        Ok(stream) => Some(stream),
+        Err(_) => slot,
+    }
+}
+
 fn debug_console_log_internal(message: &str) {
     println!("{}", message);
 }
@@ -66,19 +222,119 @@ fn init_vm() -> Result<VM, mrubyedge::Error> {
     Ok(vm)
 }
 
+/// Runs `f` with the VM cached on the current thread, initializing it on
+/// first use. The VM state is restored afterwards unless the app opted out
+/// with `isolate_requests false`.
+fn with_cached_vm<T>(f: impl FnOnce(&mut VM) -> T) -> Result<T, mrubyedge::Error> {
+    if vm_pool_size() == 0 {
+        let mut vm = init_vm()?;
+        return Ok(f(&mut vm));
+    }
+    CACHED_VM.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        if cached.is_none() {
+            let mut vm = init_vm()?;
+            let snapshot = VmSnapshot::capture(&mut vm)?;
+            *cached = Some((vm, snapshot));
+        }
+        let (vm, snapshot) = cached.as_mut().expect("VM is cached above");
+        let result = f(vm);
+        if let Some(snapshot) = snapshot {
+            snapshot.restore(vm);
+        }
+        Ok(result)
+    })
+}
+
 #[cfg(feature = "queue")]
 pub(crate) fn uzumibi_dispatch_queue_message(buf: &[u8]) -> uzumibi_google::QueueDispatchResult {
-    match init_vm() {
-        Ok(mut vm) => uzumibi_google::dispatch_queue_message(&mut vm, buf),
+    match with_cached_vm(|vm| uzumibi_google::dispatch_queue_message(vm, buf)) {
+        Ok(result) => result,
         Err(e) => uzumibi_google::QueueDispatchResult::InternalError(e.to_string()),
     }
 }
 
+/// Runs the request and sends the response through `respond`. A streamed
+/// body is written after the response is sent, while the VM is still held.
 #[cfg(not(feature = "queue"))]
 pub(crate) fn uzumibi_handle_request(
     request: uzumibi_gem::request::Request,
-) -> Result<Response<Full<Bytes>>, mrubyedge::error::StaticError> {
+    respond: oneshot::Sender<Response<UzumibiBody>>,
+) -> Result<(), mrubyedge::error::StaticError> {
+    with_cached_vm(|vm| {
+        let response_robject = uzumibi_start(vm, request)?;
+        if uzumibi_response_websocket(&response_robject).is_some() {
+            // The route accepted an upgrade the request cannot complete,
+            // such as one without `Sec-WebSocket-Key`
+            let response = Response::builder()
+                .status(400)
+                .body(Either::Left(Full::new(Bytes::from_static(b"Bad Request"))))
+                .map_err(|e| StaticError::General(format!("{}", e)))?;
+            let _ = respond.send(response);
+            return Ok(());
+        }
+        uzumibi_respond(vm, response_robject, respond)
+    })?
+}
+
+/// Runs a WebSocket upgrade request. Once the route accepts it, events
+/// from the connection are run one at a time until either side closes,
+/// and the messages the app sends go out through `frames`.
+/// The connection outlives the request, so it gets a VM of its own
+/// instead of holding one of the cached VMs.
+#[cfg(not(feature = "queue"))]
+pub(crate) fn uzumibi_handle_websocket(
+    request: uzumibi_gem::request::Request,
+    respond: oneshot::Sender<Response<UzumibiBody>>,
+    mut events: mpsc::UnboundedReceiver<WebSocketMessage>,
+    frames: mpsc::UnboundedSender<WebSocketMessage>,
+) -> Result<(), mrubyedge::error::StaticError> {
     let mut vm = init_vm()?;
+    let vm = &mut vm;
+    let response_robject = uzumibi_start(vm, request)?;
+    let Some(websocket) = uzumibi_response_websocket(&response_robject) else {
+        return uzumibi_respond(vm, response_robject, respond);
+    };
+    let response =
+        build_response_from_robject(vm, response_robject, Either::Left(Full::new(Bytes::new())))?;
+    let _ = respond.send(response);
+
+    let mut result = uzumibi_websocket_open(vm, &websocket);
+    loop {
+        let outgoing = match result {
+            Ok(outgoing) => outgoing,
+            Err(e) => {
+                let _ = frames.send(WebSocketMessage::Close(1011, String::new()));
+                return Err(e.into());
+            }
+        };
+        let closing = outgoing
+            .iter()
+            .any(|message| matches!(message, WebSocketMessage::Close(..)));
+        for message in outgoing {
+            let _ = frames.send(message);
+        }
+        if closing {
+            return Ok(());
+        }
+        // The connection task drops its sender when the connection is gone
+        let message = events
+            .blocking_recv()
+            .unwrap_or(WebSocketMessage::Close(1006, String::new()));
+        if matches!(message, WebSocketMessage::Close(..)) {
+            uzumibi_websocket_receive(vm, &websocket, message)?;
+            return Ok(());
+        }
+        result = uzumibi_websocket_receive(vm, &websocket, message);
+    }
+}
+
+/// Runs the app for a request and returns its response object.
+#[cfg(not(feature = "queue"))]
+fn uzumibi_start(
+    vm: &mut VM,
+    request: uzumibi_gem::request::Request,
+) -> Result<Rc<RObject>, mrubyedge::error::StaticError> {
     let app = vm
         .globals
         .get("$APP")
@@ -87,15 +343,55 @@ pub(crate) fn uzumibi_handle_request(
             mrubyedge::error::StaticError::General("$APP is not defined".into())
         })?
         .clone();
-    let request_robject = request.into_robject(&mut vm);
-    mrb_funcall(
-        &mut vm,
-        Some(app.clone()),
-        "set_request",
-        &[request_robject],
+    let request_robject = request.into_robject(vm);
+    mrb_funcall(vm, Some(app.clone()), "set_request", &[request_robject])?;
+    Ok(mrb_funcall(vm, Some(app), "start_request", &[])?)
+}
+
+/// Sends a response through `respond`, then writes its streamed body.
+#[cfg(not(feature = "queue"))]
+fn uzumibi_respond(
+    vm: &mut VM,
+    response_robject: Rc<RObject>,
+    respond: oneshot::Sender<Response<UzumibiBody>>,
+) -> Result<(), mrubyedge::error::StaticError> {
+    if !uzumibi_response_is_streaming(&response_robject) {
+        let body = uzumibi_response_body_bytes(vm, &response_robject)?;
+        let response = build_response_from_robject(
+            vm,
+            response_robject,
+            Either::Left(Full::new(Bytes::from(body))),
+        )?;
+        let _ = respond.send(response);
+        return Ok(());
+    }
+
+    let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+    let response = build_response_from_robject(
+        vm,
+        response_robject.clone(),
+        Either::Right(ChunkBody {
+            chunks: rx,
+            heartbeat: uzumibi_response_heartbeat_interval(&response_robject)
+                .map(Duration::from_secs),
+            idle: None,
+        }),
     )?;
-    let response_robject = mrb_funcall(&mut vm, Some(app.clone()), "start_request", &[])?;
-    build_response_from_robject(&mut vm, response_robject)
+    let _ = respond.send(response);
+    let sink = tx.clone();
+    let result = uzumibi_response_stream(
+        vm,
+        &response_robject,
+        Box::new(move |chunk| {
+            sink.blocking_send(Ok(Bytes::copy_from_slice(chunk)))
+                .map_err(|_| mrubyedge::Error::RuntimeError("Client disconnected".to_string()))
+        }),
+    );
+    if let Err(e) = &result {
+        let _ = tx.blocking_send(Err(e.to_string()));
+    }
+    result?;
+    Ok(())
 }
 
 #[cfg(not(feature = "queue"))]
@@ -111,7 +407,7 @@ pub(crate) fn build_uzumibi_request(
             let value_str = v.to_str().unwrap_or_default();
             (k.as_str().to_string(), value_str.to_string())
         })
-        .collect::<HashMap<String, String>>();
+        .collect::<Vec<_>>();
     let query_string = request.uri().query().unwrap_or_default().to_string();
 
     uzumibi_gem::request::Request {
@@ -128,30 +424,20 @@ pub(crate) fn build_uzumibi_request(
 pub(crate) fn build_response_from_robject(
     vm: &mut VM,
     response: Rc<RObject>,
-) -> Result<Response<Full<Bytes>>, mrubyedge::error::StaticError> {
+    body: UzumibiBody,
+) -> Result<Response<UzumibiBody>, mrubyedge::error::StaticError> {
     let status_code: u32 = {
         let status_obj = mrb_funcall(vm, response.clone().into(), "status_code", &[])?;
         status_obj.as_ref().try_into()?
     };
-    let headers: Vec<_> = {
-        let headers_obj = mrb_funcall(vm, response.clone().into(), "headers", &[])?;
-        headers_obj.as_ref().try_into()?
-    };
-    let body = {
-        let body_obj = mrb_funcall(vm, response.clone().into(), "body", &[])?;
-        let body_str: String = body_obj.as_ref().try_into()?;
-        body_str.into_bytes()
-    };
-
+    let headers = uzumibi_response_header_pairs(&response)?;
     let builder = Response::builder();
     let mut response = builder.status(status_code as u16);
     for (key, value) in headers {
-        let key: String = key.as_ref().try_into()?;
-        let value: String = value.as_ref().try_into()?;
         response = response.header(&key, &value);
     }
     let res = response
-        .body(Full::new(Bytes::from(body)))
+        .body(body)
         .map_err(|e| StaticError::General(format!("{}", e)))?;
     Ok(res)
 }
@@ -5,7 +5,7 @@ extern crate mrubyedge;
 extern crate mrubyedge_serde_json;
 extern crate uzumibi_gem;
 
-use std::{collections::HashMap, mem::MaybeUninit, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, io::Write, mem::MaybeUninit, rc::Rc};
 
 use mrubyedge::{
     rite::rite,
@@ -15,11 +15,17 @@ use mrubyedge::{
         vm::VM,
     },
 };
+use uzumibi_gem::{
+    isolation::VmSnapshot,
+    response::{uzumibi_response_body_bytes, uzumibi_response_header_pairs},
+    streaming::{uzumibi_response_is_streaming, uzumibi_response_stream},
+};
 
 static MRB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/app.mrb"));
 
 static mut MRUBY_VM: MaybeUninit<VM> = MaybeUninit::uninit();
 static mut MRUBY_VM_LOADED: bool = false;
+static mut MRUBY_VM_SNAPSHOT: Option<VmSnapshot> = None;
 
 fn debug_console_log_internal(message: &str) {
     log::info!("{}", message);
@@ -63,11 +69,26 @@ fn assume_init_vm() -> Result<&'static mut VM, mrubyedge::Error> {
         if !MRUBY_VM_LOADED {
             MRUBY_VM = MaybeUninit::new(init_vm()?);
             MRUBY_VM_LOADED = true;
+            // `isolate_requests false` in the app leaves this unset
+            MRUBY_VM_SNAPSHOT = VmSnapshot::capture(MRUBY_VM.assume_init_mut())?;
         }
         Ok(MRUBY_VM.assume_init_mut())
     }
 }
 
+/// Puts the VM back into its state right after `init_vm`, so that nothing
+/// a request leaves in globals, constants or ivars reaches the next one.
+fn reset_vm_state() {
+    unsafe {
+        if !MRUBY_VM_LOADED {
+            return;
+        }
+        if let Some(snapshot) = MRUBY_VM_SNAPSHOT.as_ref() {
+            snapshot.restore(MRUBY_VM.assume_init_mut());
+        }
+    }
+}
+
 pub fn uzumibi_initialize_request(request: fastly::Request) -> Result<(), mrubyedge::Error> {
     let vm = assume_init_vm()?;
     let method = request.get_method_str().to_string();
@@ -78,7 +99,7 @@ pub fn uzumibi_initialize_request(request: fastly::Request) -> Result<(), mrubye
             let value_str = v.to_str().unwrap_or_default();
             (k.as_str().to_string(), value_str.to_string())
         })
-        .collect::<HashMap<String, String>>();
+        .collect::<Vec<_>>();
     let query_string = request.get_url().query().unwrap_or("").to_string();
     let body = request.into_body().into_bytes();
 
@@ -101,17 +122,57 @@ pub fn uzumibi_initialize_request(request: fastly::Request) -> Result<(), mrubye
     Ok(())
 }
 
-pub fn uzumibi_start_request() -> Result<fastly::Response, mrubyedge::Error> {
+/// Runs the request and sends the response to the client.
+pub fn uzumibi_start_request() -> Result<(), mrubyedge::Error> {
     let vm = assume_init_vm()?;
     let app = vm
         .globals
         .get("$APP")
         .ok_or_else(|| mrubyedge::Error::RuntimeError("$APP is not defined".to_string()))?;
-    let ret = mrb_funcall(vm, app.clone().into(), "start_request", &[]).map_err(|e| {
-        debug_console_log_internal(&format!("Error in start_request: {}", e));
-        e
-    })?;
-    robject_as_response(vm, ret)
+    let result = mrb_funcall(vm, app.clone().into(), "start_request", &[])
+        .map_err(|e| {
+            debug_console_log_internal(&format!("Error in start_request: {}", e));
+            e
+        })
+        .and_then(|ret| send_response(vm, ret));
+    reset_vm_state();
+    result
+}
+
+/// Sends the response in one piece, or streams its body with
+/// `stream_to_client` when the app used `res.stream` or an Enumerable body.
+fn send_response(vm: &mut VM, obj: Rc<RObject>) -> Result<(), mrubyedge::Error> {
+    let response = robject_as_response(vm, obj.clone())?;
+    if !uzumibi_response_is_streaming(&obj) {
+        response.send_to_client();
+        return Ok(());
+    }
+
+    let body = Rc::new(RefCell::new(response.stream_to_client()));
+    let sink = body.clone();
+    uzumibi_response_stream(
+        vm,
+        &obj,
+        Box::new(move |chunk| {
+            let mut body = sink.borrow_mut();
+            body.write_all(chunk)
+                .and_then(|_| body.flush())
+                .map_err(|e| {
+                    mrubyedge::Error::RuntimeError(format!("Failed to write chunk: {}", e))
+                })
+        }),
+    )?;
+    // The sink is dropped once streaming ends; an error above leaves the
+    // body unfinished so that the client sees an aborted response
+    match Rc::try_unwrap(body) {
+        Ok(body) => body
+            .into_inner()
+            .finish()
+            .map_err(|e| mrubyedge::Error::RuntimeError(format!("Failed to finish body: {}", e))),
+        Err(_) => Err(mrubyedge::Error::RuntimeError(
+            "Streaming body is still in use".to_string(),
+        )),
+    }
 }
 
 fn robject_as_response(
@@ -122,22 +183,14 @@ fn robject_as_response(
         let status_obj = mrb_funcall(vm, obj.clone().into(), "status_code", &[])?;
         status_obj.as_ref().try_into()?
     };
-    let headers: Vec<_> = {
-        let headers_obj = mrb_funcall(vm, obj.clone().into(), "headers", &[])?;
-        headers_obj.as_ref().try_into()?
-    };
-    let body = {
-        let body_obj = mrb_funcall(vm, obj.clone().into(), "body", &[])?;
-        let body_str: String = body_obj.as_ref().try_into()?;
-        body_str.into_bytes()
-    };
-
+    let headers = uzumibi_response_header_pairs(&obj)?;
     let mut response = fastly::Response::from_status(status_code as u16);
     for (key, value) in headers {
-        let key: String = key.as_ref().try_into()?;
-        let value: String = value.as_ref().try_into()?;
-        response.set_header(key.as_str(), value.as_str());
+        response.append_header(key.as_str(), value.as_str());
+    }
+    // A streamed body is written by `send_response`
+    if !uzumibi_response_is_streaming(&obj) {
+        response.set_body(uzumibi_response_body_bytes(vm, &obj)?);
     }
-    response.set_body(body);
     Ok(response)
 }
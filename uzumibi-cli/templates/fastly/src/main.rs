@@ -1,12 +1,15 @@
 extern crate anyhow;
 
 use anyhow::anyhow;
-use fastly::{Error, Request, Response};
+use fastly::{Error, Request};
 use $$PROJECT_NAME_UNDERSCORE$$ as uzumibi;
 
-#[fastly::main]
-fn main(req: Request) -> Result<Response, Error> {
+// The response is sent from the library, which streams the body when the
+// app asks for it, so this entry point does not return a Response.
+fn main() -> Result<(), Error> {
+    let req = Request::from_client();
     uzumibi::uzumibi_initialize_request(req)
         .map_err(|e| anyhow!("Failed to initialize request: {}\n", e))?;
-    uzumibi::uzumibi_start_request().map_err(|e| anyhow!("Failed to start request: {}\n", e))
+    uzumibi::uzumibi_start_request().map_err(|e| anyhow!("Failed to start request: {}\n", e))?;
+    Ok(())
 }
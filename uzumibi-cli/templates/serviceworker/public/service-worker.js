@@ -26,11 +26,12 @@ async function initWasm() {
     const wasmModule = await WebAssembly.instantiate(await response.arrayBuffer(), importObject);
     console.log('[Service Worker] WASM module loaded and instantiated');
     wasmExports = wasmModule.instance.exports;
+    wasmExports.uzumibi_seed(...crypto.getRandomValues(new Uint32Array(2)));
     return wasmExports;
 }
 
 // Pack request data into WASM memory
-function packRequest(exports, request, url) {
+function packRequest(exports, request, url, body) {
     const reqResult = exports.uzumibi_initialize_request(65536);
     const reqOffset = Number(reqResult & 0xFFFFFFFFn);
     if (reqOffset === 0) {
@@ -106,14 +107,18 @@ function packRequest(exports, request, url) {
         pos += valueBytes.length;
     }
 
-    // Request body size (u32 little-endian) - always 0 for now
-    dataView.setUint32(pos, 0, true);
-    pos += 4;
-
-    if (pos > 65536) {
+    if (pos + 4 + body.length > 65536) {
         throw new Error("Request data exceeds allocated buffer size");
     }
 
+    // Request body size (u32 little-endian)
+    dataView.setUint32(pos, body.length, true);
+    pos += 4;
+
+    // Request body
+    requestBuffer.set(body, pos);
+    pos += body.length;
+
     return reqOffset;
 }
 
@@ -133,7 +138,7 @@ function unpackResponse(exports, resOffset) {
     resPos += 2;
 
     // Parse headers
-    const responseHeaders = {};
+    const responseHeaders = new Headers();
     for (let i = 0; i < headersCount; i++) {
         // Header key size (u16 little-endian)
         const keySize = resDataView.getUint16(resPos, true);
@@ -153,7 +158,7 @@ function unpackResponse(exports, resOffset) {
         const value = decoder.decode(valueBytes);
         resPos += valueSize;
 
-        responseHeaders[key] = value;
+        responseHeaders.append(key, value);
     }
 
     // Body size (u32 little-endian)
@@ -162,9 +167,10 @@ function unpackResponse(exports, resOffset) {
 
     // Body
     const bodyBuffer = new Uint8Array(exports.memory.buffer, resOffset + resPos, bodySize);
-    const responseText = decoder.decode(bodyBuffer);
+    // Copy the bytes out: the WASM memory is reused by the next request
+    const body = bodyBuffer.slice();
 
-    return { statusCode, headers: responseHeaders, body: responseText };
+    return { statusCode, headers: responseHeaders, body };
 }
 
 // Handle request through WASM
@@ -175,7 +181,8 @@ async function handleWithWasm(request) {
     console.log('[Service Worker] Handling request with WASM:', url.pathname);
 
     // Pack request
-    packRequest(exports, request, url);
+    const requestBody = new Uint8Array(await request.arrayBuffer());
+    packRequest(exports, request, url, requestBody);
 
     // Execute WASM
     const resResult = exports.uzumibi_start_request();
@@ -1,15 +1,30 @@
 extern crate anyhow;
 
-use anyhow::anyhow;
-use spin_sdk::http::{IntoResponse, Request};
+use spin_sdk::http::{Request, ResponseOutparam};
 use spin_sdk::http_component;
 
 pub mod uzumibi;
 
 /// A simple Spin HTTP component.
 #[http_component]
-fn handle_uzumibi_on_spin_spike(req: Request) -> anyhow::Result<impl IntoResponse> {
-    uzumibi::uzumibi_initialize_request(req)
-        .map_err(|e| anyhow!("Failed to initialize request: {}\n", e))?;
-    uzumibi::uzumibi_start_request().map_err(|e| anyhow!("Failed to start request: {}\n", e))
+async fn handle_uzumibi_on_spin_spike(req: Request, response_out: ResponseOutparam) {
+    let result = uzumibi::uzumibi_initialize_request(req)
+        .map_err(|e| format!("Failed to initialize request: {}\n", e))
+        .and_then(|_| {
+            uzumibi::uzumibi_start_request()
+                .map_err(|e| format!("Failed to start request: {}\n", e))
+        });
+    let (response, body) = match result {
+        Ok(response) => response,
+        Err(message) => {
+            eprintln!("{}", message);
+            let headers = [("content-type".to_string(), "text/plain".to_string())];
+            let response = uzumibi::outgoing_response(500, &headers)
+                .expect("a plain 500 response is always valid");
+            (response, message.into_bytes())
+        }
+    };
+    if let Err(e) = response_out.set_with_body(response, body).await {
+        eprintln!("Failed to send response: {:?}", e);
+    }
 }
@@ -14,12 +14,17 @@ use mrubyedge::{
         vm::VM,
     },
 };
-use spin_sdk::http::{Request, Response};
+use spin_sdk::http::{Fields, OutgoingResponse, Request};
+use uzumibi_gem::{
+    isolation::VmSnapshot,
+    response::{uzumibi_response_body_bytes, uzumibi_response_header_pairs},
+};
 
 static MRB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/app.mrb"));
 
 static mut MRUBY_VM: MaybeUninit<VM> = MaybeUninit::uninit();
 static mut MRUBY_VM_LOADED: bool = false;
+static mut MRUBY_VM_SNAPSHOT: Option<VmSnapshot> = None;
 
 fn debug_console_log_internal(message: &str) {
     println!("{}", message);
@@ -61,11 +66,26 @@ fn assume_init_vm() -> Result<&'static mut VM, mrubyedge::Error> {
         if !MRUBY_VM_LOADED {
             MRUBY_VM = MaybeUninit::new(init_vm()?);
             MRUBY_VM_LOADED = true;
+            // `isolate_requests false` in the app leaves this unset
+            MRUBY_VM_SNAPSHOT = VmSnapshot::capture(MRUBY_VM.assume_init_mut())?;
         }
         Ok(MRUBY_VM.assume_init_mut())
     }
 }
 
+/// Puts the VM back into its state right after `init_vm`, so that nothing
+/// a request leaves in globals, constants or ivars reaches the next one.
+fn reset_vm_state() {
+    unsafe {
+        if !MRUBY_VM_LOADED {
+            return;
+        }
+        if let Some(snapshot) = MRUBY_VM_SNAPSHOT.as_ref() {
+            snapshot.restore(MRUBY_VM.assume_init_mut());
+        }
+    }
+}
+
 pub fn uzumibi_initialize_request(request: Request) -> Result<(), mrubyedge::Error> {
     let vm = assume_init_vm()?;
     let method = request.method().to_string();
@@ -76,7 +96,7 @@ pub fn uzumibi_initialize_request(request: Request) -> Result<(), mrubyedge::Err
             let value_str = v.as_str().unwrap_or("");
             (k.to_string(), value_str.to_string())
         })
-        .collect::<HashMap<String, String>>();
+        .collect::<Vec<_>>();
     let query_string = request.query().to_string();
     let body = request.into_body();
 
@@ -99,41 +119,55 @@ pub fn uzumibi_initialize_request(request: Request) -> Result<(), mrubyedge::Err
     Ok(())
 }
 
-pub fn uzumibi_start_request() -> Result<Response, mrubyedge::Error> {
+pub fn uzumibi_start_request() -> Result<(OutgoingResponse, Vec<u8>), mrubyedge::Error> {
     let vm = assume_init_vm()?;
     let app = vm
         .globals
         .get("$APP")
         .cloned()
         .ok_or_else(|| mrubyedge::Error::RuntimeError("Failed to get $APP".to_string()))?;
-    let ret = mrb_funcall(vm, app.clone().into(), "start_request", &[]).map_err(|e| {
-        debug_console_log_internal(&format!("Error in start_request: {}", e));
-        e
-    })?;
-    robject_as_response(vm, ret)
+    let result = mrb_funcall(vm, app.clone().into(), "start_request", &[])
+        .map_err(|e| {
+            debug_console_log_internal(&format!("Error in start_request: {}", e));
+            e
+        })
+        .and_then(|ret| robject_as_response(vm, ret));
+    reset_vm_state();
+    result
 }
 
-fn robject_as_response(vm: &mut VM, obj: Rc<RObject>) -> Result<Response, mrubyedge::Error> {
+fn robject_as_response(
+    vm: &mut VM,
+    obj: Rc<RObject>,
+) -> Result<(OutgoingResponse, Vec<u8>), mrubyedge::Error> {
     let status_code: u32 = {
         let status_obj = mrb_funcall(vm, obj.clone().into(), "status_code", &[])?;
         status_obj.as_ref().try_into()?
     };
-    let headers: Vec<_> = {
-        let headers_obj = mrb_funcall(vm, obj.clone().into(), "headers", &[])?;
-        headers_obj.as_ref().try_into()?
-    };
-    let body = {
-        let body_obj = mrb_funcall(vm, obj.clone().into(), "body", &[])?;
-        let body_str: String = body_obj.as_ref().try_into()?;
-        body_str.into_bytes()
-    };
+    let headers = uzumibi_response_header_pairs(&obj)?;
+    // A streamed body is sent in one piece
+    let body = uzumibi_response_body_bytes(vm, &obj)?;
 
-    let mut builder = Response::builder();
-    let mut response = builder.status(status_code as u16);
-    for (key, value) in headers {
-        let key: String = key.as_ref().try_into()?;
-        let value: String = value.as_ref().try_into()?;
-        response = response.header(&key, &value);
-    }
-    Ok(response.body(body).build())
+    Ok((outgoing_response(status_code as u16, &headers)?, body))
+}
+
+/// Builds the status and headers of a response. Spin's `Response` keeps
+/// one value per header name, so the WASI fields are used instead to
+/// send repeated headers such as Set-Cookie.
+pub fn outgoing_response(
+    status_code: u16,
+    headers: &[(String, String)],
+) -> Result<OutgoingResponse, mrubyedge::Error> {
+    let entries: Vec<(String, Vec<u8>)> = headers
+        .iter()
+        .map(|(key, value)| (key.to_lowercase(), value.as_bytes().to_vec()))
+        .collect();
+    let fields = Fields::from_list(&entries).map_err(|e| {
+        mrubyedge::Error::RuntimeError(format!("Invalid response headers: {:?}", e))
+    })?;
+    let response = OutgoingResponse::new(fields);
+    response.set_status_code(status_code).map_err(|_| {
+        mrubyedge::Error::RuntimeError(format!("Invalid status code: {}", status_code))
+    })?;
+    Ok(response)
 }
@@ -27,6 +27,7 @@ async function initWasm() {
     const wasmModule = await WebAssembly.instantiate(await response.arrayBuffer(), importObject);
     console.log('[Worker] WASM module loaded and instantiated');
     wasmExports = wasmModule.instance.exports;
+    wasmExports.uzumibi_seed(...crypto.getRandomValues(new Uint32Array(2)));
     return wasmExports;
 }
 
@@ -45,6 +46,7 @@ function packRequest(exports, request) {
     }
 
     const requestBuffer = new Uint8Array(exports.memory.buffer, reqOffset, 65536);
+    const body = request.body || new Uint8Array(0);
     const encoder = new TextEncoder();
     const dataView = new DataView(exports.memory.buffer, reqOffset);
 
@@ -103,14 +105,18 @@ function packRequest(exports, request) {
         pos += valueBytes.length;
     }
 
-    // Request body size (u32 little-endian) - always 0 for now
-    dataView.setUint32(pos, 0, true);
-    pos += 4;
-
-    if (pos > 65536) {
+    if (pos + 4 + body.length > 65536) {
         throw new Error("Request data exceeds allocated buffer size");
     }
 
+    // Request body size (u32 little-endian)
+    dataView.setUint32(pos, body.length, true);
+    pos += 4;
+
+    // Request body
+    requestBuffer.set(body, pos);
+    pos += body.length;
+
     return reqOffset;
 }
 
@@ -129,8 +135,9 @@ function unpackResponse(exports, resOffset) {
     const headersCount = resDataView.getUint16(resPos, true);
     resPos += 2;
 
-    // Parse headers
-    const responseHeaders = {};
+    // Parse headers into [key, value] pairs, as a repeated header such as
+    // Set-Cookie cannot be a key of a plain object
+    const responseHeaders = [];
     for (let i = 0; i < headersCount; i++) {
         // Header key size (u16 little-endian)
         const keySize = resDataView.getUint16(resPos, true);
@@ -150,7 +157,7 @@ function unpackResponse(exports, resOffset) {
         const value = decoder.decode(valueBytes);
         resPos += valueSize;
 
-        responseHeaders[key] = value;
+        responseHeaders.push([key, value]);
     }
 
     // Body size (u32 little-endian)
@@ -159,9 +166,10 @@ function unpackResponse(exports, resOffset) {
 
     // Body
     const bodyBuffer = new Uint8Array(exports.memory.buffer, resOffset + resPos, bodySize);
-    const responseText = decoder.decode(bodyBuffer);
+    // Copy the bytes out: the WASM memory is reused by the next request
+    const body = bodyBuffer.slice();
 
-    return { statusCode, headers: responseHeaders, body: responseText };
+    return { statusCode, headers: responseHeaders, body };
 }
 
 // Handle request through WASM
@@ -185,7 +193,7 @@ async function handleRequest(request) {
         }
         return {
             statusCode: 500,
-            headers: { 'Content-Type': 'text/plain' },
+            headers: [['Content-Type', 'text/plain']],
             body: `Failed to start request: ${errStr}`
         };
     }
@@ -13,11 +13,13 @@ use mrubyedge::{
         vm::VM,
     },
 };
+use uzumibi_gem::isolation::VmSnapshot;
 
 static MRB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/app.mrb"));
 
 static mut MRUBY_VM: MaybeUninit<VM> = MaybeUninit::uninit();
 static mut MRUBY_VM_LOADED: bool = false;
+static mut MRUBY_VM_SNAPSHOT: Option<VmSnapshot> = None;
 
 static mut ERROR_BUF: [u8; 4096] = [0; 4096];
 
@@ -49,11 +51,26 @@ fn assume_init_vm() -> Result<&'static mut VM, mrubyedge::Error> {
         if !MRUBY_VM_LOADED {
             MRUBY_VM = MaybeUninit::new(init_vm()?);
             MRUBY_VM_LOADED = true;
+            // `isolate_requests false` in the app leaves this unset
+            MRUBY_VM_SNAPSHOT = VmSnapshot::capture(MRUBY_VM.assume_init_mut())?;
         }
         Ok(MRUBY_VM.assume_init_mut())
     }
 }
 
+/// Puts the VM back into its state right after `init_vm`, so that nothing
+/// a request leaves in globals, constants or ivars reaches the next one.
+fn reset_vm_state() {
+    unsafe {
+        if !MRUBY_VM_LOADED {
+            return;
+        }
+        if let Some(snapshot) = MRUBY_VM_SNAPSHOT.as_ref() {
+            snapshot.restore(MRUBY_VM.assume_init_mut());
+        }
+    }
+}
+
 fn do_uzumibi_initialize_request(size: i32) -> Result<*mut u8, mrubyedge::Error> {
     let vm = assume_init_vm()?;
     vm.exception.take(); // Clear any existing exception
@@ -86,6 +103,13 @@ fn do_uzumibi_start_request() -> Result<*mut u8, mrubyedge::Error> {
     }
 }
 
+/// Seeds request ids with randomness from the host; `RandomState` is
+/// fixed on wasm32-unknown-unknown.
+#[unsafe(export_name = "uzumibi_seed")]
+extern "C" fn uzumibi_seed(lo: u32, hi: u32) {
+    uzumibi_gem::error_handlers::uzumibi_seed_request_ids(((hi as u64) << 32) | lo as u64);
+}
+
 #[unsafe(export_name = "uzumibi_initialize_request")]
 unsafe extern "C" fn uzumibi_initialize_request(size: i32) -> u64 {
     // Clear error buffer at the start of each request
@@ -102,7 +126,10 @@ unsafe extern "C" fn uzumibi_initialize_request(size: i32) -> u64 {
 
 #[unsafe(export_name = "uzumibi_start_request")]
 unsafe extern "C" fn uzumibi_start_request() -> u64 {
-    match do_uzumibi_start_request() {
+    // The response buffer is leaked, so it outlives the reset
+    let result = do_uzumibi_start_request();
+    reset_vm_state();
+    match result {
         Ok(ptr) => (ptr as u32) as u64,
         Err(e) => {
             let err_buf = set_error_to_buf(format!("Error in start_request: {}", e));
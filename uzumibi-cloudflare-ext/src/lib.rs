@@ -106,7 +106,7 @@ pub fn debug_console_log_internal(message: &str) {
 ///   u32 LE body_size
 ///   body bytes
 #[cfg(feature = "enable-external")]
-fn cf_fetch(url: &str, method: &str, body: &str, headers: &[u8]) -> Result<Vec<u8>, String> {
+fn cf_fetch(url: &str, method: &str, body: &[u8], headers: &[u8]) -> Result<Vec<u8>, String> {
     const BUFFER_SIZE: usize = 65536;
     let mut buffer = vec![0u8; BUFFER_SIZE];
 
@@ -128,6 +128,10 @@ fn cf_fetch(url: &str, method: &str, body: &str, headers: &[u8]) -> Result<Vec<u
                 let len = len as usize;
                 Ok(buffer[..len].to_vec())
             }
+            -2 => Err(format!(
+                "response does not fit in the {}-byte fetch buffer",
+                BUFFER_SIZE
+            )),
             _ => Err(format!("Fetch failed with return code: {}", result)),
         }
     }
@@ -280,12 +284,18 @@ fn uzumibi_fetch_class_fetch(
         "GET".to_string()
     };
 
+    // Body bytes are passed through unchanged, so binary payloads survive
     let body = if args.len() > 2 {
-        let b = mrb_funcall(vm, args[2].clone().into(), "to_s", &[])?;
-        let b: String = b.as_ref().try_into()?;
-        b
+        let b = match &args[2].value {
+            RValue::String(_, _) => args[2].clone(),
+            _ => mrb_funcall(vm, args[2].clone().into(), "to_s", &[])?,
+        };
+        match &b.value {
+            RValue::String(s, _) => s.borrow().to_vec(),
+            _ => Vec::new(),
+        }
     } else {
-        String::new()
+        Vec::new()
     };
 
     // Pack request headers from Hash (4th argument)
@@ -305,6 +315,7 @@ fn uzumibi_fetch_class_fetch(
 /// Pack a mruby Hash into binary format for request headers:
 ///   u16 LE headers_count
 ///   (u16 LE key_size, key bytes, u16 LE value_size, value bytes) * count
+/// An Array value is packed as one header per element.
 #[cfg(feature = "enable-external")]
 fn pack_headers_from_hash(
     vm: &mut VM,
@@ -312,15 +323,25 @@ fn pack_headers_from_hash(
 ) -> Result<Vec<u8>, mrubyedge::Error> {
     match &hash_obj.as_ref().value {
         RValue::Hash(h) => {
-            let hash = h.borrow();
+            let entries: Vec<_> = h.borrow().iter().map(|(_, pair)| pair.clone()).collect();
+            let mut headers = Vec::new();
+            for (key_obj, value_obj) in entries {
+                let key = mrb_funcall(vm, key_obj.into(), "to_s", &[])?;
+                let key: String = key.as_ref().try_into()?;
+                let values = match &value_obj.value {
+                    RValue::Array(values) => values.borrow().clone(),
+                    _ => vec![value_obj],
+                };
+                for value_obj in values {
+                    let value = mrb_funcall(vm, value_obj.into(), "to_s", &[])?;
+                    let value: String = value.as_ref().try_into()?;
+                    headers.push((key.clone(), value));
+                }
+            }
             let mut buf = Vec::new();
-            let count = hash.len() as u16;
+            let count = headers.len() as u16;
             buf.extend_from_slice(&count.to_le_bytes());
-            for (_, (key_obj, value_obj)) in hash.iter() {
-                let key = mrb_funcall(vm, key_obj.clone().into(), "to_s", &[])?;
-                let key: String = key.as_ref().try_into()?;
-                let value = mrb_funcall(vm, value_obj.clone().into(), "to_s", &[])?;
-                let value: String = value.as_ref().try_into()?;
+            for (key, value) in headers {
                 buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
                 buf.extend_from_slice(key.as_bytes());
                 buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
@@ -350,8 +371,8 @@ fn unpack_response_to_robject(vm: &mut VM, buf: &[u8]) -> Result<Rc<RObject>, mr
     let headers_count = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
     offset += 2;
 
-    // Parse headers
-    let headers_hash = mrb_hash_new(vm, &[])?;
+    // Parse headers; a repeated header becomes an Array of its values
+    let mut headers: Vec<(String, Vec<String>)> = Vec::new();
     for _ in 0..headers_count {
         let key_size = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
         offset += 2;
@@ -363,10 +384,29 @@ fn unpack_response_to_robject(vm: &mut VM, buf: &[u8]) -> Result<Rc<RObject>, mr
         let value = String::from_utf8_lossy(&buf[offset..offset + value_size]).to_string();
         offset += value_size;
 
+        match headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&key))
+        {
+            Some((_, values)) => values.push(value),
+            None => headers.push((key, vec![value])),
+        }
+    }
+    let headers_hash = mrb_hash_new(vm, &[])?;
+    for (key, mut values) in headers {
+        let value = if values.len() == 1 {
+            RObject::string(values.remove(0)).to_refcount_assigned()
+        } else {
+            let values = values
+                .into_iter()
+                .map(|value| RObject::string(value).to_refcount_assigned())
+                .collect();
+            RObject::array(values).to_refcount_assigned()
+        };
         mrb_hash_set_index(
             headers_hash.clone(),
             RObject::string(key).to_refcount_assigned(),
-            RObject::string(value).to_refcount_assigned(),
+            value,
         )?;
     }
 
@@ -380,7 +420,7 @@ fn unpack_response_to_robject(vm: &mut VM, buf: &[u8]) -> Result<Rc<RObject>, mr
     offset += 4;
 
     // Body
-    let body = String::from_utf8_lossy(&buf[offset..offset + body_size]).to_string();
+    let body = buf[offset..offset + body_size].to_vec();
 
     // Create Uzumibi::Response instance
     let uzumibi = vm
@@ -406,7 +446,10 @@ fn unpack_response_to_robject(vm: &mut VM, buf: &[u8]) -> Result<Rc<RObject>, mr
         RObject::integer(status_code as i64).to_refcount_assigned(),
     );
     response.set_ivar("@headers", headers_hash);
-    response.set_ivar("@body", RObject::string(body).to_refcount_assigned());
+    response.set_ivar(
+        "@body",
+        RObject::string_from_vec(body).to_refcount_assigned(),
+    );
 
     Ok(response)
 }
@@ -721,7 +764,7 @@ fn uzumibi_access_get_identity(
     headers_buf.extend_from_slice(&(val.len() as u16).to_le_bytes());
     headers_buf.extend_from_slice(val);
 
-    let packed = cf_fetch(&url, "GET", "", &headers_buf)
+    let packed = cf_fetch(&url, "GET", b"", &headers_buf)
         .map_err(|e| mrubyedge::Error::RuntimeError(format!("Access fetch failed: {}", e)))?;
 
     let body = unpack_response_body(&packed)?;
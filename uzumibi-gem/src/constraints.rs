@@ -0,0 +1,328 @@
+//! This module implements host and header constraints on routes.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.host(pattern: String | Regexp | Array[String]) { () -> untyped } -> nil
+//!       def self.constraints(constraints: Hash[Symbol, untyped]) { () -> untyped } -> nil
+//!       def self.get(path: String, constraints: Hash[Symbol, untyped]?) { (Request, Response) -> untyped } -> String
+//! ```
+//!
+//! A constraints Hash maps `:host`, `:subdomain` or a header name
+//! (`:accept`, `:x_api_version` for `X-Api-Version`) to a String, an Array
+//! of candidates, a Proc called with the value, or any object answering
+//! `===`, such as a Regexp. Host and subdomain compare case-insensitively
+//! and ignore the port.
+//!
+//! Each pattern in the ArtRouter store holds an Array of
+//! `[constraints, handler]` candidates. Candidates with constraints are
+//! tried in definition order; the last one without constraints is the
+//! fallback. When none applies, dispatch moves on to `any` routes,
+//! mounted routers and finally `not_found`.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RSym, RValue},
+        vm::VM,
+    },
+};
+
+use crate::request::uzumibi_request_header;
+
+const CONSTRAINTS_KEY: &str = "@_constraints";
+
+/// A constraint key and pattern, as stored in the constraints Hash.
+type Constraint = (Rc<RObject>, Rc<RObject>);
+
+/// Copies `base` (a Hash or nil) into a new Hash and adds `extra` to it.
+fn uzumibi_constraints_merged(
+    vm: &mut VM,
+    base: &Rc<RObject>,
+    extra: &[Constraint],
+) -> Result<Rc<RObject>, Error> {
+    let merged = mrb_hash_new(vm, &[])?;
+    if let RValue::Hash(h) = &base.value {
+        let entries: Vec<_> = h
+            .borrow()
+            .iter()
+            .map(|(_, (k, v))| (k.clone(), v.clone()))
+            .collect();
+        for (key, value) in entries {
+            mrb_hash_set_index(merged.clone(), key, value)?;
+        }
+    }
+    for (key, value) in extra {
+        let key = match &key.value {
+            RValue::String(_, _) => mrb_funcall(vm, Some(key.clone()), "to_sym", &[])?,
+            _ => key.clone(),
+        };
+        mrb_hash_set_index(merged.clone(), key, value.clone())?;
+    }
+    Ok(merged)
+}
+
+fn uzumibi_hash_entries(hash: &Rc<RObject>) -> Result<Vec<Constraint>, Error> {
+    match &hash.value {
+        RValue::Hash(h) => Ok(h
+            .borrow()
+            .iter()
+            .map(|(_, (k, v))| (k.clone(), v.clone()))
+            .collect()),
+        RValue::Nil => Ok(Vec::new()),
+        _ => Err(Error::ArgumentError(
+            "constraints must be a Hash".to_string(),
+        )),
+    }
+}
+
+/// Runs a block with extra constraints (a Hash) applied to the routes it defines.
+pub(crate) fn uzumibi_router_with_constraints(
+    vm: &mut VM,
+    constraints: &Rc<RObject>,
+    block: Rc<RObject>,
+) -> Result<Rc<RObject>, Error> {
+    let extra = uzumibi_hash_entries(constraints)?;
+    let klass = vm.getself()?;
+    let outer = klass.get_ivar(CONSTRAINTS_KEY);
+    let scoped = uzumibi_constraints_merged(vm, &outer, &extra)?;
+    klass.set_ivar(CONSTRAINTS_KEY, scoped);
+    let result = mrb_funcall(vm, Some(block), "call", &[]);
+    // Restore the outer constraints even when the block raised
+    klass.set_ivar(CONSTRAINTS_KEY, outer);
+    result?;
+
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// host(pattern) { ... } -> nil
+pub(crate) fn uzumibi_router_host(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (pattern, block) = match args {
+        [pattern, block] if block.is_truthy() => (pattern.clone(), block.clone()),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected 2 arguments: host pattern, block".to_string(),
+            ));
+        }
+    };
+    let key = RObject::symbol(RSym::new("host".to_string())).to_refcount_assigned();
+    let constraints = mrb_hash_new(vm, &[])?;
+    mrb_hash_set_index(constraints.clone(), key, pattern)?;
+    uzumibi_router_with_constraints(vm, &constraints, block)
+}
+
+/// constraints(hash) { ... } -> nil
+pub(crate) fn uzumibi_router_constraints(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let mut extra: Vec<(Rc<RObject>, Rc<RObject>)> = Vec::new();
+    if let Some(kwargs) = vm.get_kwargs() {
+        for (key, value) in kwargs.iter() {
+            extra.push((
+                RObject::symbol(RSym::new(key.to_string())).to_refcount_assigned(),
+                value.clone(),
+            ));
+        }
+    }
+    let block = match args.split_last() {
+        Some((block, rest)) if matches!(block.value, RValue::Proc(_)) => {
+            for hash in rest {
+                extra.extend(uzumibi_hash_entries(hash)?);
+            }
+            block.clone()
+        }
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected constraints and a block".to_string(),
+            ));
+        }
+    };
+    let constraints =
+        uzumibi_constraints_merged(vm, &RObject::nil().to_refcount_assigned(), &extra)?;
+    uzumibi_router_with_constraints(vm, &constraints, block)
+}
+
+/// Combines the constraints of the enclosing `host`/`constraints` blocks
+/// with the `constraints:` option of a route. Returns nil when there are none.
+pub(crate) fn uzumibi_route_constraints(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    options: &[(String, Rc<RObject>)],
+) -> Result<Rc<RObject>, Error> {
+    let scoped = klass.get_ivar(CONSTRAINTS_KEY);
+    let extra = match options.iter().find(|(k, _)| k == "constraints") {
+        Some((_, constraints)) => uzumibi_hash_entries(constraints)?,
+        None => Vec::new(),
+    };
+    if scoped.is_falsy() && extra.is_empty() {
+        return Ok(RObject::nil().to_refcount_assigned());
+    }
+    uzumibi_constraints_merged(vm, &scoped, &extra)
+}
+
+/// Adds a `[constraints, handler]` candidate to the candidates of a pattern.
+/// A candidate without constraints replaces the previous one.
+pub(crate) fn uzumibi_candidates_push(
+    candidates: &Rc<RObject>,
+    constraints: Rc<RObject>,
+    handler: Rc<RObject>,
+) -> Result<(), Error> {
+    let RValue::Array(arr) = &candidates.value else {
+        return Err(Error::RuntimeError(
+            "Route candidates must be an Array".to_string(),
+        ));
+    };
+    let mut arr = arr.borrow_mut();
+    if constraints.is_falsy() {
+        arr.retain(|candidate| match &candidate.value {
+            RValue::Array(pair) => pair.borrow()[0].is_truthy(),
+            _ => false,
+        });
+    }
+    arr.push(RObject::array(vec![constraints, handler]).to_refcount_assigned());
+    Ok(())
+}
+
+/// Picks the handler among the candidates of a matched pattern.
+pub(crate) fn uzumibi_select_candidate(
+    vm: &mut VM,
+    candidates: &Rc<RObject>,
+    request: &Rc<RObject>,
+) -> Result<Option<Rc<RObject>>, Error> {
+    let candidates = match &candidates.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        // A bare handler stored by a plain ArtRouter
+        _ => return Ok(Some(candidates.clone())),
+    };
+    let mut fallback = None;
+    for candidate in candidates {
+        let (constraints, handler) = match &candidate.value {
+            RValue::Array(pair) => {
+                let pair = pair.borrow();
+                (pair[0].clone(), pair[1].clone())
+            }
+            _ => continue,
+        };
+        if constraints.is_falsy() {
+            fallback = Some(handler);
+        } else if uzumibi_constraints_match(vm, &constraints, request)? {
+            return Ok(Some(handler));
+        }
+    }
+    Ok(fallback)
+}
+
+/// Checks every entry of a constraints Hash against the request.
+fn uzumibi_constraints_match(
+    vm: &mut VM,
+    constraints: &Rc<RObject>,
+    request: &Rc<RObject>,
+) -> Result<bool, Error> {
+    for (key, expected) in uzumibi_hash_entries(constraints)? {
+        let key = mrb_funcall(vm, Some(key), "to_s", &[])?;
+        let key: String = key.as_ref().try_into()?;
+        let (value, ignore_case) = match key.as_str() {
+            "host" => (request_host(request)?, true),
+            "subdomain" => (request_host(request)?.map(|h| subdomain_of(&h)), true),
+            _ => {
+                let name = key.replace('_', "-").to_ascii_lowercase();
+                (uzumibi_request_header(request, &name)?, false)
+            }
+        };
+        if !uzumibi_constraint_value_match(vm, &expected, value.as_deref(), ignore_case)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn uzumibi_constraint_value_match(
+    vm: &mut VM,
+    expected: &Rc<RObject>,
+    value: Option<&str>,
+    ignore_case: bool,
+) -> Result<bool, Error> {
+    match &expected.value {
+        RValue::Array(arr) => {
+            let items = arr.borrow().clone();
+            for item in items {
+                if uzumibi_constraint_value_match(vm, &item, value, ignore_case)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        RValue::Proc(_) => {
+            let arg = match value {
+                Some(value) => RObject::string(value.to_string()).to_refcount_assigned(),
+                None => RObject::nil().to_refcount_assigned(),
+            };
+            Ok(mrb_funcall(vm, Some(expected.clone()), "call", &[arg])?.is_truthy())
+        }
+        _ => {
+            let Some(value) = value else {
+                return Ok(false);
+            };
+            if let RValue::String(_, _) | RValue::Symbol(_) = &expected.value {
+                let expected: String = expected.as_ref().try_into()?;
+                return Ok(if ignore_case {
+                    expected.eq_ignore_ascii_case(value)
+                } else {
+                    expected == value
+                });
+            }
+            let value = RObject::string(value.to_string()).to_refcount_assigned();
+            Ok(mrb_funcall(vm, Some(expected.clone()), "===", &[value])?.is_truthy())
+        }
+    }
+}
+
+/// The Host header without the port, lower-cased.
+fn request_host(request: &Rc<RObject>) -> Result<Option<String>, Error> {
+    Ok(uzumibi_request_header(request, "host")?.map(|host| strip_port(&host).to_ascii_lowercase()))
+}
+
+fn strip_port(host: &str) -> &str {
+    // Keep IPv6 literals such as "[::1]:8080" intact up to the bracket
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+/// "api.example.com" -> "api", "a.b.example.com" -> "a.b", "example.com" -> "".
+fn subdomain_of(host: &str) -> String {
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.len() <= 2 {
+        return String::new();
+    }
+    labels[..labels.len() - 2].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("api.example.com:8787"), "api.example.com");
+        assert_eq!(strip_port("api.example.com"), "api.example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn test_subdomain_of() {
+        assert_eq!(subdomain_of("api.example.com"), "api");
+        assert_eq!(subdomain_of("a.b.example.com"), "a.b");
+        assert_eq!(subdomain_of("example.com"), "");
+        assert_eq!(subdomain_of("localhost"), "");
+    }
+}
@@ -0,0 +1,372 @@
+//! This module implements setting cookies on responses.
+//! `init_uzumibi_cookies()` should be called on prelude process, after
+//! `init_uzumibi_response()`.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Response
+//!       def set_cookie(name: String, value: untyped, path: String?, domain: String?, expires: Integer | String | nil, max_age: Integer?, secure: bool?, http_only: bool?, same_site: String | Symbol | nil) -> nil
+//!       def delete_cookie(name: String, path: String?, domain: String?) -> nil
+//! ```
+//!
+//! Each cookie is one `Set-Cookie` header. Several cookies are stored as
+//! an Array under `Set-Cookie` and sent as separate headers. Setting a
+//! cookie again with the same name, path and domain replaces the earlier
+//! one instead of sending both.
+//!
+//! Values are percent-encoded (see [`encode_cookie_value`]) and decoded
+//! again in `req.cookie`, so any String survives the round trip. `expires:`
+//! takes seconds since the Unix epoch or a preformatted HTTP date, and
+//! `path:` is `/` unless given.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_cmethod, mrb_funcall},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    helpers::{encode_cookie_value, format_http_date},
+    response::{
+        keyword_arguments, uzumibi_response_get_header_values, uzumibi_response_set_header_values,
+    },
+};
+
+const SET_COOKIE: &str = "Set-Cookie";
+/// The `Expires` date that makes a browser drop a cookie at once.
+pub(crate) const EXPIRED: &str = "Thu, 01 Jan 1970 00:00:00 GMT";
+
+/// The attributes sent with a cookie.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CookieAttributes {
+    pub(crate) path: Option<String>,
+    pub(crate) domain: Option<String>,
+    pub(crate) expires: Option<String>,
+    pub(crate) max_age: Option<i64>,
+    pub(crate) secure: bool,
+    pub(crate) http_only: bool,
+    pub(crate) same_site: Option<String>,
+}
+
+pub(crate) fn init_uzumibi_cookies(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let response_class = match uzumibi_module.get_const_by_name("Response") {
+        Some(class) => match &class.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("Response must be a class"),
+        },
+        None => panic!("Response class must be defined beforehand"),
+    };
+
+    mrb_define_cmethod(
+        vm,
+        response_class.clone(),
+        "set_cookie",
+        Box::new(uzumibi_response_set_cookie),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class,
+        "delete_cookie",
+        Box::new(uzumibi_response_delete_cookie),
+    );
+}
+
+/// res.set_cookie(name, value, path: "/", domain: nil, expires: nil,
+///   max_age: nil, secure: false, http_only: false, same_site: nil) -> nil
+fn uzumibi_response_set_cookie(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let kwargs = keyword_arguments(vm);
+    let (name, value) = match args {
+        [name, value, ..] => (name.clone(), value.clone()),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected 2 arguments: name, value".to_string(),
+            ));
+        }
+    };
+    let name = cookie_name(vm, &name)?;
+    let value = match &value.value {
+        RValue::String(s, _) => s.borrow().to_vec(),
+        RValue::Nil => Vec::new(),
+        _ => {
+            let value = mrb_funcall(vm, Some(value.clone()), "to_s", &[])?;
+            let value: String = value.as_ref().try_into()?;
+            value.into_bytes()
+        }
+    };
+
+    let option = |key: &str| kwargs.get(key).filter(|v| !matches!(v.value, RValue::Nil));
+    let expires = match option("expires") {
+        Some(expires) => match &expires.value {
+            RValue::Integer(seconds) => Some(format_http_date(*seconds)),
+            RValue::String(_, _) => Some(attribute_value("expires", expires)?),
+            _ => {
+                return Err(Error::ArgumentError(
+                    "expires must be seconds since the epoch or an HTTP date".to_string(),
+                ));
+            }
+        },
+        None => None,
+    };
+    let max_age = match option("max_age") {
+        Some(max_age) => match max_age.value {
+            RValue::Integer(seconds) => Some(seconds),
+            _ => {
+                return Err(Error::ArgumentError(
+                    "max_age must be a number of seconds".to_string(),
+                ));
+            }
+        },
+        None => None,
+    };
+    let secure = option("secure").is_some_and(|v| v.is_truthy());
+    let same_site = match option("same_site") {
+        Some(same_site) => Some(same_site_value(&attribute_value("same_site", same_site)?)?),
+        None => None,
+    };
+    if same_site.as_deref() == Some("None") && !secure {
+        return Err(Error::ArgumentError(
+            "same_site: None requires secure: true".to_string(),
+        ));
+    }
+    let attributes = CookieAttributes {
+        path: Some(match option("path") {
+            Some(path) => attribute_value("path", path)?,
+            None => "/".to_string(),
+        }),
+        domain: option("domain")
+            .map(|domain| attribute_value("domain", domain))
+            .transpose()?,
+        expires,
+        max_age,
+        secure,
+        http_only: option("http_only").is_some_and(|v| v.is_truthy()),
+        same_site,
+    };
+
+    let header = format!(
+        "{}={}{}",
+        name,
+        encode_cookie_value(&value),
+        attributes.format()
+    );
+    let response = vm.getself()?;
+    uzumibi_response_add_cookie(vm, &response, &name, &attributes, &header)?;
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// res.delete_cookie(name, path: "/", domain: nil) -> nil
+///
+/// The path and domain must match the ones the cookie was set with.
+fn uzumibi_response_delete_cookie(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let kwargs = keyword_arguments(vm);
+    let name = match args.first() {
+        Some(name) => cookie_name(vm, name)?,
+        None => {
+            return Err(Error::ArgumentError(
+                "Expected 1 argument: name".to_string(),
+            ));
+        }
+    };
+    let option = |key: &str| kwargs.get(key).filter(|v| !matches!(v.value, RValue::Nil));
+    let attributes = CookieAttributes {
+        path: Some(match option("path") {
+            Some(path) => attribute_value("path", path)?,
+            None => "/".to_string(),
+        }),
+        domain: option("domain")
+            .map(|domain| attribute_value("domain", domain))
+            .transpose()?,
+        expires: Some(EXPIRED.to_string()),
+        max_age: Some(0),
+        ..Default::default()
+    };
+
+    let header = format!("{}={}", name, attributes.format());
+    let response = vm.getself()?;
+    uzumibi_response_add_cookie(vm, &response, &name, &attributes, &header)?;
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// Adds a `Set-Cookie` header to a response, dropping an earlier one for
+/// the same name, path and domain.
+pub(crate) fn uzumibi_response_add_cookie(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    name: &str,
+    attributes: &CookieAttributes,
+    header: &str,
+) -> Result<(), Error> {
+    let mut values = match uzumibi_response_get_header_values(response, SET_COOKIE)? {
+        Some((_, values)) => values,
+        None => Vec::new(),
+    };
+    let identity = (
+        name,
+        attributes.path.as_deref(),
+        attributes.domain.as_deref(),
+    );
+    values.retain(|value| {
+        let (other_name, other_path, other_domain) = cookie_identity(value);
+        (other_name, other_path.as_deref(), other_domain.as_deref()) != identity
+    });
+    values.push(header.to_string());
+    uzumibi_response_set_header_values(vm, response, SET_COOKIE, values)
+}
+
+impl CookieAttributes {
+    /// The attributes as they follow `name=value` in a `Set-Cookie` header.
+    pub(crate) fn format(&self) -> String {
+        let mut attributes = String::new();
+        if let Some(path) = &self.path {
+            attributes.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            attributes.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(expires) = &self.expires {
+            attributes.push_str(&format!("; Expires={}", expires));
+        }
+        if let Some(max_age) = self.max_age {
+            attributes.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.secure {
+            attributes.push_str("; Secure");
+        }
+        if self.http_only {
+            attributes.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = &self.same_site {
+            attributes.push_str(&format!("; SameSite={}", same_site));
+        }
+        attributes
+    }
+}
+
+/// The name, path and domain of a `Set-Cookie` header, which together
+/// identify the cookie in the browser.
+fn cookie_identity(header: &str) -> (&str, Option<String>, Option<String>) {
+    let mut parts = header.split(';');
+    let name = parts
+        .next()
+        .and_then(|pair| pair.split_once('='))
+        .map_or("", |(name, _)| name.trim());
+    let mut path = None;
+    let mut domain = None;
+    for attribute in parts {
+        if let Some((key, value)) = attribute.split_once('=') {
+            let key = key.trim();
+            if key.eq_ignore_ascii_case("path") {
+                path = Some(value.trim().to_string());
+            } else if key.eq_ignore_ascii_case("domain") {
+                domain = Some(value.trim().to_string());
+            }
+        }
+    }
+    (name, path, domain)
+}
+
+/// A cookie name, which must be an HTTP token.
+fn cookie_name(vm: &mut VM, name: &Rc<RObject>) -> Result<String, Error> {
+    let name: String = match &name.value {
+        RValue::String(_, _) => name.as_ref().try_into()?,
+        _ => {
+            let name = mrb_funcall(vm, Some(name.clone()), "to_s", &[])?;
+            name.as_ref().try_into()?
+        }
+    };
+    let is_token = !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+    if !is_token {
+        return Err(Error::ArgumentError(format!(
+            "invalid cookie name: {:?}",
+            name
+        )));
+    }
+    Ok(name)
+}
+
+/// A String or Symbol attribute value, which cannot end the attribute
+/// early with `;` or contain control characters.
+fn attribute_value(key: &str, value: &Rc<RObject>) -> Result<String, Error> {
+    let value: String = match &value.value {
+        RValue::String(_, _) => value.as_ref().try_into()?,
+        RValue::Symbol(sym) => sym.name.clone(),
+        _ => return Err(Error::ArgumentError(format!("{} must be a String", key))),
+    };
+    if value.chars().any(|c| c == ';' || c.is_control()) {
+        return Err(Error::ArgumentError(format!(
+            "invalid cookie {}: {:?}",
+            key, value
+        )));
+    }
+    Ok(value)
+}
+
+/// `Strict`, `Lax` or `None`, given in any case.
+fn same_site_value(value: &str) -> Result<String, Error> {
+    ["Strict", "Lax", "None"]
+        .into_iter()
+        .find(|same_site| same_site.eq_ignore_ascii_case(value))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            Error::ArgumentError(format!(
+                "same_site must be Strict, Lax or None, not {:?}",
+                value
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_attributes() {
+        let attributes = CookieAttributes {
+            path: Some("/app".to_string()),
+            domain: Some("example.com".to_string()),
+            expires: Some(EXPIRED.to_string()),
+            max_age: Some(60),
+            secure: true,
+            http_only: true,
+            same_site: Some("Strict".to_string()),
+        };
+        assert_eq!(
+            attributes.format(),
+            "; Path=/app; Domain=example.com; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=60; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(CookieAttributes::default().format(), "");
+    }
+
+    #[test]
+    fn test_cookie_identity() {
+        assert_eq!(
+            cookie_identity("a=1; path=/x; Domain=example.com; Secure"),
+            ("a", Some("/x".to_string()), Some("example.com".to_string()))
+        );
+        assert_eq!(cookie_identity("b=; Max-Age=0"), ("b", None, None));
+    }
+
+    #[test]
+    fn test_same_site_value() {
+        assert_eq!(same_site_value("lax").unwrap(), "Lax");
+        assert_eq!(same_site_value("NONE").unwrap(), "None");
+        assert!(same_site_value("sometimes").is_err());
+    }
+}
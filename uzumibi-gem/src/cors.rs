@@ -0,0 +1,319 @@
+//! This module implements the CORS policy declared with `Uzumibi::Router.cors`.
+//! The policy is stored on the router class and applied by `start_request`.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.cors(origins: Array[String] | String, methods: Array[String]?, headers: Array[String]?,
+//!                     expose: Array[String]?, max_age: Integer?, credentials: bool) -> nil
+//! ```
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::response::{uzumibi_response_get_header, uzumibi_response_set_header};
+
+const CORS_KEY: &str = "@_cors";
+
+const CORS_OPTION_KEYS: [&str; 6] = [
+    "origins",
+    "methods",
+    "headers",
+    "expose",
+    "max_age",
+    "credentials",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct CorsPolicy {
+    pub origins: Vec<String>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub expose: Vec<String>,
+    pub max_age: Option<i64>,
+    pub credentials: bool,
+}
+
+impl CorsPolicy {
+    /// Reads the policy stored on a router class, if any.
+    pub(crate) fn from_router_class(
+        vm: &mut VM,
+        klass: &Rc<RObject>,
+    ) -> Result<Option<Self>, Error> {
+        let options = klass.get_ivar(CORS_KEY);
+        if !matches!(options.value, RValue::Hash(_)) {
+            return Ok(None);
+        }
+        Self::from_options(vm, &options).map(Some)
+    }
+
+    /// Builds a policy from the options Hash given to `cors`.
+    fn from_options(vm: &mut VM, options: &Rc<RObject>) -> Result<Self, Error> {
+        let RValue::Hash(h) = &options.value else {
+            return Ok(Self::default());
+        };
+        let entries: Vec<(String, Rc<RObject>)> = {
+            let options_h = h.borrow();
+            let mut entries = Vec::new();
+            for (_, (key_obj, value_obj)) in options_h.iter() {
+                let key: String = key_obj.as_ref().try_into()?;
+                entries.push((key, value_obj.clone()));
+            }
+            entries
+        };
+
+        let mut policy = CorsPolicy {
+            origins: vec!["*".to_string()],
+            ..Default::default()
+        };
+        for (key, value) in entries {
+            match key.as_str() {
+                "origins" => policy.origins = string_list(vm, value)?,
+                "methods" => {
+                    let methods = string_list(vm, value)?;
+                    policy.methods = Some(methods.iter().map(|m| m.to_ascii_uppercase()).collect());
+                }
+                "headers" => policy.headers = Some(string_list(vm, value)?),
+                "expose" => policy.expose = string_list(vm, value)?,
+                "max_age" => policy.max_age = Some(value.as_ref().try_into()?),
+                "credentials" => policy.credentials = value.is_truthy(),
+                _ => {}
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Rejects a policy that would let any site make credentialed
+    /// requests: a wildcard origin combined with `credentials: true`.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.credentials && self.origins.iter().any(|o| o == "*") {
+            return Err(Error::ArgumentError(
+                "cors: credentials: true cannot be combined with origins \"*\"; list the allowed origins".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the value for `Access-Control-Allow-Origin`, or None when
+    /// the origin is not allowed.
+    pub fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.iter().any(|o| o == "*") {
+            return Some("*".to_string());
+        }
+        self.origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin))
+            .then(|| origin.to_string())
+    }
+
+    /// Headers added to every response for a cross-origin request.
+    pub fn response_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return Vec::new();
+        };
+        let mut headers = vec![("Access-Control-Allow-Origin", allow_origin)];
+        if self.credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        if !self.expose.is_empty() {
+            headers.push(("Access-Control-Expose-Headers", self.expose.join(", ")));
+        }
+        headers
+    }
+
+    /// Headers added to a preflight response. `allowed` lists the methods
+    /// registered for the path; `request_headers` is the value of
+    /// `Access-Control-Request-Headers`.
+    pub fn preflight_headers(
+        &self,
+        origin: &str,
+        allowed: &[String],
+        request_headers: Option<&str>,
+    ) -> Vec<(&'static str, String)> {
+        if self.allow_origin(origin).is_none() {
+            return Vec::new();
+        }
+        let methods = match &self.methods {
+            Some(methods) => methods
+                .iter()
+                .filter(|m| allowed.iter().any(|a| a == *m))
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => allowed.to_vec(),
+        };
+        let mut headers = vec![("Access-Control-Allow-Methods", methods.join(", "))];
+        match (&self.headers, request_headers) {
+            (Some(allowed_headers), _) => {
+                headers.push(("Access-Control-Allow-Headers", allowed_headers.join(", ")));
+            }
+            (None, Some(requested)) if !requested.trim().is_empty() => {
+                headers.push(("Access-Control-Allow-Headers", requested.trim().to_string()));
+            }
+            _ => {}
+        }
+        if let Some(max_age) = self.max_age {
+            headers.push(("Access-Control-Max-Age", max_age.to_string()));
+        }
+        headers
+    }
+
+    /// Injects the CORS response headers into a response object.
+    /// Headers already set by the handler are left untouched.
+    pub(crate) fn apply(
+        &self,
+        vm: &mut VM,
+        response: &Rc<RObject>,
+        origin: Option<&str>,
+        extra: Vec<(&'static str, String)>,
+    ) -> Result<(), Error> {
+        let mut vary = match uzumibi_response_get_header(response, "Vary")? {
+            Some((_, value)) => value,
+            None => String::new(),
+        };
+        if !vary
+            .split(',')
+            .any(|v| v.trim().eq_ignore_ascii_case("origin"))
+        {
+            if !vary.is_empty() {
+                vary.push_str(", ");
+            }
+            vary.push_str("Origin");
+            uzumibi_response_set_header(vm, response, "Vary", &vary)?;
+        }
+
+        let Some(origin) = origin else {
+            return Ok(());
+        };
+        let mut headers = self.response_headers(origin);
+        headers.extend(extra);
+        for (name, value) in headers {
+            if uzumibi_response_get_header(response, name)?.is_none() {
+                uzumibi_response_set_header(vm, response, name, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn string_list(vm: &mut VM, value: Rc<RObject>) -> Result<Vec<String>, Error> {
+    let items = match &value.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        RValue::Nil => Vec::new(),
+        _ => vec![value.clone()],
+    };
+    let mut list = Vec::with_capacity(items.len());
+    for item in items {
+        let item = mrb_funcall(vm, Some(item), "to_s", &[])?;
+        let item: String = item.as_ref().try_into()?;
+        list.push(item);
+    }
+    Ok(list)
+}
+
+/// cors(origins: [...], methods: [...], headers: [...], expose: [...], max_age: Integer, credentials: bool) -> nil
+pub(crate) fn uzumibi_router_cors(
+    vm: &mut VM,
+    _args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let klass = vm.getself()?;
+    let options = mrb_hash_new(vm, &[])?;
+    if let Some(kwargs) = vm.get_kwargs() {
+        for key in CORS_OPTION_KEYS {
+            if let Some(value) = kwargs.get(key) {
+                mrb_hash_set_index(
+                    options.clone(),
+                    RObject::string(key.to_string()).to_refcount_assigned(),
+                    value.clone(),
+                )?;
+            }
+        }
+    }
+    CorsPolicy::from_options(vm, &options)?.validate()?;
+    klass.set_ivar(CORS_KEY, options);
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            credentials,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_allow_origin_wildcard() {
+        let p = policy(&["*"], false);
+        assert_eq!(p.allow_origin("https://a.example"), Some("*".to_string()));
+    }
+
+    #[test]
+    fn test_wildcard_with_credentials_is_rejected() {
+        assert!(policy(&["*"], true).validate().is_err());
+        assert!(policy(&["*"], false).validate().is_ok());
+        assert!(policy(&["https://a.example"], true).validate().is_ok());
+        // Never reflect an arbitrary origin
+        assert_eq!(
+            policy(&["*"], true).allow_origin("https://a.example"),
+            Some("*".to_string())
+        );
+    }
+
+    #[test]
+    fn test_allow_origin_list() {
+        let p = policy(&["https://a.example"], false);
+        assert_eq!(
+            p.allow_origin("https://a.example"),
+            Some("https://a.example".to_string())
+        );
+        assert_eq!(p.allow_origin("https://b.example"), None);
+        assert!(p.response_headers("https://b.example").is_empty());
+    }
+
+    #[test]
+    fn test_preflight_headers_use_route_methods() {
+        let mut p = policy(&["*"], false);
+        p.max_age = Some(600);
+        let allowed = vec!["GET".to_string(), "PATCH".to_string()];
+        let headers = p.preflight_headers("https://a.example", &allowed, Some("content-type"));
+        assert_eq!(
+            headers,
+            vec![
+                ("Access-Control-Allow-Methods", "GET, PATCH".to_string()),
+                ("Access-Control-Allow-Headers", "content-type".to_string()),
+                ("Access-Control-Max-Age", "600".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preflight_headers_restricted_methods() {
+        let mut p = policy(&["*"], false);
+        p.methods = Some(vec!["GET".to_string()]);
+        p.headers = Some(vec!["x-token".to_string()]);
+        let allowed = vec!["GET".to_string(), "DELETE".to_string()];
+        let headers = p.preflight_headers("https://a.example", &allowed, Some("content-type"));
+        assert_eq!(
+            headers,
+            vec![
+                ("Access-Control-Allow-Methods", "GET".to_string()),
+                ("Access-Control-Allow-Headers", "x-token".to_string()),
+            ]
+        );
+    }
+}
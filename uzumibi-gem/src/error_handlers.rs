@@ -0,0 +1,235 @@
+//! This module implements global error handlers on Uzumibi::Router.
+//! `start_request` consults them when a route, filter or middleware raises.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.error(*exception_classes: Class) { (Exception, Request, Response) -> untyped } -> nil
+//!       def self.rescue_from(*exception_classes: Class) { (Exception, Request, Response) -> untyped } -> nil
+//! ```
+//!
+//! Handlers are tried in definition order; one without classes handles
+//! every error. The handler gets a fresh response object. When no handler
+//! matches, the handler raises, or it leaves `res.status_code` unset, the
+//! request gets the default 500 JSON response with a request id.
+//! `halt` and `redirect` pass through here as well and never reach a
+//! handler.
+//!
+//! Handlers receive the exception object the VM raised, so
+//! `error(MyError)` matches a user-defined exception class.
+//!
+//! Generated request ids mix in a seed. `RandomState` is random on native
+//! and WASI hosts, but fixed on wasm32-unknown-unknown, so those hosts pass
+//! randomness from the platform to [`uzumibi_seed_request_ids`] when
+//! they instantiate the module.
+//!
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        value::{RException, RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    halt::{is_halt, uzumibi_take_halt},
+    request::uzumibi_request_header,
+    response::{
+        uzumibi_response_is_filled, uzumibi_response_new, uzumibi_response_replace,
+        uzumibi_return_internal_error,
+    },
+};
+
+const ERROR_HANDLERS_KEY: &str = "@_error_handlers";
+
+/// Tagged errors used for control flow between the gem and the hosts.
+/// They are never passed to error handlers.
+const PASSTHROUGH_TAGS: [&str; 1] = ["UzumibiPassAssets"];
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+static REQUEST_ID_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Mixes host randomness into generated request ids. Call it once after
+/// the module is instantiated on hosts without a random `RandomState`.
+pub fn uzumibi_seed_request_ids(seed: u64) {
+    REQUEST_ID_SEED.store(seed, Ordering::Relaxed);
+}
+
+/// error(*exception_classes) { |e, req, res| ... } -> nil
+pub(crate) fn uzumibi_router_error(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let (handler, classes) = match args.split_last() {
+        Some((handler, classes)) if matches!(handler.value, RValue::Proc(_)) => {
+            (handler.clone(), classes.to_vec())
+        }
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a block and optional exception classes".to_string(),
+            ));
+        }
+    };
+
+    let klass = vm.getself()?;
+    let mut handlers = klass.get_ivar(ERROR_HANDLERS_KEY);
+    if handlers.is_falsy() {
+        handlers = RObject::array(vec![]).to_refcount_assigned();
+        klass.set_ivar(ERROR_HANDLERS_KEY, handlers.clone());
+    }
+    let entry = RObject::array(vec![
+        RObject::array(classes).to_refcount_assigned(),
+        handler,
+    ])
+    .to_refcount_assigned();
+    mrb_funcall(vm, Some(handlers), "push", &[entry])?;
+
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// Turns an error raised while serving a request into a response.
+/// Returns the error itself when it must reach the host unchanged.
+pub(crate) fn uzumibi_rescue_error(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    error: Error,
+    request: &Rc<RObject>,
+    response: &Rc<RObject>,
+) -> Result<(), Error> {
+    if let Error::TaggedError(tag, _) = &error
+        && PASSTHROUGH_TAGS.iter().any(|t| tag == t)
+    {
+        return Err(error);
+    }
+    // The error is handled here, so the host must not see it again
+    let raised = vm.exception.take();
+
+    if is_halt(&error)
+        && let Some(halted) = uzumibi_take_halt(vm, request)?
+    {
+        uzumibi_response_replace(response, &halted);
+        return Ok(());
+    }
+
+    let exception = uzumibi_exception_object(vm, raised, &error);
+    if let Some(handler) = uzumibi_find_error_handler(vm, klass, &exception)? {
+        let handled = uzumibi_response_new(vm);
+        let result = mrb_funcall(
+            vm,
+            Some(handler),
+            "call",
+            &[exception, request.clone(), handled.clone()],
+        );
+        vm.exception.take();
+        if let Err(e) = &result
+            && is_halt(e)
+            && let Some(halted) = uzumibi_take_halt(vm, request)?
+        {
+            uzumibi_response_replace(response, &halted);
+            return Ok(());
+        }
+        if result.is_ok() && uzumibi_response_is_filled(&handled) {
+            uzumibi_response_replace(response, &handled);
+            return Ok(());
+        }
+    }
+
+    let request_id = uzumibi_request_id(request)?;
+    let fallback = uzumibi_return_internal_error(vm, &request_id)?;
+    uzumibi_response_replace(response, &fallback);
+    Ok(())
+}
+
+fn uzumibi_find_error_handler(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    exception: &Rc<RObject>,
+) -> Result<Option<Rc<RObject>>, Error> {
+    let entries = match &klass.get_ivar(ERROR_HANDLERS_KEY).value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        _ => return Ok(None),
+    };
+    for entry in entries {
+        let (classes, handler) = match &entry.value {
+            RValue::Array(pair) => {
+                let pair = pair.borrow();
+                (pair[0].clone(), pair[1].clone())
+            }
+            _ => continue,
+        };
+        let classes = match &classes.value {
+            RValue::Array(arr) => arr.borrow().clone(),
+            _ => Vec::new(),
+        };
+        if classes.is_empty() {
+            return Ok(Some(handler));
+        }
+        for class in classes {
+            let matched = mrb_funcall(vm, Some(exception.clone()), "is_a?", &[class])?;
+            if matched.is_truthy() {
+                return Ok(Some(handler));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The exception object handed to handlers: the one the VM raised, with
+/// its own class and message. An error that did not come through the VM
+/// gets an exception built from it.
+fn uzumibi_exception_object(
+    vm: &mut VM,
+    raised: Option<Rc<RException>>,
+    error: &Error,
+) -> Rc<RObject> {
+    let exception = match raised {
+        Some(raised) if *raised.error_type.borrow() == *error => raised,
+        _ => Rc::new(RException::from_error(vm, error)),
+    };
+    RObject::exception(exception).to_refcount_assigned()
+}
+
+/// Returns the incoming `X-Request-Id`, or a newly generated id.
+pub(crate) fn uzumibi_request_id(request: &Rc<RObject>) -> Result<String, Error> {
+    if let Some(id) = uzumibi_request_header(request, "x-request-id")?
+        && !id.trim().is_empty()
+    {
+        return Ok(id.trim().to_string());
+    }
+    Ok(generate_request_id(Rc::as_ptr(request) as usize))
+}
+
+fn generate_request_id(seed: usize) -> String {
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(REQUEST_ID_SEED.load(Ordering::Relaxed));
+    hasher.write_u64(count);
+    hasher.write_usize(seed);
+    let high = hasher.finish();
+    hasher.write_u64(high);
+    format!("{:016x}{:016x}", high, hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_request_id() {
+        let a = generate_request_id(1);
+        let b = generate_request_id(1);
+        assert_eq!(a.len(), 32);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+}
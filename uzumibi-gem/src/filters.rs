@@ -0,0 +1,150 @@
+//! This module implements before/after filters on Uzumibi::Router.
+//! Filters run around the matched route proc in `start_request`.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.before(path_pattern: String?) { (Request, Response) -> untyped } -> nil
+//!       def self.after(path_pattern: String?) { (Request, Response) -> untyped } -> nil
+//! ```
+//!
+//! A filter without a pattern runs for every matched route. A pattern uses
+//! the same syntax as routes, e.g. `"/admin/*"`. A before filter that sets
+//! `res.status_code` short-circuits the remaining before filters and the
+//! route; after filters still run. Inside a `namespace` block, patterns
+//! are prefixed with the namespace.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    init::{uzumibi_art_router_new, uzumibi_router_match},
+    mount::uzumibi_router_prefixed_path,
+};
+
+const BEFORE_FILTERS_KEY: &str = "@_before_filters";
+const AFTER_FILTERS_KEY: &str = "@_after_filters";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FilterKind {
+    Before,
+    After,
+}
+
+impl FilterKind {
+    fn ivar_key(self) -> &'static str {
+        match self {
+            FilterKind::Before => BEFORE_FILTERS_KEY,
+            FilterKind::After => AFTER_FILTERS_KEY,
+        }
+    }
+}
+
+fn uzumibi_router_add_filter(
+    vm: &mut VM,
+    kind: FilterKind,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let (pattern, filter) = match args {
+        [filter] if filter.is_truthy() => (None, filter.clone()),
+        [pattern, filter] if filter.is_truthy() => {
+            let pattern = if pattern.is_truthy() {
+                Some(pattern.clone())
+            } else {
+                None
+            };
+            (pattern, filter.clone())
+        }
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a block and an optional path pattern".to_string(),
+            ));
+        }
+    };
+
+    let klass = vm.getself()?;
+
+    // Patterns are matched with an ArtRouter holding only the filter
+    let matcher = match pattern {
+        Some(pattern) => {
+            let pattern: String = pattern.as_ref().try_into()?;
+            let pattern = uzumibi_router_prefixed_path(&klass, &pattern)?;
+            let pattern = RObject::string(pattern).to_refcount_assigned();
+            let art_router = uzumibi_art_router_new(vm)?;
+            mrb_funcall(
+                vm,
+                Some(art_router.clone()),
+                "set_route",
+                &[pattern, filter.clone()],
+            )?;
+            art_router
+        }
+        None => RObject::nil().to_refcount_assigned(),
+    };
+
+    let mut filters = klass.get_ivar(kind.ivar_key());
+    if filters.is_falsy() {
+        filters = RObject::array(vec![]).to_refcount_assigned();
+        klass.set_ivar(kind.ivar_key(), filters.clone());
+    }
+    let entry = RObject::array(vec![matcher, filter]).to_refcount_assigned();
+    mrb_funcall(vm, Some(filters), "push", &[entry])?;
+
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// before(path_pattern = nil) { |req, res| ... } -> nil
+pub(crate) fn uzumibi_router_before(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    uzumibi_router_add_filter(vm, FilterKind::Before, args)
+}
+
+/// after(path_pattern = nil) { |req, res| ... } -> nil
+pub(crate) fn uzumibi_router_after(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    uzumibi_router_add_filter(vm, FilterKind::After, args)
+}
+
+/// Returns the filter procs of the given kind that apply to the path,
+/// in definition order.
+pub(crate) fn uzumibi_filters_for(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    kind: FilterKind,
+    path: &str,
+) -> Result<Vec<Rc<RObject>>, Error> {
+    let filters = klass.get_ivar(kind.ivar_key());
+    let entries = match &filters.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut procs = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (matcher, filter) = match &entry.value {
+            RValue::Array(pair) => {
+                let pair = pair.borrow();
+                (pair[0].clone(), pair[1].clone())
+            }
+            _ => continue,
+        };
+        if matcher.is_falsy() || uzumibi_router_match(vm, matcher, path)?.is_some() {
+            procs.push(filter);
+        }
+    }
+    Ok(procs)
+}
@@ -0,0 +1,173 @@
+//! This module implements `halt` and `redirect` on Uzumibi::Router.
+//! `init_uzumibi_halt()` should be called on prelude process.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   class UzumibiHalt < Exception
+//!   end
+//!   module Uzumibi
+//!     class Router
+//!       def self.halt(status: Integer, body: String?, headers: Hash[String, String]?) -> bot
+//!       def self.redirect(location: String, status: Integer?) -> bot
+//! ```
+//!
+//! Both end the current route, filter or handler at once. They raise the
+//! tagged error `UzumibiHalt`, the same way `fetch_assets` raises
+//! `UzumibiPassAssets`. The pending response is kept on the request being
+//! served until the rescue in `start_request` turns it into the response,
+//! so a halt never outlives its request. The Router class only points at
+//! that request while `start_request` runs.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_define_class_cmethod,
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::response::{redirect_arguments, uzumibi_response_new, uzumibi_response_return_values};
+
+pub(crate) const HALT_TAG: &str = "UzumibiHalt";
+const CURRENT_REQUEST_KEY: &str = "@_current_request";
+const REQUEST_HALT_KEY: &str = "@_halt";
+
+pub(crate) fn init_uzumibi_halt(vm: &mut VM) {
+    let exception = vm.get_class_by_name("Exception");
+    vm.define_class(HALT_TAG, Some(exception), None);
+
+    let router_class = uzumibi_router_base_class(vm).expect("Router class must be defined");
+    let router_class = match &router_class.value {
+        RValue::Class(c) => c.clone(),
+        _ => panic!("Router must be a class"),
+    };
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "halt",
+        Box::new(uzumibi_router_halt),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class,
+        "redirect",
+        Box::new(uzumibi_router_redirect),
+    );
+}
+
+/// Uzumibi::Router itself; subclasses and mounted routers share the
+/// request it points at.
+fn uzumibi_router_base_class(vm: &mut VM) -> Result<Rc<RObject>, Error> {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .ok_or_else(|| Error::RuntimeError("Uzumibi module not found".to_string()))?;
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => return Err(Error::RuntimeError("Uzumibi must be a module".to_string())),
+    };
+    uzumibi_module
+        .get_const_by_name("Router")
+        .ok_or_else(|| Error::RuntimeError("Router class not found".to_string()))
+}
+
+fn uzumibi_halt_with(
+    vm: &mut VM,
+    status: Rc<RObject>,
+    body: Rc<RObject>,
+    headers: Rc<RObject>,
+) -> Result<Rc<RObject>, Error> {
+    let request = uzumibi_router_base_class(vm)?.get_ivar(CURRENT_REQUEST_KEY);
+    if request.is_falsy() {
+        return Err(Error::RuntimeError(
+            "halt and redirect can only be called while serving a request".to_string(),
+        ));
+    }
+    let halted = RObject::array(vec![status, headers, body]).to_refcount_assigned();
+    request.set_ivar(REQUEST_HALT_KEY, halted);
+    Err(halt_error())
+}
+
+/// Points the Router class at the request being served, so `halt` can
+/// leave its response there. Returns the request served before, which
+/// `uzumibi_leave_request` puts back; mounted and nested apps share it.
+pub(crate) fn uzumibi_enter_request(
+    vm: &mut VM,
+    request: &Rc<RObject>,
+) -> Result<Rc<RObject>, Error> {
+    let router_class = uzumibi_router_base_class(vm)?;
+    let previous = router_class.get_ivar(CURRENT_REQUEST_KEY);
+    router_class.set_ivar(CURRENT_REQUEST_KEY, request.clone());
+    Ok(previous)
+}
+
+pub(crate) fn uzumibi_leave_request(vm: &mut VM, previous: Rc<RObject>) -> Result<(), Error> {
+    uzumibi_router_base_class(vm)?.set_ivar(CURRENT_REQUEST_KEY, previous);
+    Ok(())
+}
+
+/// The error that unwinds a route block to the router. The tag is a
+/// `&'static str` in mrubyedge 1.x and a `String` in 2.x.
+#[allow(clippy::useless_conversion)]
+fn halt_error() -> Error {
+    Error::TaggedError(HALT_TAG.into(), "halt".to_string())
+}
+
+/// halt(status, body = nil, headers = {})
+fn uzumibi_router_halt(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let status = match args.first() {
+        Some(status) if matches!(status.value, RValue::Integer(_)) => status.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a status code as the first argument".to_string(),
+            ));
+        }
+    };
+    let body = match args.get(1) {
+        Some(body) if body.is_truthy() => body.clone(),
+        _ => RObject::string("".to_string()).to_refcount_assigned(),
+    };
+    let headers = match args.get(2) {
+        Some(headers) if matches!(headers.value, RValue::Hash(_)) => headers.clone(),
+        _ => mrb_hash_new(vm, &[])?,
+    };
+    uzumibi_halt_with(vm, status, body, headers)
+}
+
+/// redirect(location, status = 302)
+fn uzumibi_router_redirect(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (location, status) = redirect_arguments(vm, args.first(), args.get(1))?;
+    let status = RObject::integer(status as i64).to_refcount_assigned();
+    let headers = mrb_hash_new(vm, &[])?;
+    mrb_hash_set_index(
+        headers.clone(),
+        RObject::string("Location".to_string()).to_refcount_assigned(),
+        RObject::string(location).to_refcount_assigned(),
+    )?;
+    let body = RObject::string("".to_string()).to_refcount_assigned();
+    uzumibi_halt_with(vm, status, body, headers)
+}
+
+pub(crate) fn is_halt(error: &Error) -> bool {
+    matches!(error, Error::TaggedError(tag, _) if *tag == HALT_TAG)
+}
+
+/// Takes the response `halt` or `redirect` left on the request, if any.
+pub(crate) fn uzumibi_take_halt(
+    vm: &mut VM,
+    request: &Rc<RObject>,
+) -> Result<Option<Rc<RObject>>, Error> {
+    let halted = request.get_ivar(REQUEST_HALT_KEY);
+    request.set_ivar(REQUEST_HALT_KEY, RObject::nil().to_refcount_assigned());
+    let values = match &halted.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        _ => return Ok(None),
+    };
+    let response = uzumibi_response_new(vm);
+    uzumibi_response_return_values(&response, &values);
+    Ok(Some(response))
+}
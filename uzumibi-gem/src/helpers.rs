@@ -87,6 +87,202 @@ fn url_decode(input: &str) -> String {
     result
 }
 
+/// Escape a string for use inside a JSON string literal
+///
+/// Used for the small JSON bodies Uzumibi builds itself, which must not
+/// depend on the `use-json` feature.
+///
+/// # Example
+/// ```
+/// use uzumibi_gem::helpers::escape_json_string;
+///
+/// assert_eq!(escape_json_string("say \"hi\"\n"), "say \\\"hi\\\"\\n");
+/// ```
+pub fn escape_json_string(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Parse a `Cookie` request header into name and value pairs
+///
+/// Values are decoded with [`decode_cookie_value`].
+///
+/// # Example
+/// ```
+/// use uzumibi_gem::helpers::parse_cookie_header;
+///
+/// let cookies = parse_cookie_header("a=1; b=x%20y");
+/// assert_eq!(cookies, vec![
+///     ("a".to_string(), "1".to_string()),
+///     ("b".to_string(), "x y".to_string()),
+/// ]);
+/// ```
+pub fn parse_cookie_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), decode_cookie_value(value.trim())))
+        .collect()
+}
+
+/// Percent-encode a cookie value
+///
+/// Every byte outside the `cookie-octet` set of RFC 6265, and `%` itself,
+/// becomes `%XX`, so any String can be stored in a cookie.
+///
+/// # Example
+/// ```
+/// use uzumibi_gem::helpers::encode_cookie_value;
+///
+/// assert_eq!(encode_cookie_value("a b;c=d".as_bytes()), "a%20b%3Bc=d");
+/// ```
+pub fn encode_cookie_value(value: &[u8]) -> String {
+    let mut result = String::with_capacity(value.len());
+    for &byte in value {
+        match byte {
+            0x21 | 0x23..=0x24 | 0x26..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E => {
+                result.push(byte as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
+
+/// Decode a cookie value written by [`encode_cookie_value`]
+///
+/// Unlike form data, `+` is kept as is. Invalid `%` sequences are kept,
+/// and invalid UTF-8 is replaced.
+///
+/// # Example
+/// ```
+/// use uzumibi_gem::helpers::decode_cookie_value;
+///
+/// assert_eq!(decode_cookie_value("a%20b+c"), "a b+c");
+/// ```
+pub fn decode_cookie_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(byte) => {
+                result.push(byte);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Format seconds since the Unix epoch as an HTTP date (IMF-fixdate)
+///
+/// # Example
+/// ```
+/// use uzumibi_gem::helpers::format_http_date;
+///
+/// assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+/// ```
+pub fn format_http_date(unix_seconds: i64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = unix_seconds.div_euclid(86400);
+    let seconds = unix_seconds.rem_euclid(86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Format a `Content-Disposition` header value (RFC 6266)
+///
+/// The filename is quoted, with characters outside printable ASCII
+/// replaced by `_`. A filename that is not plain ASCII is also sent as a
+/// UTF-8 `filename*` parameter, which browsers prefer.
+///
+/// # Example
+/// ```
+/// use uzumibi_gem::helpers::content_disposition;
+///
+/// assert_eq!(content_disposition("inline", None), "inline");
+/// assert_eq!(
+///     content_disposition("attachment", Some("report.csv")),
+///     "attachment; filename=\"report.csv\""
+/// );
+/// assert_eq!(
+///     content_disposition("attachment", Some("résumé.pdf")),
+///     "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+/// );
+/// ```
+pub fn content_disposition(disposition: &str, filename: Option<&str>) -> String {
+    let Some(filename) = filename else {
+        return disposition.to_string();
+    };
+    let mut value = format!("{}; filename=\"", disposition);
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                value.push('\\');
+                value.push(c);
+            }
+            ' '..='~' => value.push(c),
+            _ => value.push('_'),
+        }
+    }
+    value.push('"');
+    if !filename.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        value.push_str("; filename*=UTF-8''");
+        for &b in filename.as_bytes() {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                value.push(b as char);
+            } else {
+                value.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    value
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -160,4 +356,44 @@ mod tests {
         assert_eq!(url_decode("test%1"), "test%1");
         assert_eq!(url_decode("test%GG"), "test%GG");
     }
+
+    #[test]
+    fn test_escape_json_string() {
+        assert_eq!(escape_json_string("plain"), "plain");
+        assert_eq!(escape_json_string("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(escape_json_string("line\n\u{1}"), "line\\n\\u0001");
+    }
+
+    #[test]
+    fn test_cookie_value_round_trip() {
+        let value = "100% \"quoted\", semi;colon\\ ünï";
+        let encoded = encode_cookie_value(value.as_bytes());
+        assert!(!encoded.contains([' ', '"', ',', ';', '\\']));
+        assert_eq!(decode_cookie_value(&encoded), value);
+        assert_eq!(decode_cookie_value("50%"), "50%");
+        assert_eq!(decode_cookie_value("%zz"), "%zz");
+    }
+
+    #[test]
+    fn test_format_http_date() {
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(format_http_date(-1), "Wed, 31 Dec 1969 23:59:59 GMT");
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("attachment", Some("a \"b\"\\c.txt")),
+            "attachment; filename=\"a \\\"b\\\"\\\\c.txt\""
+        );
+        assert_eq!(
+            content_disposition("inline", Some("日本.png")),
+            "inline; filename=\"__.png\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.png"
+        );
+        assert_eq!(
+            content_disposition("attachment", Some("tab\there")),
+            "attachment; filename=\"tab_here\"; filename*=UTF-8''tab%09here"
+        );
+    }
 }
@@ -13,7 +13,11 @@ use mrubyedge::{
     },
 };
 
-use crate::{request::*, response::*};
+use crate::{
+    constraints::*, cookies::*, cors::*, error_handlers::*, filters::*, halt::*, isolation::*,
+    middleware::*, mount::*, named_routes::*, negotiation::*, request::*, response::*,
+    route_table::*, session::*, sse::*, streaming::*, websocket::*,
+};
 
 extern crate mrubyedge;
 #[cfg(feature = "use-json")]
@@ -29,7 +33,27 @@ extern crate uzumibi_art_router;
 ///   module Uzumibi
 ///     class Router
 ///       def self.routes() -> Hash
-///       def self.get(path: String, handler: Proc) -> String
+///       def self.route_table() -> Array[[String, String, Symbol?, Hash[Symbol, untyped]]]
+///       def self.get(path: String, handler: Proc, as: Symbol?) -> String
+///       def self.patch(path: String, handler: Proc) -> String
+///       def self.route(methods: Array[String | Symbol] | String | Symbol, path: String, handler: Proc) -> String
+///       def self.any(path: String, handler: Proc) -> String
+///       def self.cors(origins: Array[String] | String, **options) -> nil
+///       def self.use(middleware: Class, options: untyped?) -> nil
+///       def self.before(path_pattern: String?, handler: Proc) -> nil
+///       def self.after(path_pattern: String?, handler: Proc) -> nil
+///       def self.error(*exception_classes: Class, handler: Proc) -> nil
+///       def self.rescue_from(*exception_classes: Class, handler: Proc) -> nil
+///       def self.not_found(handler: Proc) -> nil
+///       def self.namespace(prefix: String, block: Proc) -> nil
+///       def self.mount(router: singleton(Router), at: String) -> nil
+///       def self.host(pattern: String | Regexp | Array[String], block: Proc) -> nil
+///       def self.constraints(constraints: Hash[Symbol, untyped], block: Proc) -> nil
+///       def self.path_for(name: Symbol | String, params: Hash[Symbol, untyped]?) -> String
+///       def self.halt(status: Integer, body: String?, headers: Hash[String, String]?) -> bot
+///       def self.redirect(location: String, status: Integer?) -> bot
+///       def self.respond_to(req: Request, res: Response?, block: Proc) -> untyped
+///       def self.isolate_requests(enabled: bool) -> nil
 ///       def initialize_request(size: Integer) -> SharedMemory
 ///       def set_request(request: Request)
 ///       def start_request() -> Response
@@ -68,6 +92,12 @@ pub fn init_uzumibi(vm: &mut VM) {
         "put",
         Box::new(uzumibi_router_put),
     );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "patch",
+        Box::new(uzumibi_router_patch),
+    );
     mrb_define_class_cmethod(
         vm,
         router_class.clone(),
@@ -86,6 +116,96 @@ pub fn init_uzumibi(vm: &mut VM) {
         "options",
         Box::new(uzumibi_router_options),
     );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "route",
+        Box::new(uzumibi_router_route),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "any",
+        Box::new(uzumibi_router_any),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "cors",
+        Box::new(uzumibi_router_cors),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "use",
+        Box::new(uzumibi_router_use),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "before",
+        Box::new(uzumibi_router_before),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "after",
+        Box::new(uzumibi_router_after),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "error",
+        Box::new(uzumibi_router_error),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "rescue_from",
+        Box::new(uzumibi_router_error),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "not_found",
+        Box::new(uzumibi_router_not_found),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "namespace",
+        Box::new(uzumibi_router_namespace),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "mount",
+        Box::new(uzumibi_router_mount),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "route_table",
+        Box::new(uzumibi_router_route_table),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "host",
+        Box::new(uzumibi_router_host),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "constraints",
+        Box::new(uzumibi_router_constraints),
+    );
+    mrb_define_class_cmethod(
+        vm,
+        router_class.clone(),
+        "isolate_requests",
+        Box::new(uzumibi_router_isolate_requests),
+    );
 
     mrb_define_cmethod(
         vm,
@@ -115,52 +235,197 @@ pub fn init_uzumibi(vm: &mut VM) {
 
     init_uzumibi_response(vm);
     init_uzumibi_request(vm);
+    init_uzumibi_cookies(vm);
+    init_uzumibi_middleware(vm);
+    init_uzumibi_named_routes(vm);
+    init_uzumibi_halt(vm);
+    init_uzumibi_negotiation(vm);
+    init_uzumibi_streaming(vm);
+    init_uzumibi_sse(vm);
+    init_uzumibi_websocket(vm);
+    init_uzumibi_session(vm);
 
     uzumibi_art_router::init_uzumibi_art_router(vm);
 }
 
-const ROUTES_KEY_GET: &str = "@_art_router_get";
-const ROUTES_KEY_POST: &str = "@_art_router_post";
-const ROUTES_KEY_PUT: &str = "@_art_router_put";
-const ROUTES_KEY_DELETE: &str = "@_art_router_delete";
-const ROUTES_KEY_OPTIONS: &str = "@_art_router_options";
+const ROUTERS_KEY: &str = "@_art_routers";
 const REQUEST_KEY: &str = "@_request";
 const REQUEST_BUF_KEY: &str = "@_request_buf";
+const NOT_FOUND_KEY: &str = "@_not_found";
+/// Hash of "method pattern" to the candidates Array stored in the ArtRouter.
+const ROUTE_CANDIDATES_KEY: &str = "@_route_candidates";
+
+/// Router table key for routes registered with `any`.
+/// They are consulted after the method-specific router.
+const ANY_METHOD_KEY: &str = "*";
+
+/// A matched route: the matched value and the path params Hash.
+type RouteMatch = (Rc<RObject>, Rc<RObject>);
+
+/// Returns the router table key for a request method.
+/// HEAD shares the GET router; every other method, including custom
+/// verbs, gets its own entry and never falls back to GET.
+fn get_router_key_for_method(method: &str) -> String {
+    let method = method.trim().to_ascii_uppercase();
+    match method.as_str() {
+        "HEAD" => "GET".to_string(),
+        _ => method,
+    }
+}
+
+fn uzumibi_router_table(vm: &mut VM, klass: &Rc<RObject>) -> Result<Rc<RObject>, Error> {
+    let table = klass.get_ivar(ROUTERS_KEY);
+    if table.is_falsy() {
+        let table = mrb_hash_new(vm, &[])?;
+        klass.set_ivar(ROUTERS_KEY, table.clone());
+        Ok(table)
+    } else {
+        Ok(table)
+    }
+}
 
-fn get_router_key_for_method(method: &str) -> &'static str {
-    match method {
-        "GET" | "HEAD" => ROUTES_KEY_GET,
-        "POST" => ROUTES_KEY_POST,
-        "PUT" => ROUTES_KEY_PUT,
-        "DELETE" => ROUTES_KEY_DELETE,
-        "OPTIONS" => ROUTES_KEY_OPTIONS,
-        _ => ROUTES_KEY_GET,
+/// Lists `(method, ArtRouter)` pairs in registration order.
+fn uzumibi_router_table_entries(klass: &Rc<RObject>) -> Result<Vec<(String, Rc<RObject>)>, Error> {
+    let table = klass.get_ivar(ROUTERS_KEY);
+    let mut entries = Vec::new();
+    if let RValue::Hash(h) = &table.value {
+        let table_h = h.borrow();
+        for (_, (key_obj, value_obj)) in table_h.iter() {
+            let key: String = key_obj.as_ref().try_into()?;
+            entries.push((key, value_obj.clone()));
+        }
     }
+    Ok(entries)
+}
+
+fn uzumibi_router_lookup_router(
+    klass: &Rc<RObject>,
+    router_key: &str,
+) -> Result<Option<Rc<RObject>>, Error> {
+    Ok(uzumibi_router_table_entries(klass)?
+        .into_iter()
+        .find(|(key, _)| key == router_key)
+        .map(|(_, router)| router))
+}
+
+/// Creates an Uzumibi::ArtRouter instance
+pub(crate) fn uzumibi_art_router_new(vm: &mut VM) -> Result<Rc<RObject>, Error> {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .ok_or_else(|| Error::RuntimeError("Uzumibi module not found".to_string()))?;
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => return Err(Error::RuntimeError("Uzumibi must be a module".to_string())),
+    };
+    let art_router_class = uzumibi_module
+        .get_const_by_name("ArtRouter")
+        .ok_or_else(|| Error::RuntimeError("ArtRouter class not found".to_string()))?;
+    mrb_funcall(vm, Some(art_router_class), "new", &[])
 }
 
 fn uzumibi_router_get_router_by_method(vm: &mut VM, method: &str) -> Result<Rc<RObject>, Error> {
     let klass = vm.getself()?;
     let router_key = get_router_key_for_method(method);
-    let router = if klass.get_ivar(router_key).is_falsy() {
-        // Create an ArtRouter instance
-        let uzumibi = vm
-            .get_const_by_name("Uzumibi")
-            .ok_or_else(|| Error::RuntimeError("Uzumibi module not found".to_string()))?;
-        let uzumibi_module = match &uzumibi.as_ref().value {
-            RValue::Module(m) => m.clone(),
-            _ => return Err(Error::RuntimeError("Uzumibi must be a module".to_string())),
-        };
-        let art_router_class = uzumibi_module
-            .get_const_by_name("ArtRouter")
-            .ok_or_else(|| Error::RuntimeError("ArtRouter class not found".to_string()))?;
-        let router = mrb_funcall(vm, Some(art_router_class), "new", &[])?;
-        klass.set_ivar(router_key, router.clone());
-        router
+    if let Some(router) = uzumibi_router_lookup_router(&klass, &router_key)? {
+        return Ok(router);
+    }
+
+    let router = uzumibi_art_router_new(vm)?;
+    let table = uzumibi_router_table(vm, &klass)?;
+    mrb_hash_set_index(
+        table,
+        RObject::string(router_key).to_refcount_assigned(),
+        router.clone(),
+    )?;
+
+    Ok(router)
+}
+
+/// Calls ArtRouter#get_route and unpacks its `[route, params]` result.
+pub(crate) fn uzumibi_router_match(
+    vm: &mut VM,
+    art_router: Rc<RObject>,
+    path: &str,
+) -> Result<Option<RouteMatch>, Error> {
+    let path_obj = RObject::string(path.to_string()).to_refcount_assigned();
+    let result = mrb_funcall(vm, Some(art_router), "get_route", &[path_obj])?;
+    match &result.value {
+        RValue::Array(arr) => {
+            let arr = arr.borrow();
+            if arr.len() == 2 {
+                Ok(Some((arr[0].clone(), arr[1].clone())))
+            } else {
+                Ok(None)
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Finds the route for a method and path, trying the method-specific
+/// router first and then routes registered with `any`. A pattern whose
+/// constraints reject the request does not count as a match.
+fn uzumibi_router_find_route(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    method: &str,
+    path: &str,
+    request: &Rc<RObject>,
+    include_any: bool,
+) -> Result<Option<RouteMatch>, Error> {
+    let router_key = get_router_key_for_method(method);
+    let keys = if include_any {
+        vec![router_key.as_str(), ANY_METHOD_KEY]
     } else {
-        klass.get_ivar(router_key)
+        vec![router_key.as_str()]
     };
+    for key in keys {
+        if let Some(art_router) = uzumibi_router_lookup_router(klass, key)?
+            && let Some((candidates, params)) = uzumibi_router_match(vm, art_router, path)?
+            && let Some(handler) = uzumibi_select_candidate(vm, &candidates, request)?
+        {
+            return Ok(Some((handler, params)));
+        }
+    }
+    Ok(None)
+}
 
-    Ok(router)
+/// Collects the methods whose routers match the path, for the `Allow`
+/// header of a 405 response. GET implies HEAD.
+fn uzumibi_router_allowed_methods(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    path: &str,
+    request: &Rc<RObject>,
+) -> Result<Vec<String>, Error> {
+    let mut allowed = Vec::new();
+    for (method, art_router) in uzumibi_router_table_entries(klass)? {
+        if method == ANY_METHOD_KEY {
+            continue;
+        }
+        if let Some((candidates, _)) = uzumibi_router_match(vm, art_router, path)?
+            && uzumibi_select_candidate(vm, &candidates, request)?.is_some()
+        {
+            if method == "GET" {
+                allowed.push("HEAD".to_string());
+            }
+            allowed.push(method);
+        }
+    }
+    // The router table is a Hash, so put the methods in a stable order
+    allowed.sort_by_key(|method| allowed_method_rank(method));
+    Ok(allowed)
+}
+
+/// Sort key for the `Allow` header: HEAD and GET first, then the other
+/// standard methods, then custom ones by name.
+fn allowed_method_rank(method: &str) -> (usize, String) {
+    const ORDER: [&str; 6] = ["HEAD", "GET", "POST", "PUT", "PATCH", "DELETE"];
+    let rank = ORDER
+        .iter()
+        .position(|m| *m == method)
+        .unwrap_or(ORDER.len());
+    (rank, method.to_string())
 }
 
 fn uzumibi_router_routes(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
@@ -168,16 +433,12 @@ fn uzumibi_router_routes(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObjec
     let klass = vm.getself()?;
     let hash = mrb_hash_new(vm, &[])?;
 
-    for method in ["GET", "POST", "PUT", "DELETE", "OPTIONS"] {
-        let router_key = get_router_key_for_method(method);
-        if !klass.get_ivar(router_key).is_falsy() {
-            let router = klass.get_ivar(router_key);
-            mrb_hash_set_index(
-                hash.clone(),
-                RObject::string(method.to_string()).to_refcount_assigned(),
-                router,
-            )?;
-        }
+    for (method, router) in uzumibi_router_table_entries(&klass)? {
+        mrb_hash_set_index(
+            hash.clone(),
+            RObject::string(method).to_refcount_assigned(),
+            router,
+        )?;
     }
 
     Ok(hash)
@@ -187,22 +448,112 @@ fn uzumibi_router_set_route_with_method(
     vm: &mut VM,
     method: &str,
     args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let options = uzumibi_route_options(vm);
+    uzumibi_router_add_route(vm, method, args, &options)
+}
+
+/// Keyword options given to a route definition, such as `as:`.
+/// They must be read before any other method is called on the VM.
+pub(crate) fn uzumibi_route_options(vm: &mut VM) -> Vec<(String, Rc<RObject>)> {
+    match vm.get_kwargs() {
+        Some(kwargs) => kwargs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub(crate) fn uzumibi_router_add_route(
+    vm: &mut VM,
+    method: &str,
+    args: &[Rc<RObject>],
+    options: &[(String, Rc<RObject>)],
 ) -> Result<Rc<RObject>, Error> {
     if args.len() != 2 || args[args.len() - 1].is_falsy() {
         return Err(Error::ArgumentError(
             "Expected 2 arguments: path, handler".to_string(),
         ));
     }
+    let klass = vm.getself()?;
+    let path: String = args[0].as_ref().try_into()?;
+    let path = uzumibi_router_prefixed_path(&klass, &path)?;
+    uzumibi_router_register_name(vm, &klass, &path, options)?;
+
+    let constraints = uzumibi_route_constraints(vm, &klass, options)?;
+    let mut recorded: Vec<(String, Rc<RObject>)> = options
+        .iter()
+        .filter(|(k, _)| k != "constraints")
+        .cloned()
+        .collect();
+    if constraints.is_truthy() {
+        recorded.push(("constraints".to_string(), constraints.clone()));
+    }
+    uzumibi_router_record_route(vm, &klass, method, &path, &recorded)?;
+
+    // Routes sharing a pattern are kept as candidates under one ArtRouter entry
+    let candidates_key = format!("{} {}", get_router_key_for_method(method), path);
+    let candidates = uzumibi_router_route_candidates(vm, &klass, &candidates_key)?;
+    uzumibi_candidates_push(&candidates, constraints, args[1].clone())?;
+
     let art_router = uzumibi_router_get_router_by_method(vm, method)?;
-    let path = args[0].clone();
-    let handler = args[1].clone();
+    let path = RObject::string(path).to_refcount_assigned();
 
     // Call ArtRouter's set_route method
-    mrb_funcall(vm, Some(art_router), "set_route", &[path.clone(), handler])?;
+    mrb_funcall(
+        vm,
+        Some(art_router),
+        "set_route",
+        &[path.clone(), candidates],
+    )?;
 
     Ok(path)
 }
 
+/// Returns the candidates Array for a "method pattern" key, creating it.
+fn uzumibi_router_route_candidates(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    key: &str,
+) -> Result<Rc<RObject>, Error> {
+    let mut table = klass.get_ivar(ROUTE_CANDIDATES_KEY);
+    if table.is_falsy() {
+        table = mrb_hash_new(vm, &[])?;
+        klass.set_ivar(ROUTE_CANDIDATES_KEY, table.clone());
+    }
+    let key = RObject::string(key.to_string()).to_refcount_assigned();
+    let existing = mrb_funcall(vm, Some(table.clone()), "[]", std::slice::from_ref(&key))?;
+    if existing.is_truthy() {
+        return Ok(existing);
+    }
+    let candidates = RObject::array(vec![]).to_refcount_assigned();
+    mrb_hash_set_index(table, key, candidates.clone())?;
+    Ok(candidates)
+}
+
+/// Converts a method name or an Array of them (Strings or Symbols)
+/// into upper-case method names.
+fn uzumibi_method_names(vm: &mut VM, methods: Rc<RObject>) -> Result<Vec<String>, Error> {
+    let items = match &methods.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        _ => vec![methods.clone()],
+    };
+    let mut names = Vec::with_capacity(items.len());
+    for item in items {
+        let name = mrb_funcall(vm, Some(item), "to_s", &[])?;
+        let name: String = name.as_ref().try_into()?;
+        let name = name.trim().to_ascii_uppercase();
+        if name.is_empty() {
+            return Err(Error::ArgumentError(
+                "HTTP method name must not be empty".to_string(),
+            ));
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
 fn uzumibi_router_get(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
     uzumibi_router_set_route_with_method(vm, "GET", args)
 }
@@ -215,19 +566,53 @@ fn uzumibi_router_put(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>,
     uzumibi_router_set_route_with_method(vm, "PUT", args)
 }
 
+fn uzumibi_router_patch(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_router_set_route_with_method(vm, "PATCH", args)
+}
+
 fn uzumibi_router_delete(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
     uzumibi_router_set_route_with_method(vm, "DELETE", args)
 }
 
 fn uzumibi_router_head(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
     // HEAD uses the same router as GET
-    uzumibi_router_set_route_with_method(vm, "GET", args)
+    uzumibi_router_set_route_with_method(vm, "HEAD", args)
 }
 
 fn uzumibi_router_options(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
     uzumibi_router_set_route_with_method(vm, "OPTIONS", args)
 }
 
+/// route(methods, path) { |req, res| ... } -> path
+fn uzumibi_router_route(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    if args.len() != 3 || args[args.len() - 1].is_falsy() {
+        return Err(Error::ArgumentError(
+            "Expected 3 arguments: methods, path, handler".to_string(),
+        ));
+    }
+    let options = uzumibi_route_options(vm);
+    let methods = uzumibi_method_names(vm, args[0].clone())?;
+    for method in methods {
+        uzumibi_router_add_route(vm, &method, &args[1..], &options)?;
+    }
+    Ok(args[1].clone())
+}
+
+/// any(path) { |req, res| ... } -> path
+fn uzumibi_router_any(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_router_set_route_with_method(vm, ANY_METHOD_KEY, args)
+}
+
+/// not_found { |req, res| ... } -> nil
+fn uzumibi_router_not_found(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let handler = match args.last() {
+        Some(handler) if matches!(handler.value, RValue::Proc(_)) => handler.clone(),
+        _ => return Err(Error::ArgumentError("Expected a block".to_string())),
+    };
+    vm.getself()?.set_ivar(NOT_FOUND_KEY, handler);
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
 fn uzumibi_initialize_request(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
     let shared_memory = mrb_shared_memory_new(vm, args)?;
     vm.getself()?
@@ -246,7 +631,7 @@ fn uzumibi_set_request(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>,
 fn uzumibi_start_request(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
     let app = vm.getself()?;
     let request_obj = app.get_ivar(REQUEST_KEY);
-    let mut request = match &request_obj.value {
+    let request = match &request_obj.value {
         RValue::Nil => {
             let request_buf = vm.getself()?.get_ivar(REQUEST_BUF_KEY);
             uzumibi_construct_request(request_buf)?
@@ -258,66 +643,202 @@ fn uzumibi_start_request(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObjec
     };
 
     let self_class = mrb_funcall(vm, app.into(), "class", &[])?;
-    let is_head_request = request.method == "HEAD";
-
-    // For HEAD requests, use GET router
-    let lookup_method = if is_head_request {
-        "GET"
-    } else {
-        &request.method
+    let cors = CorsPolicy::from_router_class(vm, &self_class)?;
+    let origin = request.header("origin").map(|v| v.to_string());
+    let is_head_request = request.method.eq_ignore_ascii_case("HEAD");
+
+    let request = request.into_robject(vm);
+    request.set_ivar(REQUEST_ROUTER_CLASS_KEY, self_class.clone());
+    let response = uzumibi_response_new(vm);
+
+    // Errors raised by middlewares end up here; route errors are
+    // rescued inside the chain so that middlewares see the response
+    let previous = uzumibi_enter_request(vm, &request)?;
+    let served = match uzumibi_run_middlewares(vm, &self_class, request.clone(), response.clone()) {
+        Err(e) => uzumibi_rescue_error(vm, &self_class, e, &request, &response),
+        Ok(()) => Ok(()),
     };
-    let router_key = get_router_key_for_method(lookup_method);
-    let art_router = self_class.get_ivar(router_key);
+    uzumibi_leave_request(vm, previous)?;
+    served?;
+
+    // For HEAD requests, clear the body but keep headers and status
+    if is_head_request {
+        mrb_funcall(
+            vm,
+            Some(response.clone()),
+            "body=",
+            &[RObject::string("".to_string()).to_refcount_assigned()],
+        )?;
+        response.set_ivar(
+            RESPONSE_STREAM_IVAR_KEY,
+            RObject::nil().to_refcount_assigned(),
+        );
+    }
 
-    if art_router.is_falsy() {
-        return uzumibi_return_notfound(vm);
+    if let Some(cors) = &cors {
+        cors.apply(vm, &response, origin.as_deref(), Vec::new())?;
     }
 
-    // Get route and params from ArtRouter's get_route method
-    let path_obj = RObject::string(request.path.clone()).to_refcount_assigned();
-    let result = mrb_funcall(vm, Some(art_router), "get_route", &[path_obj])?;
+    Ok(response)
+}
 
-    // result is an array [route, params] or an empty array
-    match &result.value {
-        RValue::Array(arr) => {
-            let arr = arr.borrow();
-            if arr.len() == 2 {
-                let route = arr[0].clone();
-                let params_hash = arr[1].clone();
-
-                // Merge params into request
-                if let RValue::Hash(h) = &params_hash.value {
-                    let params_h = h.borrow();
-                    for (_, (key_obj, value_obj)) in params_h.iter() {
-                        let key: String = key_obj.as_ref().try_into()?;
-                        let value: String = value_obj.as_ref().try_into()?;
-                        request.params.insert(key, value);
-                    }
+/// Routes the request object and fills in the response object.
+/// This is the innermost step of the middleware chain.
+pub(crate) fn uzumibi_dispatch_route(
+    vm: &mut VM,
+    self_class: &Rc<RObject>,
+    request: Rc<RObject>,
+    response: Rc<RObject>,
+) -> Result<(), Error> {
+    let (method, path) = uzumibi_request_method_and_path(&request)?;
+
+    // A CORS preflight for a path with routes gets the automatic response
+    // before `any` routes see it; an explicit OPTIONS route still wins
+    let preflight = method.eq_ignore_ascii_case("OPTIONS")
+        && uzumibi_request_header(&request, "access-control-request-method")?.is_some();
+    if preflight
+        && uzumibi_router_find_route(vm, self_class, &method, &path, &request, false)?.is_none()
+    {
+        let allowed = uzumibi_router_allowed_methods(vm, self_class, &path, &request)?;
+        if !allowed.is_empty() {
+            let preflight_response = uzumibi_return_preflight(vm, self_class, &request, allowed)?;
+            uzumibi_response_replace(&response, &preflight_response);
+            return Ok(());
+        }
+    }
+
+    // HEAD requests are looked up in the GET router
+    let found = uzumibi_router_find_route(vm, self_class, &method, &path, &request, true)?;
+    let Some((route, params_hash)) = found else {
+        if uzumibi_dispatch_mounted(vm, self_class, &path, &request, &response)? {
+            return Ok(());
+        }
+        // The path may still be registered under other methods
+        let allowed = uzumibi_router_allowed_methods(vm, self_class, &path, &request)?;
+        let fallback = if allowed.is_empty() {
+            let handler = self_class.get_ivar(NOT_FOUND_KEY);
+            if handler.is_truthy() {
+                if let Err(e) = uzumibi_run_not_found(vm, handler, &request, &response) {
+                    uzumibi_rescue_error(vm, self_class, e, &request, &response)?;
                 }
+                return Ok(());
+            }
+            uzumibi_return_notfound(vm)?
+        } else if method.eq_ignore_ascii_case("OPTIONS") {
+            uzumibi_return_preflight(vm, self_class, &request, allowed)?
+        } else {
+            uzumibi_return_method_not_allowed(vm, &allowed)?
+        };
+        uzumibi_response_replace(&response, &fallback);
+        return Ok(());
+    };
 
-                let request = request.into_robject(vm);
-                let response = uzumibi_response_new(vm);
+    uzumibi_request_merge_route_params(vm, &request, &params_hash)?;
 
-                mrb_funcall(vm, Some(route), "call", &[request, response.clone()])?;
+    if let Err(e) = uzumibi_run_route(vm, self_class, &path, route, &request, &response) {
+        uzumibi_rescue_error(vm, self_class, e, &request, &response)?;
+    }
+    Ok(())
+}
 
-                // For HEAD requests, clear the body but keep headers and status
-                if is_head_request {
-                    mrb_funcall(
-                        vm,
-                        Some(response.clone()),
-                        "body=",
-                        &[RObject::string("".to_string()).to_refcount_assigned()],
-                    )?;
-                }
+/// Runs the router's `not_found` handler. The status defaults to 404
+/// when the handler does not set one.
+fn uzumibi_run_not_found(
+    vm: &mut VM,
+    handler: Rc<RObject>,
+    request: &Rc<RObject>,
+    response: &Rc<RObject>,
+) -> Result<(), Error> {
+    mrb_funcall(
+        vm,
+        Some(handler),
+        "call",
+        &[request.clone(), response.clone()],
+    )?;
+    if !uzumibi_response_is_filled(response) {
+        mrb_funcall(
+            vm,
+            Some(response.clone()),
+            "status_code=",
+            &[RObject::integer(404).to_refcount_assigned()],
+        )?;
+    }
+    Ok(())
+}
 
-                Ok(response)
-            } else {
-                // Route not found
-                uzumibi_return_notfound(vm)
-            }
+/// Runs the before filters, the route proc and the after filters.
+fn uzumibi_run_route(
+    vm: &mut VM,
+    self_class: &Rc<RObject>,
+    path: &str,
+    route: Rc<RObject>,
+    request: &Rc<RObject>,
+    response: &Rc<RObject>,
+) -> Result<(), Error> {
+    let before_filters = uzumibi_filters_for(vm, self_class, FilterKind::Before, path)?;
+    let after_filters = uzumibi_filters_for(vm, self_class, FilterKind::After, path)?;
+
+    // A before filter that sets the status code skips the route
+    let mut handled = false;
+    for filter in before_filters {
+        mrb_funcall(
+            vm,
+            Some(filter),
+            "call",
+            &[request.clone(), response.clone()],
+        )?;
+        if uzumibi_response_is_filled(response) {
+            handled = true;
+            break;
         }
-        _ => uzumibi_return_notfound(vm),
     }
+    if !handled && uzumibi_is_websocket_route(&route) {
+        uzumibi_run_websocket_route(vm, route, request, response)?;
+    } else if !handled {
+        let value = mrb_funcall(
+            vm,
+            Some(route),
+            "call",
+            &[request.clone(), response.clone()],
+        )?;
+        uzumibi_response_coerce(vm, response, value)?;
+    }
+    for filter in after_filters {
+        mrb_funcall(
+            vm,
+            Some(filter),
+            "call",
+            &[request.clone(), response.clone()],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Answers OPTIONS for a path without an explicit OPTIONS route.
+/// With a CORS policy, a preflight request also gets the
+/// `Access-Control-Allow-*` headers for the methods registered on the path.
+fn uzumibi_return_preflight(
+    vm: &mut VM,
+    self_class: &Rc<RObject>,
+    request: &Rc<RObject>,
+    mut allowed: Vec<String>,
+) -> Result<Rc<RObject>, Error> {
+    allowed.push("OPTIONS".to_string());
+    let response = uzumibi_return_options(vm, &allowed)?;
+
+    let cors = CorsPolicy::from_router_class(vm, self_class)?;
+    let origin = uzumibi_request_header(request, "origin")?;
+    let request_method = uzumibi_request_header(request, "access-control-request-method")?;
+    if let (Some(cors), Some(origin)) = (cors, origin)
+        && request_method.is_some()
+    {
+        let request_headers = uzumibi_request_header(request, "access-control-request-headers")?;
+        let headers = cors.preflight_headers(&origin, &allowed, request_headers.as_deref());
+        cors.apply(vm, &response, Some(&origin), headers)?;
+    }
+
+    Ok(response)
 }
 
 fn uzumibi_start_request_and_return_shared_memory(
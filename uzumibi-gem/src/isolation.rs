@@ -0,0 +1,186 @@
+//! This module implements per-request isolation of VM state for hosts
+//! that keep one VM across requests (Cloudflare, Fastly, Spin, Service
+//! Worker, Web Worker).
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.isolate_requests(enabled: bool) -> nil
+//! ```
+//!
+//! A host captures a [`VmSnapshot`] right after `vm.run()` has evaluated
+//! the app and restores it after every request. The snapshot covers
+//! globals, constants (top-level and nested in classes and modules), the
+//! instance variables of every object reachable from them, including class
+//! ivars such as memoised `@cache ||= ...`, and the contents of reachable
+//! Arrays and Hashes. Variables captured by blocks and in-place changes to
+//! Strings are not covered.
+//!
+//! Isolation is on by default; `isolate_requests false` in the app's router
+//! class keeps the state of one request visible to the next. A restore
+//! walks every captured object, so it costs time in proportion to the size
+//! of the app on every request.
+//!
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+const ISOLATE_REQUESTS_KEY: &str = "@_isolate_requests";
+
+/// isolate_requests(enabled = true) -> nil
+pub(crate) fn uzumibi_router_isolate_requests(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let enabled = args.first().is_none_or(|flag| flag.is_truthy());
+    let klass = vm.getself()?;
+    klass.set_ivar(
+        ISOLATE_REQUESTS_KEY,
+        RObject::integer(enabled as i64).to_refcount_assigned(),
+    );
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// State of one object reachable from globals or constants.
+struct ObjectState {
+    object: Rc<RObject>,
+    ivars: HashMap<String, Rc<RObject>>,
+    /// Shallow copy of an Array or Hash
+    contents: Option<Rc<RObject>>,
+    /// Constants of a class or module
+    consts: Option<HashMap<String, Rc<RObject>>>,
+}
+
+/// VM state captured after initialization, restored after each request.
+pub struct VmSnapshot {
+    globals: HashMap<String, Rc<RObject>>,
+    consts: HashMap<String, Rc<RObject>>,
+    objects: Vec<ObjectState>,
+}
+
+impl VmSnapshot {
+    /// Captures the state of an initialized VM, or returns None when the
+    /// app's router class opted out with `isolate_requests false`.
+    pub fn capture(vm: &mut VM) -> Result<Option<Self>, Error> {
+        if let Some(app) = vm.globals.get("$APP").cloned() {
+            let klass = mrb_funcall(vm, Some(app), "class", &[])?;
+            if let RValue::Integer(0) = klass.get_ivar(ISOLATE_REQUESTS_KEY).value {
+                return Ok(None);
+            }
+        }
+
+        let mut snapshot = VmSnapshot {
+            globals: vm.globals.clone(),
+            consts: vm.consts.clone(),
+            objects: Vec::new(),
+        };
+        let mut visited = HashSet::new();
+        let mut pending: Vec<Rc<RObject>> = snapshot
+            .globals
+            .values()
+            .chain(snapshot.consts.values())
+            .cloned()
+            .collect();
+        pending.push(RObject::class(vm.object_class.clone(), vm));
+
+        while let Some(object) = pending.pop() {
+            if !visited.insert(Rc::as_ptr(&object) as usize) {
+                continue;
+            }
+            let state = snapshot_object(vm, &object)?;
+            pending.extend(state.ivars.values().cloned());
+            if let Some(contents) = &state.contents {
+                pending.extend(container_items(contents));
+            }
+            if let Some(consts) = &state.consts {
+                pending.extend(consts.values().cloned());
+            }
+            snapshot.objects.push(state);
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// Puts the VM back into the captured state. Objects created since
+    /// then are dropped unless something outside the VM still holds them.
+    pub fn restore(&self, vm: &mut VM) {
+        vm.globals = self.globals.clone();
+        vm.consts = self.consts.clone();
+        vm.exception.take();
+        for state in &self.objects {
+            *state.object.ivar.borrow_mut() = state.ivars.clone();
+            if let Some(contents) = &state.contents {
+                match (&state.object.value, &contents.value) {
+                    (RValue::Array(dst), RValue::Array(src)) => {
+                        *dst.borrow_mut() = src.borrow().clone();
+                    }
+                    (RValue::Hash(dst), RValue::Hash(src)) => {
+                        *dst.borrow_mut() = src.borrow().clone();
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(consts) = &state.consts {
+                match &state.object.value {
+                    RValue::Class(c) => *c.consts.borrow_mut() = consts.clone(),
+                    RValue::Module(m) => *m.consts.borrow_mut() = consts.clone(),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn snapshot_object(vm: &mut VM, object: &Rc<RObject>) -> Result<ObjectState, Error> {
+    let contents = match &object.value {
+        RValue::Array(arr) => Some(RObject::array(arr.borrow().clone()).to_refcount_assigned()),
+        RValue::Hash(h) => {
+            let entries: Vec<_> = h
+                .borrow()
+                .iter()
+                .map(|(_, (k, v))| (k.clone(), v.clone()))
+                .collect();
+            let copy = mrb_hash_new(vm, &[])?;
+            for (key, value) in entries {
+                mrb_hash_set_index(copy.clone(), key, value)?;
+            }
+            Some(copy)
+        }
+        _ => None,
+    };
+    let consts = match &object.value {
+        RValue::Class(c) => Some(c.consts.borrow().clone()),
+        RValue::Module(m) => Some(m.consts.borrow().clone()),
+        _ => None,
+    };
+    Ok(ObjectState {
+        object: object.clone(),
+        ivars: object.ivar.borrow().clone(),
+        contents,
+        consts,
+    })
+}
+
+fn container_items(contents: &Rc<RObject>) -> Vec<Rc<RObject>> {
+    match &contents.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        RValue::Hash(h) => h
+            .borrow()
+            .iter()
+            .flat_map(|(_, (k, v))| [k.clone(), v.clone()])
+            .collect(),
+        _ => Vec::new(),
+    }
+}
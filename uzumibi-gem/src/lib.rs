@@ -1,6 +1,22 @@
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+pub mod constraints;
+pub mod cookies;
+pub mod cors;
+pub mod error_handlers;
+pub mod filters;
+pub mod halt;
 pub mod helpers;
 pub mod init;
+pub mod isolation;
+pub mod middleware;
+pub mod mount;
+pub mod named_routes;
+pub mod negotiation;
 pub mod request;
 pub mod response;
+pub mod route_table;
+pub mod session;
+pub mod sse;
+pub mod streaming;
+pub mod websocket;
@@ -0,0 +1,363 @@
+//! This module implements the middleware stack of Uzumibi::Router and
+//! the built-in middlewares.
+//! `init_uzumibi_middleware()` should be called on prelude process.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.use(middleware: Class, options: Hash[Symbol, untyped]?) -> nil
+//!     end
+//!     class MiddlewareChain
+//!       def call(req: Request, res: Response) -> Response
+//!     end
+//!     module Middleware
+//!       class RequestLogger
+//!         def initialize(options: Hash[Symbol, untyped]?)
+//!         def call(req: Request, res: Response, app: MiddlewareChain) -> Response
+//!       end
+//!       class Timing
+//!         def initialize(options: Hash[Symbol, untyped]?)
+//!         def call(req: Request, res: Response, app: MiddlewareChain) -> Response
+//!       end
+//!       class DefaultHeaders
+//!         def initialize(headers: Hash[String, String])
+//!         def call(req: Request, res: Response, app: MiddlewareChain) -> Response
+//!       end
+//! ```
+//!
+//! A middleware is any object responding to `call(req, res, app)`.
+//! `use` instantiates the class once with `new(options)`, or `new` without
+//! options. Calling `app.call(req, res)` runs the rest of the stack and
+//! the route; not calling it returns early with whatever was set on `res`.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_cmethod, mrb_funcall},
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RSym, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    init::uzumibi_dispatch_route,
+    request::uzumibi_request_method_and_path,
+    response::{
+        uzumibi_response_get_header, uzumibi_response_set_default_header,
+        uzumibi_response_set_header,
+    },
+};
+
+const MIDDLEWARES_KEY: &str = "@_middlewares";
+const CHAIN_ROUTER_CLASS_KEY: &str = "@_router_class";
+const CHAIN_INDEX_KEY: &str = "@_index";
+const MIDDLEWARE_OPTIONS_KEY: &str = "@options";
+
+pub(crate) fn init_uzumibi_middleware(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+
+    let chain_class = vm.define_class("MiddlewareChain", None, Some(uzumibi_module.clone()));
+    mrb_define_cmethod(
+        vm,
+        chain_class,
+        "call",
+        Box::new(uzumibi_middleware_chain_call),
+    );
+
+    let middleware_module = vm.define_module("Middleware", Some(uzumibi_module));
+
+    let logger_class = vm.define_class("RequestLogger", None, Some(middleware_module.clone()));
+    mrb_define_cmethod(
+        vm,
+        logger_class.clone(),
+        "initialize",
+        Box::new(uzumibi_middleware_initialize),
+    );
+    mrb_define_cmethod(
+        vm,
+        logger_class,
+        "call",
+        Box::new(uzumibi_request_logger_call),
+    );
+
+    let timing_class = vm.define_class("Timing", None, Some(middleware_module.clone()));
+    mrb_define_cmethod(
+        vm,
+        timing_class.clone(),
+        "initialize",
+        Box::new(uzumibi_middleware_initialize),
+    );
+    mrb_define_cmethod(vm, timing_class, "call", Box::new(uzumibi_timing_call));
+
+    let default_headers_class = vm.define_class("DefaultHeaders", None, Some(middleware_module));
+    mrb_define_cmethod(
+        vm,
+        default_headers_class.clone(),
+        "initialize",
+        Box::new(uzumibi_middleware_initialize),
+    );
+    mrb_define_cmethod(
+        vm,
+        default_headers_class,
+        "call",
+        Box::new(uzumibi_default_headers_call),
+    );
+}
+
+/// use(middleware_class, options = nil) -> nil
+pub(crate) fn uzumibi_router_use(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let middleware_class = match args.first() {
+        Some(klass) if klass.is_truthy() => klass.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a middleware class".to_string(),
+            ));
+        }
+    };
+    // `use Klass, key: value` arrives as kwargs; hand them over as a Hash
+    let options = match args.get(1) {
+        Some(options) if options.is_truthy() => Some(options.clone()),
+        _ => match vm.get_kwargs() {
+            Some(kwargs) => {
+                let options = mrb_hash_new(vm, &[])?;
+                for (key, value) in kwargs.iter() {
+                    mrb_hash_set_index(
+                        options.clone(),
+                        RObject::symbol(RSym::new(key.to_string())).to_refcount_assigned(),
+                        value.clone(),
+                    )?;
+                }
+                Some(options)
+            }
+            None => None,
+        },
+    };
+    let middleware = match options {
+        Some(options) => mrb_funcall(vm, Some(middleware_class), "new", &[options])?,
+        None => mrb_funcall(vm, Some(middleware_class), "new", &[])?,
+    };
+
+    let klass = vm.getself()?;
+    let mut middlewares = klass.get_ivar(MIDDLEWARES_KEY);
+    if middlewares.is_falsy() {
+        middlewares = RObject::array(vec![]).to_refcount_assigned();
+        klass.set_ivar(MIDDLEWARES_KEY, middlewares.clone());
+    }
+    mrb_funcall(vm, Some(middlewares), "push", &[middleware])?;
+
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+fn uzumibi_middlewares(klass: &Rc<RObject>) -> Vec<Rc<RObject>> {
+    match &klass.get_ivar(MIDDLEWARES_KEY).value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        _ => Vec::new(),
+    }
+}
+
+fn uzumibi_middleware_chain_new(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    index: usize,
+) -> Result<Rc<RObject>, Error> {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .ok_or_else(|| Error::RuntimeError("Uzumibi module not found".to_string()))?;
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => return Err(Error::RuntimeError("Uzumibi must be a module".to_string())),
+    };
+    let chain_class = uzumibi_module
+        .get_const_by_name("MiddlewareChain")
+        .ok_or_else(|| Error::RuntimeError("MiddlewareChain class not found".to_string()))?;
+    let chain = mrb_funcall(vm, Some(chain_class), "new", &[])?;
+    chain.set_ivar(CHAIN_ROUTER_CLASS_KEY, klass.clone());
+    chain.set_ivar(
+        CHAIN_INDEX_KEY,
+        RObject::integer(index as i64).to_refcount_assigned(),
+    );
+    Ok(chain)
+}
+
+/// Runs the middleware stack of a router class, ending with route dispatch.
+pub(crate) fn uzumibi_run_middlewares(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    request: Rc<RObject>,
+    response: Rc<RObject>,
+) -> Result<(), Error> {
+    if uzumibi_middlewares(klass).is_empty() {
+        return uzumibi_dispatch_route(vm, klass, request, response);
+    }
+    let chain = uzumibi_middleware_chain_new(vm, klass, 0)?;
+    mrb_funcall(vm, Some(chain), "call", &[request, response])?;
+    Ok(())
+}
+
+/// MiddlewareChain#call(req, res) -> res
+fn uzumibi_middleware_chain_call(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (request, response) = match args {
+        [request, response, ..] => (request.clone(), response.clone()),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected 2 arguments: request, response".to_string(),
+            ));
+        }
+    };
+    let chain = vm.getself()?;
+    let klass = chain.get_ivar(CHAIN_ROUTER_CLASS_KEY);
+    let index: i64 = chain.get_ivar(CHAIN_INDEX_KEY).as_ref().try_into()?;
+    let index = index as usize;
+
+    let middlewares = uzumibi_middlewares(&klass);
+    match middlewares.get(index) {
+        Some(middleware) => {
+            let next = uzumibi_middleware_chain_new(vm, &klass, index + 1)?;
+            mrb_funcall(
+                vm,
+                Some(middleware.clone()),
+                "call",
+                &[request, response.clone(), next],
+            )?;
+        }
+        None => uzumibi_dispatch_route(vm, &klass, request, response.clone())?,
+    }
+    Ok(response)
+}
+
+fn uzumibi_middleware_initialize(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let self_obj = vm.getself()?;
+    let options = match args.first() {
+        Some(options) => options.clone(),
+        None => RObject::nil().to_refcount_assigned(),
+    };
+    self_obj.set_ivar(MIDDLEWARE_OPTIONS_KEY, options);
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// The request, response and downstream app a middleware is called with.
+type MiddlewareCallArgs = (Rc<RObject>, Rc<RObject>, Rc<RObject>);
+
+fn middleware_call_args(args: &[Rc<RObject>]) -> Result<MiddlewareCallArgs, Error> {
+    match args {
+        [request, response, app, ..] => Ok((request.clone(), response.clone(), app.clone())),
+        _ => Err(Error::ArgumentError(
+            "Expected 3 arguments: request, response, app".to_string(),
+        )),
+    }
+}
+
+/// Uzumibi::Middleware::RequestLogger#call(req, res, app)
+///
+/// Writes `METHOD path -> status` through `debug_console` when the
+/// platform defines it, otherwise through `puts`.
+fn uzumibi_request_logger_call(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let self_obj = vm.getself()?;
+    let (request, response, app) = middleware_call_args(args)?;
+    let (method, path) = uzumibi_request_method_and_path(&request)?;
+
+    mrb_funcall(vm, Some(app), "call", &[request, response.clone()])?;
+
+    let status = mrb_funcall(vm, Some(response.clone()), "status_code", &[])?;
+    let status = mrb_funcall(vm, Some(status), "to_s", &[])?;
+    let status: String = status.as_ref().try_into()?;
+    let message =
+        RObject::string(format!("{} {} -> {}", method, path, status)).to_refcount_assigned();
+    // Errors raised by the logger itself propagate like any other
+    let logger = RObject::string("debug_console".to_string()).to_refcount_assigned();
+    let has_debug_console = mrb_funcall(vm, Some(self_obj.clone()), "respond_to?", &[logger])?;
+    if has_debug_console.is_truthy() {
+        mrb_funcall(vm, Some(self_obj), "debug_console", &[message])?;
+    } else {
+        mrb_funcall(vm, Some(self_obj), "puts", &[message])?;
+    }
+
+    Ok(response)
+}
+
+/// Uzumibi::Middleware::Timing#call(req, res, app)
+///
+/// Adds `Server-Timing: app;dur=<ms>`. Hosts without a monotonic clock
+/// (wasm32-unknown-unknown) get the metric name without a duration.
+fn uzumibi_timing_call(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (request, response, app) = middleware_call_args(args)?;
+
+    let started = timing::start();
+    mrb_funcall(vm, Some(app), "call", &[request, response.clone()])?;
+    let metric = match timing::elapsed_millis(started) {
+        Some(ms) => format!("app;dur={:.3}", ms),
+        None => "app".to_string(),
+    };
+
+    let value = match uzumibi_response_get_header(&response, "Server-Timing")? {
+        Some((_, existing)) => format!("{}, {}", existing, metric),
+        None => metric,
+    };
+    uzumibi_response_set_header(vm, &response, "Server-Timing", &value)?;
+
+    Ok(response)
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod timing {
+    use std::time::Instant;
+
+    pub(super) fn start() -> Option<Instant> {
+        Some(Instant::now())
+    }
+
+    pub(super) fn elapsed_millis(started: Option<Instant>) -> Option<f64> {
+        started.map(|s| s.elapsed().as_secs_f64() * 1000.0)
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod timing {
+    pub(super) fn start() -> Option<()> {
+        None
+    }
+
+    pub(super) fn elapsed_millis(_started: Option<()>) -> Option<f64> {
+        None
+    }
+}
+
+/// Uzumibi::Middleware::DefaultHeaders#call(req, res, app)
+///
+/// Sets each header given to `new` unless the route already set it.
+fn uzumibi_default_headers_call(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let self_obj = vm.getself()?;
+    let (request, response, app) = middleware_call_args(args)?;
+
+    mrb_funcall(vm, Some(app), "call", &[request, response.clone()])?;
+
+    let options = self_obj.get_ivar(MIDDLEWARE_OPTIONS_KEY);
+    let defaults: Vec<(Rc<RObject>, Rc<RObject>)> = match &options.value {
+        RValue::Hash(h) => h
+            .borrow()
+            .iter()
+            .map(|(_, (k, v))| (k.clone(), v.clone()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    for (key_obj, value_obj) in defaults {
+        let key = mrb_funcall(vm, Some(key_obj), "to_s", &[])?;
+        let key: String = key.as_ref().try_into()?;
+        let value = mrb_funcall(vm, Some(value_obj), "to_s", &[])?;
+        let value: String = value.as_ref().try_into()?;
+        uzumibi_response_set_default_header(vm, &response, &key, &value)?;
+    }
+
+    Ok(response)
+}
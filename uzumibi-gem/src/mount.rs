@@ -0,0 +1,231 @@
+//! This module implements route namespaces and mounted sub-routers
+//! on Uzumibi::Router.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.namespace(prefix: String, constraints: Hash[Symbol, untyped]?) { () -> untyped } -> nil
+//!       def self.mount(router: singleton(Router), at: String) -> nil
+//! ```
+//!
+//! `namespace` prefixes every route and filter pattern defined inside the
+//! block; namespaces nest. `constraints:` applies to those routes as with
+//! `Router.constraints`. `mount` delegates requests under a prefix to
+//! another router class, which sees the remainder of the path as
+//! `req.path` and runs its own middlewares, filters and handlers.
+//! Mounted routers are consulted only when no route of the mounting
+//! router matches.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    constraints::uzumibi_router_with_constraints, middleware::uzumibi_run_middlewares,
+    request::uzumibi_request_set_path,
+};
+
+const NAMESPACE_KEY: &str = "@_namespace";
+const MOUNTS_KEY: &str = "@_mounts";
+
+/// Joins a prefix and a path, e.g. "/api/v1" + "/users" -> "/api/v1/users".
+pub(crate) fn join_path(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+    match (prefix.is_empty(), path.is_empty()) {
+        (true, _) => format!("/{}", path),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{}/{}", prefix, path),
+    }
+}
+
+/// Returns the path below `prefix`, or None when the path is outside it.
+pub(crate) fn strip_mount_prefix<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() {
+        Some("/")
+    } else if rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+fn uzumibi_router_current_namespace(klass: &Rc<RObject>) -> Result<Option<String>, Error> {
+    let namespace = klass.get_ivar(NAMESPACE_KEY);
+    if namespace.is_falsy() {
+        return Ok(None);
+    }
+    Ok(Some(namespace.as_ref().try_into()?))
+}
+
+/// Applies the namespace currently being defined to a route or filter path.
+pub(crate) fn uzumibi_router_prefixed_path(
+    klass: &Rc<RObject>,
+    path: &str,
+) -> Result<String, Error> {
+    Ok(match uzumibi_router_current_namespace(klass)? {
+        Some(namespace) => join_path(&namespace, path),
+        None => path.to_string(),
+    })
+}
+
+/// namespace(prefix, constraints: {}) { ... } -> nil
+pub(crate) fn uzumibi_router_namespace(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let constraints = vm
+        .get_kwargs()
+        .and_then(|kwargs| kwargs.get("constraints").cloned());
+    let (prefix, block) = match args {
+        [prefix, block] if block.is_truthy() => (prefix.clone(), block.clone()),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected 2 arguments: prefix, block".to_string(),
+            ));
+        }
+    };
+    let prefix: String = prefix.as_ref().try_into()?;
+
+    let klass = vm.getself()?;
+    let outer = klass.get_ivar(NAMESPACE_KEY);
+    let namespace = uzumibi_router_prefixed_path(&klass, &prefix)?;
+    klass.set_ivar(
+        NAMESPACE_KEY,
+        RObject::string(namespace).to_refcount_assigned(),
+    );
+    let result = match constraints {
+        Some(constraints) => uzumibi_router_with_constraints(vm, &constraints, block),
+        None => mrb_funcall(vm, Some(block), "call", &[]),
+    };
+    // Restore the outer namespace even when the block raised
+    klass.set_ivar(NAMESPACE_KEY, outer);
+    result?;
+
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// mount(router_class, at: prefix) -> nil
+pub(crate) fn uzumibi_router_mount(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let router = match args.first() {
+        Some(router) if matches!(router.value, RValue::Class(_)) => router.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a router class to mount".to_string(),
+            ));
+        }
+    };
+    let at = match (args.get(1), vm.get_kwargs()) {
+        (Some(at), _) => at.clone(),
+        (None, Some(kwargs)) => match kwargs.get("at") {
+            Some(at) => at.clone(),
+            None => {
+                return Err(Error::ArgumentError(
+                    "Expected a mount point: at:".to_string(),
+                ));
+            }
+        },
+        (None, None) => {
+            return Err(Error::ArgumentError(
+                "Expected a mount point: at:".to_string(),
+            ));
+        }
+    };
+    let at: String = at.as_ref().try_into()?;
+
+    let klass = vm.getself()?;
+    let prefix = uzumibi_router_prefixed_path(&klass, &at)?;
+    let mut mounts = klass.get_ivar(MOUNTS_KEY);
+    if mounts.is_falsy() {
+        mounts = RObject::array(vec![]).to_refcount_assigned();
+        klass.set_ivar(MOUNTS_KEY, mounts.clone());
+    }
+    let entry = RObject::array(vec![RObject::string(prefix).to_refcount_assigned(), router])
+        .to_refcount_assigned();
+    mrb_funcall(vm, Some(mounts), "push", &[entry])?;
+
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// Returns the `(prefix, router_class)` pairs mounted on a router class.
+pub(crate) fn uzumibi_router_mounts(
+    klass: &Rc<RObject>,
+) -> Result<Vec<(String, Rc<RObject>)>, Error> {
+    let entries = match &klass.get_ivar(MOUNTS_KEY).value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        _ => return Ok(Vec::new()),
+    };
+    let mut mounts = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if let RValue::Array(pair) = &entry.value {
+            let pair = pair.borrow();
+            let prefix: String = pair[0].as_ref().try_into()?;
+            mounts.push((prefix, pair[1].clone()));
+        }
+    }
+    Ok(mounts)
+}
+
+/// Hands the request to the first router mounted above its path.
+/// Returns false when no mount point covers the path.
+pub(crate) fn uzumibi_dispatch_mounted(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    path: &str,
+    request: &Rc<RObject>,
+    response: &Rc<RObject>,
+) -> Result<bool, Error> {
+    for (prefix, router) in uzumibi_router_mounts(klass)? {
+        let Some(rest) = strip_mount_prefix(&prefix, path) else {
+            continue;
+        };
+        uzumibi_request_set_path(request, rest);
+        let result = uzumibi_run_middlewares(vm, &router, request.clone(), response.clone());
+        uzumibi_request_set_path(request, path);
+        result?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(join_path("/api/v1", "/users"), "/api/v1/users");
+        assert_eq!(join_path("/api/v1/", "users/:id"), "/api/v1/users/:id");
+        assert_eq!(join_path("/api", "/"), "/api");
+        assert_eq!(join_path("", "/users"), "/users");
+    }
+
+    #[test]
+    fn test_strip_mount_prefix() {
+        assert_eq!(strip_mount_prefix("/admin", "/admin"), Some("/"));
+        assert_eq!(strip_mount_prefix("/admin", "/admin/users"), Some("/users"));
+        assert_eq!(
+            strip_mount_prefix("/admin/", "/admin/users"),
+            Some("/users")
+        );
+        assert_eq!(strip_mount_prefix("/admin", "/administrator"), None);
+        assert_eq!(strip_mount_prefix("/admin", "/users"), None);
+    }
+}
@@ -0,0 +1,245 @@
+//! This module implements named routes and URL generation.
+//! `init_uzumibi_named_routes()` should be called on prelude process.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.get(path: String, as: Symbol?) { (Request, Response) -> untyped } -> String
+//!       def self.path_for(name: Symbol | String, params: Hash[Symbol, untyped]?) -> String
+//!     end
+//!     class Request
+//!       def url_for(name: Symbol | String, params: Hash[Symbol, untyped]?) -> String
+//! ```
+//!
+//! Paths are generated from the registered pattern with
+//! `uzumibi_art_router::generate`, so they match the same route again.
+//! Params not used by the pattern become the query string, sorted by key.
+//! Named routes of mounted routers are found with the mount prefix.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_class_cmethod, mrb_define_cmethod, mrb_funcall},
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+use uzumibi_art_router::generate::{build_path, param_names, percent_encode};
+
+use crate::{
+    mount::{join_path, uzumibi_router_mounts},
+    request::uzumibi_request_header,
+};
+
+const NAMED_ROUTES_KEY: &str = "@_named_routes";
+/// Router class that dispatched a request, used by `req.url_for`.
+pub(crate) const REQUEST_ROUTER_CLASS_KEY: &str = "@_router_class";
+
+pub(crate) fn init_uzumibi_named_routes(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let router_class = match uzumibi_module.get_const_by_name("Router") {
+        Some(router) => match &router.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("Router must be a class"),
+        },
+        None => panic!("Router class must be defined beforehand"),
+    };
+    let request_class = match uzumibi_module.get_const_by_name("Request") {
+        Some(request) => match &request.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("Request must be a class"),
+        },
+        None => panic!("Request class must be defined beforehand"),
+    };
+
+    mrb_define_class_cmethod(
+        vm,
+        router_class,
+        "path_for",
+        Box::new(uzumibi_router_path_for),
+    );
+    mrb_define_cmethod(
+        vm,
+        request_class,
+        "url_for",
+        Box::new(uzumibi_request_url_for),
+    );
+}
+
+/// Records the `as:` name given to a route definition, if any.
+pub(crate) fn uzumibi_router_register_name(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    pattern: &str,
+    options: &[(String, Rc<RObject>)],
+) -> Result<(), Error> {
+    let name = match options.iter().find(|(k, _)| k == "as") {
+        Some((_, name)) if name.is_truthy() => name.clone(),
+        _ => return Ok(()),
+    };
+    let name = mrb_funcall(vm, Some(name), "to_s", &[])?;
+
+    let mut names = klass.get_ivar(NAMED_ROUTES_KEY);
+    if names.is_falsy() {
+        names = mrb_hash_new(vm, &[])?;
+        klass.set_ivar(NAMED_ROUTES_KEY, names.clone());
+    }
+    mrb_hash_set_index(
+        names,
+        name,
+        RObject::string(pattern.to_string()).to_refcount_assigned(),
+    )?;
+    Ok(())
+}
+
+/// Returns the `(name, pattern)` pairs registered on a router class.
+pub(crate) fn uzumibi_router_named_routes(
+    klass: &Rc<RObject>,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut named = Vec::new();
+    if let RValue::Hash(h) = &klass.get_ivar(NAMED_ROUTES_KEY).value {
+        for (_, (name, pattern)) in h.borrow().iter() {
+            named.push((name.as_ref().try_into()?, pattern.as_ref().try_into()?));
+        }
+    }
+    Ok(named)
+}
+
+/// Finds the pattern of a named route, looking into mounted routers too.
+fn uzumibi_router_find_named(klass: &Rc<RObject>, name: &str) -> Result<Option<String>, Error> {
+    if let Some((_, pattern)) = uzumibi_router_named_routes(klass)?
+        .into_iter()
+        .find(|(n, _)| n == name)
+    {
+        return Ok(Some(pattern));
+    }
+    for (prefix, router) in uzumibi_router_mounts(klass)? {
+        if let Some(pattern) = uzumibi_router_find_named(&router, name)? {
+            return Ok(Some(join_path(&prefix, &pattern)));
+        }
+    }
+    Ok(None)
+}
+
+/// Collects URL params from an optional positional Hash and kwargs.
+/// Must run before any other method call clears the kwargs.
+fn uzumibi_url_params(
+    vm: &mut VM,
+    params: Option<&Rc<RObject>>,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut entries: Vec<(Rc<RObject>, Rc<RObject>)> = Vec::new();
+    if let Some(params) = params
+        && let RValue::Hash(h) = &params.value
+    {
+        entries.extend(h.borrow().iter().map(|(_, (k, v))| (k.clone(), v.clone())));
+    }
+    if let Some(kwargs) = vm.get_kwargs() {
+        for (key, value) in kwargs.iter() {
+            entries.push((
+                RObject::string(key.to_string()).to_refcount_assigned(),
+                value.clone(),
+            ));
+        }
+    }
+
+    let mut params = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let key = mrb_funcall(vm, Some(key), "to_s", &[])?;
+        let value = mrb_funcall(vm, Some(value), "to_s", &[])?;
+        params.push((key.as_ref().try_into()?, value.as_ref().try_into()?));
+    }
+    Ok(params)
+}
+
+/// Builds the path for a named route of a router class.
+fn uzumibi_generate_path(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    args: &[Rc<RObject>],
+) -> Result<String, Error> {
+    let params = uzumibi_url_params(vm, args.get(1))?;
+    let name = match args.first() {
+        Some(name) if name.is_truthy() => name.clone(),
+        _ => {
+            return Err(Error::ArgumentError("Expected a route name".to_string()));
+        }
+    };
+    let name = mrb_funcall(vm, Some(name), "to_s", &[])?;
+    let name: String = name.as_ref().try_into()?;
+    let pattern = uzumibi_router_find_named(klass, &name)?
+        .ok_or_else(|| Error::ArgumentError(format!("No route named {}", name)))?;
+
+    let path = build_path(&pattern, |key| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    })
+    .map_err(|missing| {
+        Error::ArgumentError(format!("Missing param {} for route {}", missing, name))
+    })?;
+
+    let used = param_names(&pattern);
+    let mut query: Vec<&(String, String)> =
+        params.iter().filter(|(k, _)| !used.contains(k)).collect();
+    if query.is_empty() {
+        return Ok(path);
+    }
+    query.sort_by(|a, b| a.0.cmp(&b.0));
+    let query = query
+        .iter()
+        .map(|(k, v)| format!("{}={}", percent_encode(k), percent_encode(v)))
+        .collect::<Vec<_>>()
+        .join("&");
+    Ok(format!("{}?{}", path, query))
+}
+
+/// path_for(name, params = {}) -> String
+pub(crate) fn uzumibi_router_path_for(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let klass = vm.getself()?;
+    let path = uzumibi_generate_path(vm, &klass, args)?;
+    Ok(RObject::string(path).to_refcount_assigned())
+}
+
+/// req.url_for(name, params = {}) -> String
+///
+/// Returns an absolute URL when the request has a Host header.
+fn uzumibi_request_url_for(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let request = vm.getself()?;
+    let klass = request.get_ivar(REQUEST_ROUTER_CLASS_KEY);
+    if klass.is_falsy() {
+        return Err(Error::RuntimeError(
+            "url_for is available only while a router handles the request".to_string(),
+        ));
+    }
+    let path = uzumibi_generate_path(vm, &klass, args)?;
+
+    let url = match uzumibi_request_header(&request, "host")? {
+        Some(host) => {
+            let scheme = match uzumibi_request_header(&request, "x-forwarded-proto")? {
+                Some(proto) => proto,
+                None if host.starts_with("localhost") || host.starts_with("127.0.0.1") => {
+                    "http".to_string()
+                }
+                None => "https".to_string(),
+            };
+            format!("{}://{}{}", scheme, host, path)
+        }
+        None => path,
+    };
+    Ok(RObject::string(url).to_refcount_assigned())
+}
@@ -0,0 +1,451 @@
+//! This module implements content negotiation with `respond_to`.
+//! `init_uzumibi_negotiation()` should be called on prelude process.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.respond_to(req: Request, res: Response?) { (Responder) -> untyped } -> untyped
+//!     end
+//!     class Responder
+//!       def json() { () -> untyped } -> nil
+//!       def html() { () -> String } -> nil
+//!       def text() { () -> String } -> nil
+//!       def xml() { () -> String } -> nil
+//!       def on(media_type: String) { () -> String } -> nil
+//! ```
+//!
+//! The representations are offered in the order they are declared and
+//! ranked against the request's `Accept` header by q-value, then by how
+//! specific the matching media range is, then by declaration order. A
+//! missing `Accept` accepts anything. The chosen block's value becomes the
+//! body (a non-String value of `json` goes through `JSON.generate`) with
+//! its `Content-Type` and `Vary: Accept`. When nothing is acceptable the
+//! response is 406. Without `res`, `respond_to` returns a
+//! `[status, headers, body]` triplet to be returned from the route.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_class_cmethod, mrb_define_cmethod, mrb_funcall},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    request::uzumibi_request_header,
+    response::{
+        uzumibi_json_generate, uzumibi_response_fill, uzumibi_response_get_header,
+        uzumibi_response_new, uzumibi_response_set_header,
+    },
+};
+
+const FORMATS_KEY: &str = "@_formats";
+
+pub(crate) fn init_uzumibi_negotiation(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let router_class = match uzumibi_module.get_const_by_name("Router") {
+        Some(router) => match &router.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("Router must be a class"),
+        },
+        None => panic!("Router class must be defined beforehand"),
+    };
+
+    let responder_class = vm.define_class("Responder", None, Some(uzumibi_module));
+    mrb_define_cmethod(
+        vm,
+        responder_class.clone(),
+        "json",
+        Box::new(uzumibi_responder_json),
+    );
+    mrb_define_cmethod(
+        vm,
+        responder_class.clone(),
+        "html",
+        Box::new(uzumibi_responder_html),
+    );
+    mrb_define_cmethod(
+        vm,
+        responder_class.clone(),
+        "text",
+        Box::new(uzumibi_responder_text),
+    );
+    mrb_define_cmethod(
+        vm,
+        responder_class.clone(),
+        "xml",
+        Box::new(uzumibi_responder_xml),
+    );
+    mrb_define_cmethod(vm, responder_class, "on", Box::new(uzumibi_responder_on));
+
+    mrb_define_class_cmethod(
+        vm,
+        router_class,
+        "respond_to",
+        Box::new(uzumibi_router_respond_to),
+    );
+}
+
+fn uzumibi_responder_add(
+    vm: &mut VM,
+    media_type: &str,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let block = match args.last() {
+        Some(block) if matches!(block.value, RValue::Proc(_)) => block.clone(),
+        _ => {
+            return Err(Error::ArgumentError(format!(
+                "Expected a block for {}",
+                media_type
+            )));
+        }
+    };
+    let responder = vm.getself()?;
+    let mut formats = responder.get_ivar(FORMATS_KEY);
+    if formats.is_falsy() {
+        formats = RObject::array(vec![]).to_refcount_assigned();
+        responder.set_ivar(FORMATS_KEY, formats.clone());
+    }
+    let entry = RObject::array(vec![
+        RObject::string(media_type.to_string()).to_refcount_assigned(),
+        block,
+    ])
+    .to_refcount_assigned();
+    mrb_funcall(vm, Some(formats), "push", &[entry])?;
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+fn uzumibi_responder_json(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_responder_add(vm, "application/json", args)
+}
+
+fn uzumibi_responder_html(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_responder_add(vm, "text/html", args)
+}
+
+fn uzumibi_responder_text(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_responder_add(vm, "text/plain", args)
+}
+
+fn uzumibi_responder_xml(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_responder_add(vm, "application/xml", args)
+}
+
+/// on(media_type) { ... } -> nil
+fn uzumibi_responder_on(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let media_type: String = match args.first() {
+        Some(media_type) if !matches!(media_type.value, RValue::Proc(_)) => {
+            media_type.as_ref().try_into()?
+        }
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a media type and a block".to_string(),
+            ));
+        }
+    };
+    uzumibi_responder_add(vm, media_type.trim(), &args[1..])
+}
+
+/// respond_to(req, res = nil) { |f| ... } -> [status, headers, body] | res
+fn uzumibi_router_respond_to(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (block, rest) = match args.split_last() {
+        Some((block, rest)) if matches!(block.value, RValue::Proc(_)) && !rest.is_empty() => {
+            (block.clone(), rest)
+        }
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a request and a block".to_string(),
+            ));
+        }
+    };
+    let request = rest[0].clone();
+    let given = rest.get(1).filter(|res| res.is_truthy()).cloned();
+
+    let responder_class = vm
+        .get_const_by_name("Uzumibi")
+        .and_then(|uzumibi| match &uzumibi.value {
+            RValue::Module(m) => m.get_const_by_name("Responder"),
+            _ => None,
+        })
+        .ok_or_else(|| Error::RuntimeError("Uzumibi::Responder not found".to_string()))?;
+    let responder = mrb_funcall(vm, Some(responder_class), "new", &[])?;
+    mrb_funcall(vm, Some(block), "call", std::slice::from_ref(&responder))?;
+
+    let mut formats: Vec<(String, Rc<RObject>)> = Vec::new();
+    if let RValue::Array(arr) = &responder.get_ivar(FORMATS_KEY).value {
+        for entry in arr.borrow().iter() {
+            if let RValue::Array(pair) = &entry.value {
+                let pair = pair.borrow();
+                formats.push((pair[0].as_ref().try_into()?, pair[1].clone()));
+            }
+        }
+    }
+
+    let accept = uzumibi_request_header(&request, "accept")?;
+    let offered: Vec<&str> = formats.iter().map(|(t, _)| t.as_str()).collect();
+    let response = match &given {
+        Some(res) => res.clone(),
+        None => uzumibi_response_new(vm),
+    };
+
+    match negotiate(accept.as_deref(), &offered) {
+        Some(index) => {
+            let (media_type, handler) = formats[index].clone();
+            let value = mrb_funcall(vm, Some(handler), "call", &[])?;
+            let body = match &value.value {
+                RValue::String(_, _) => value.clone(),
+                _ if media_type == "application/json" => {
+                    RObject::string(uzumibi_json_generate(vm, value.clone())?)
+                        .to_refcount_assigned()
+                }
+                RValue::Nil => RObject::string("".to_string()).to_refcount_assigned(),
+                _ => mrb_funcall(vm, Some(value.clone()), "to_s", &[])?,
+            };
+            uzumibi_response_fill(vm, &response, 200, body)?;
+            uzumibi_response_set_header(
+                vm,
+                &response,
+                "Content-Type",
+                &content_type_for(&media_type),
+            )?;
+        }
+        None => {
+            let body = RObject::string("Not Acceptable".to_string()).to_refcount_assigned();
+            uzumibi_response_fill(vm, &response, 406, body)?;
+            uzumibi_response_set_header(
+                vm,
+                &response,
+                "Content-Type",
+                "text/plain; charset=utf-8",
+            )?;
+        }
+    }
+    let vary = match uzumibi_response_get_header(&response, "Vary")? {
+        Some((_, vary))
+            if vary
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case("accept") || v.trim() == "*") =>
+        {
+            vary
+        }
+        Some((_, vary)) if !vary.trim().is_empty() => format!("{}, Accept", vary),
+        _ => "Accept".to_string(),
+    };
+    uzumibi_response_set_header(vm, &response, "Vary", &vary)?;
+
+    if given.is_some() {
+        return Ok(response);
+    }
+    let triplet = ["status_code", "headers", "body"]
+        .into_iter()
+        .map(|name| mrb_funcall(vm, Some(response.clone()), name, &[]))
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(RObject::array(triplet).to_refcount_assigned())
+}
+
+/// A media range from an `Accept` header, e.g. `text/*;q=0.5`.
+#[derive(Debug, PartialEq)]
+struct MediaRange {
+    main: String,
+    sub: String,
+    /// q-value in thousandths, 0..=1000
+    quality: u16,
+}
+
+impl MediaRange {
+    /// 2 for `type/sub`, 1 for `type/*`, 0 for `*/*`; None when it does not match.
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        let (main, sub) = media_type.split_once('/')?;
+        if self.main == "*" {
+            Some(0)
+        } else if !self.main.eq_ignore_ascii_case(main) {
+            None
+        } else if self.sub == "*" {
+            Some(1)
+        } else if self.sub.eq_ignore_ascii_case(sub) {
+            Some(2)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses a q-value such as "0.8" into thousandths. Invalid values are None.
+fn parse_quality(value: &str) -> Option<u16> {
+    let value = value.trim();
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let frac_value = format!("{:0<3}", frac).parse::<u16>().ok()?;
+    match int {
+        "0" => Some(frac_value),
+        "1" if frac_value == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let (main, sub) = parts.next()?.trim().split_once('/')?;
+            let (main, sub) = (main.trim(), sub.trim());
+            if main.is_empty() || sub.is_empty() || (main == "*" && sub != "*") {
+                return None;
+            }
+            let mut quality = 1000;
+            for param in parts {
+                if let Some((key, value)) = param.split_once('=')
+                    && key.trim().eq_ignore_ascii_case("q")
+                {
+                    // A malformed q-value makes the range unusable
+                    quality = parse_quality(value)?;
+                }
+            }
+            Some(MediaRange {
+                main: main.to_string(),
+                sub: sub.to_string(),
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// The `Content-Type` for an offered media type. Text, JSON and XML
+/// types get `; charset=utf-8`; binary types such as `image/png` are
+/// sent as they are.
+pub fn content_type_for(media_type: &str) -> String {
+    let essence = media_type.to_ascii_lowercase();
+    let textual = essence.starts_with("text/")
+        || matches!(essence.as_str(), "application/json" | "application/xml")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml");
+    if textual && !essence.contains(';') {
+        format!("{}; charset=utf-8", media_type)
+    } else {
+        media_type.to_string()
+    }
+}
+
+/// Picks the index of the best offered media type for an `Accept` header,
+/// or None when none is acceptable.
+pub fn negotiate(accept: Option<&str>, offered: &[&str]) -> Option<usize> {
+    let ranges = match accept.map(str::trim) {
+        Some(accept) if !accept.is_empty() => parse_accept(accept),
+        _ => return if offered.is_empty() { None } else { Some(0) },
+    };
+    let mut best: Option<(u16, u8, usize)> = None;
+    for (index, media_type) in offered.iter().enumerate() {
+        // The most specific matching range decides the quality
+        let Some((specificity, quality)) = ranges
+            .iter()
+            .filter_map(|range| range.specificity(media_type).map(|s| (s, range.quality)))
+            .max_by_key(|(s, _)| *s)
+        else {
+            continue;
+        };
+        if quality == 0 {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((q, s, _)) => quality > q || (quality == q && specificity > s),
+        };
+        if better {
+            best = Some((quality, specificity, index));
+        }
+    }
+    best.map(|(_, _, index)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFERED: [&str; 3] = ["application/json", "text/html", "text/plain"];
+
+    #[test]
+    fn test_content_type_for() {
+        assert_eq!(content_type_for("text/csv"), "text/csv; charset=utf-8");
+        assert_eq!(
+            content_type_for("application/ld+json"),
+            "application/ld+json; charset=utf-8"
+        );
+        assert_eq!(content_type_for("image/png"), "image/png");
+        assert_eq!(
+            content_type_for("application/octet-stream"),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            content_type_for("text/plain; charset=shift_jis"),
+            "text/plain; charset=shift_jis"
+        );
+    }
+
+    #[test]
+    fn test_negotiate_without_accept() {
+        assert_eq!(negotiate(None, &OFFERED), Some(0));
+        assert_eq!(negotiate(Some(""), &OFFERED), Some(0));
+        assert_eq!(negotiate(None, &[]), None);
+    }
+
+    #[test]
+    fn test_negotiate_by_quality() {
+        assert_eq!(negotiate(Some("text/html"), &OFFERED), Some(1));
+        assert_eq!(
+            negotiate(Some("application/json;q=0.5, text/plain"), &OFFERED),
+            Some(2)
+        );
+        assert_eq!(
+            negotiate(Some("text/*;q=0.9, */*;q=0.1"), &OFFERED),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_prefers_specific_range() {
+        // Browsers send a wildcard next to the types they want
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(negotiate(Some(browser), &OFFERED), Some(1));
+        assert_eq!(negotiate(Some("*/*, text/plain"), &OFFERED), Some(2));
+    }
+
+    #[test]
+    fn test_negotiate_not_acceptable() {
+        assert_eq!(negotiate(Some("image/png"), &OFFERED), None);
+        assert_eq!(
+            negotiate(Some("text/html;q=0, application/json;q=0"), &OFFERED[..2]),
+            None
+        );
+        // An explicit q=0 excludes a type even when a wildcard accepts it
+        assert_eq!(
+            negotiate(Some("*/*, application/json;q=0"), &OFFERED[..1]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_accept_ignores_malformed_ranges() {
+        assert_eq!(
+            parse_accept("text/html;q=2, json, */html, text/plain; charset=utf-8; Q=0.25"),
+            vec![MediaRange {
+                main: "text".to_string(),
+                sub: "plain".to_string(),
+                quality: 250,
+            }]
+        );
+    }
+}
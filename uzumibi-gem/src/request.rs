@@ -9,28 +9,43 @@
 //!       def method: String
 //!       def path: String
 //!       def headers: Hash<String, String>
+//!       def header_values(name: String) -> Array[String]
+//!       def cookie: Hash[String, String]
+//!       def env: Hash[untyped, untyped]
+//!       def context: Hash[untyped, untyped]
 //! ```
 //!
+//! `env` (also available as `context`) is an empty Hash created for every
+//! dispatched request. Middleware, filters, error handlers and the route
+//! see the same Hash, so it can carry per-request data such as the
+//! current user without using globals that outlive the request.
+//!
+//! A header sent more than once has one entry in `headers`, with the
+//! values joined by `, ` (`; ` for `Cookie`). `header_values` returns
+//! them one by one, in order, for headers whose values may contain commas.
+//!
 use std::{collections::HashMap, rc::Rc};
 
 use mrubyedge::{
     Error,
     yamrb::{
-        helpers::mrb_funcall,
+        helpers::{mrb_define_cmethod, mrb_funcall},
         prelude::hash::{mrb_hash_new, mrb_hash_set_index},
         value::{RObject, RSym, RValue},
         vm::VM,
     },
 };
 
-use crate::helpers;
+use crate::{helpers, response::uzumibi_header_values};
 
 #[derive(Debug)]
 pub struct Request {
     pub method: String,
     pub path: String,
     pub query_string: String,
-    pub headers: HashMap<String, String>,
+    /// Headers in the order received; a repeated header appears once per
+    /// value.
+    pub headers: Vec<(String, String)>,
     pub body: Vec<u8>,
     pub params: HashMap<String, String>,
 }
@@ -53,6 +68,9 @@ const REQUEST_PARAMS_IVAR_KEY: &str = "@params";
 const REQUEST_BODY_IVAR_KEY: &str = "@body";
 const REQUEST_RAW_BODY_IVAR_KEY: &str = "@raw_body";
 const REQUEST_COOKIE_IVAR_KEY: &str = "@cookie";
+const REQUEST_ENV_IVAR_KEY: &str = "@env";
+/// Every header as a `[name, value]` pair, in the order received
+const REQUEST_HEADER_LIST_IVAR_KEY: &str = "@_header_list";
 
 pub(crate) fn init_uzumibi_request(vm: &mut VM) {
     let uzumibi = vm
@@ -62,8 +80,20 @@ pub(crate) fn init_uzumibi_request(vm: &mut VM) {
         RValue::Module(m) => m.clone(),
         _ => panic!("Uzumibi must be a module"),
     };
-    let request_class = vm.define_class("Request", None, Some(uzumibi_module));
-    let request_class = RObject::class(request_class, vm);
+    let request_class_ = vm.define_class("Request", None, Some(uzumibi_module));
+    mrb_define_cmethod(
+        vm,
+        request_class_.clone(),
+        "env",
+        Box::new(uzumibi_request_env),
+    );
+    mrb_define_cmethod(
+        vm,
+        request_class_.clone(),
+        "context",
+        Box::new(uzumibi_request_env),
+    );
+    let request_class = RObject::class(request_class_.clone(), vm);
 
     mrb_funcall(
         vm,
@@ -114,6 +144,133 @@ pub(crate) fn init_uzumibi_request(vm: &mut VM) {
         &[as_sym(REQUEST_COOKIE_KEY)],
     )
     .expect("attr_accessor failed");
+    mrb_define_cmethod(
+        vm,
+        request_class_.clone(),
+        REQUEST_COOKIE_KEY,
+        Box::new(uzumibi_request_cookie),
+    );
+    mrb_define_cmethod(
+        vm,
+        request_class_,
+        "header_values",
+        Box::new(uzumibi_request_header_values),
+    );
+}
+
+/// env -> Hash
+///
+/// Request objects built by hand get their Hash on first use.
+fn uzumibi_request_env(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let request = vm.getself()?;
+    let env = request.get_ivar(REQUEST_ENV_IVAR_KEY);
+    if env.is_truthy() {
+        return Ok(env);
+    }
+    let env = mrb_hash_new(vm, &[])?;
+    request.set_ivar(REQUEST_ENV_IVAR_KEY, env.clone());
+    Ok(env)
+}
+
+/// cookie -> Hash
+///
+/// Request objects built by hand get their Hash from the `Cookie` header
+/// on first use.
+fn uzumibi_request_cookie(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let request = vm.getself()?;
+    let cookie = request.get_ivar(REQUEST_COOKIE_IVAR_KEY);
+    if cookie.is_truthy() {
+        return Ok(cookie);
+    }
+    let cookie = mrb_hash_new(vm, &[])?;
+    if let RValue::Hash(h) = &request.get_ivar(REQUEST_HEADERS_IVAR_KEY).value {
+        let headers: Vec<_> = h.borrow().values().cloned().collect();
+        for (key, value) in headers {
+            let key: String = key.as_ref().try_into()?;
+            if !key.eq_ignore_ascii_case("cookie") {
+                continue;
+            }
+            for value in uzumibi_header_values(&value)? {
+                for (name, value) in helpers::parse_cookie_header(&value) {
+                    mrb_hash_set_index(
+                        cookie.clone(),
+                        RObject::string(name).to_refcount_assigned(),
+                        RObject::string(value).to_refcount_assigned(),
+                    )?;
+                }
+            }
+        }
+    }
+    request.set_ivar(REQUEST_COOKIE_IVAR_KEY, cookie.clone());
+    Ok(cookie)
+}
+
+/// header_values(name) -> Array[String]
+///
+/// Every value of a header, by case-insensitive name, in the order they
+/// were received. Request objects built by hand read `headers`, where an
+/// Array value gives several values.
+fn uzumibi_request_header_values(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let name: String = match args.first() {
+        Some(name) => name.as_ref().try_into()?,
+        None => {
+            return Err(Error::ArgumentError(
+                "Expected 1 argument: name".to_string(),
+            ));
+        }
+    };
+    let request = vm.getself()?;
+    let mut values = Vec::new();
+    let header_list = request.get_ivar(REQUEST_HEADER_LIST_IVAR_KEY);
+    if let RValue::Array(pairs) = &header_list.value {
+        for pair in pairs.borrow().iter() {
+            if let RValue::Array(pair) = &pair.value {
+                let pair = pair.borrow();
+                if let [key, value] = pair.as_slice() {
+                    let key: String = key.as_ref().try_into()?;
+                    if key.eq_ignore_ascii_case(&name) {
+                        values.push(value.clone());
+                    }
+                }
+            }
+        }
+    } else if let RValue::Hash(h) = &request.get_ivar(REQUEST_HEADERS_IVAR_KEY).value {
+        for (_, (key, value)) in h.borrow().iter() {
+            let key: String = key.as_ref().try_into()?;
+            if key.eq_ignore_ascii_case(&name) {
+                for value in uzumibi_header_values(value)? {
+                    values.push(RObject::string(value).to_refcount_assigned());
+                }
+            }
+        }
+    }
+    Ok(RObject::array(values).to_refcount_assigned())
+}
+
+/// Joins the values of repeated headers into one, as `req.headers` has
+/// one String per name: `Cookie` values with `; ` and any other header
+/// with `, `. Names are compared case-insensitively and keep the casing
+/// they were first seen with.
+fn combine_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut combined: Vec<(String, String)> = Vec::new();
+    for (key, value) in headers {
+        match combined
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        {
+            Some((_, joined)) => {
+                let separator = if key.eq_ignore_ascii_case("cookie") {
+                    "; "
+                } else {
+                    ", "
+                };
+                joined.push_str(separator);
+                joined.push_str(value);
+            }
+            None => combined.push((key.clone(), value.clone())),
+        }
+    }
+    combined
 }
 
 fn as_sym(name: impl Into<String>) -> Rc<RObject> {
@@ -154,7 +311,7 @@ impl Request {
         // Parse Headers count (u16) + Headers
         let headers_count = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
         offset += 2;
-        let mut headers = HashMap::new();
+        let mut headers = Vec::with_capacity(headers_count);
         for _ in 0..headers_count {
             let name_size = u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
             offset += 2;
@@ -172,7 +329,7 @@ impl Request {
                 .collect();
             offset += value_size;
 
-            headers.insert(name, value);
+            headers.push((name, value));
         }
 
         // Parse Request body size (u32) + Request body
@@ -195,6 +352,21 @@ impl Request {
         }
     }
 
+    /// Looks up a header value by case-insensitive name.
+    /// A repeated header gives its first value.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).into_iter().next()
+    }
+
+    /// Every value of a header, by case-insensitive name, in order.
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
     pub fn into_robject(self, vm: &mut VM) -> Rc<RObject> {
         let request_obj = uzumibi_request_new(vm);
 
@@ -209,18 +381,15 @@ impl Request {
         let headers_hash = mrb_hash_new(vm, &[]).expect("Failed to create headers hash");
         let cookie_hash = mrb_hash_new(vm, &[]).expect("Failed to create cookie hash");
         let mut content_type: &'static str = "";
-        for (key, value) in self.headers {
+        for (key, value) in &self.headers {
             if key.to_lowercase() == "cookie" {
-                for cookie_pair in value.split(';') {
-                    let cookie_pair = cookie_pair.trim();
-                    if let Some((ckey, cval)) = cookie_pair.split_once('=') {
-                        mrb_hash_set_index(
-                            cookie_hash.clone(),
-                            RObject::string(ckey.trim().to_string()).to_refcount_assigned(),
-                            RObject::string(cval.trim().to_string()).to_refcount_assigned(),
-                        )
-                        .expect("Failed to set cookie");
-                    }
+                for (ckey, cval) in helpers::parse_cookie_header(value) {
+                    mrb_hash_set_index(
+                        cookie_hash.clone(),
+                        RObject::string(ckey).to_refcount_assigned(),
+                        RObject::string(cval).to_refcount_assigned(),
+                    )
+                    .expect("Failed to set cookie");
                 }
             }
             if key.to_lowercase() == "content-type" {
@@ -230,7 +399,8 @@ impl Request {
                     content_type = "application/json";
                 }
             }
-
+        }
+        for (key, value) in combine_headers(&self.headers) {
             mrb_hash_set_index(
                 headers_hash.clone(),
                 RObject::string(key).to_refcount_assigned(),
@@ -238,8 +408,25 @@ impl Request {
             )
             .expect("Failed to set header");
         }
+        let header_list = self
+            .headers
+            .into_iter()
+            .map(|(key, value)| {
+                RObject::array(vec![
+                    RObject::string(key).to_refcount_assigned(),
+                    RObject::string(value).to_refcount_assigned(),
+                ])
+                .to_refcount_assigned()
+            })
+            .collect();
+        request_obj.set_ivar(
+            REQUEST_HEADER_LIST_IVAR_KEY,
+            RObject::array(header_list).to_refcount_assigned(),
+        );
         request_obj.set_ivar(REQUEST_HEADERS_IVAR_KEY, headers_hash);
         request_obj.set_ivar(REQUEST_COOKIE_IVAR_KEY, cookie_hash);
+        let env_hash = mrb_hash_new(vm, &[]).expect("Failed to create env hash");
+        request_obj.set_ivar(REQUEST_ENV_IVAR_KEY, env_hash);
         let params_hash = mrb_hash_new(vm, &[]).expect("Failed to create params hash");
 
         // Merge route params
@@ -333,14 +520,15 @@ impl Request {
         let path: String = path_obj.as_ref().try_into()?;
 
         let headers_obj = obj.get_ivar(REQUEST_HEADERS_IVAR_KEY);
-        let mut headers = HashMap::new();
+        let mut headers = Vec::new();
         match &headers_obj.value {
             RValue::Hash(h) => {
                 let headers_hash = h.borrow();
                 for (_, (key_obj, value_obj)) in headers_hash.iter() {
                     let key: String = key_obj.as_ref().try_into()?;
-                    let value: String = value_obj.as_ref().try_into()?;
-                    headers.insert(key, value);
+                    for value in uzumibi_header_values(value_obj)? {
+                        headers.push((key.clone(), value));
+                    }
                 }
             }
             _ => {
@@ -385,6 +573,89 @@ impl Request {
     }
 }
 
+/// Reads `method` and `path` from a request object.
+pub(crate) fn uzumibi_request_method_and_path(
+    request: &Rc<RObject>,
+) -> Result<(String, String), Error> {
+    let method: String = request
+        .get_ivar(REQUEST_METHOD_IVAR_KEY)
+        .as_ref()
+        .try_into()?;
+    let path: String = request
+        .get_ivar(REQUEST_PATH_IVAR_KEY)
+        .as_ref()
+        .try_into()?;
+    Ok((method, path))
+}
+
+/// Replaces `path` of a request object, e.g. while a mounted router
+/// handles the remainder of the path.
+pub(crate) fn uzumibi_request_set_path(request: &Rc<RObject>, path: &str) {
+    request.set_ivar(
+        REQUEST_PATH_IVAR_KEY,
+        RObject::string(path.to_string()).to_refcount_assigned(),
+    );
+}
+
+/// Looks up a header of a request object by case-insensitive name.
+pub(crate) fn uzumibi_request_header(
+    request: &Rc<RObject>,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let headers = request.get_ivar(REQUEST_HEADERS_IVAR_KEY);
+    if let RValue::Hash(h) = &headers.value {
+        let headers_h = h.borrow();
+        for (_, (key_obj, value_obj)) in headers_h.iter() {
+            let key: String = key_obj.as_ref().try_into()?;
+            if key.eq_ignore_ascii_case(name) {
+                let value: String = value_obj.as_ref().try_into()?;
+                return Ok(Some(value));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Merges route params into `req.params`. Path params have the lowest
+/// precedence, so keys already set from the query or body are kept.
+pub(crate) fn uzumibi_request_merge_route_params(
+    vm: &mut VM,
+    request: &Rc<RObject>,
+    route_params: &Rc<RObject>,
+) -> Result<(), Error> {
+    let mut params = request.get_ivar(REQUEST_PARAMS_IVAR_KEY);
+    if params.is_falsy() {
+        params = mrb_hash_new(vm, &[])?;
+        request.set_ivar(REQUEST_PARAMS_IVAR_KEY, params.clone());
+    }
+    let existing: Vec<String> = match &params.value {
+        RValue::Hash(h) => {
+            let params_h = h.borrow();
+            let mut keys = Vec::new();
+            for (_, (key_obj, _)) in params_h.iter() {
+                keys.push(key_obj.as_ref().try_into()?);
+            }
+            keys
+        }
+        _ => return Err(Error::RuntimeError("params must be a Hash".to_string())),
+    };
+    let entries: Vec<(Rc<RObject>, Rc<RObject>)> = match &route_params.value {
+        RValue::Hash(h) => h
+            .borrow()
+            .iter()
+            .map(|(_, (k, v))| (k.clone(), v.clone()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    for (key_obj, value_obj) in entries {
+        let key: String = key_obj.as_ref().try_into()?;
+        if !existing.contains(&key) {
+            mrb_hash_set_index(params.clone(), key_obj, value_obj)?;
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn uzumibi_request_new(vm: &mut VM) -> Rc<RObject> {
     let uzumibi = vm
         .get_const_by_name("Uzumibi")
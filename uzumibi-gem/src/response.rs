@@ -7,11 +7,23 @@
 //!   module Uzumibi
 //!     class Response
 //!       def status_code: Integer # u16
-//!       def headers: Hash<String, String>
-//!       def body: String
+//!       def headers: Hash<String, String | Array[String]>
+//!       def body: String | Enumerable[String]
 //!       def to_shared_memory() -> SharedMemory
+//!       def return(status_code: Integer, headers: Hash<String, String | Array[String]>, body: String) -> self
+//!       def json(value: untyped, status: Integer?) -> self
+//!       def text(body: String, status: Integer?) -> self
+//!       def html(body: String, status: Integer?) -> self
+//!       def send_data(data: String, type: String?, filename: String?, disposition: String?, status: Integer?) -> self
+//!       def redirect(location: String, status: Integer?) -> self
+//!       def no_content() -> self
 //! ```
 //!
+//! The builders `json`, `text`, `html`, `send_data`, `redirect` and
+//! `no_content` set the status, the body, and a `Content-Type` and
+//! `Content-Length` that match the body. Text types carry
+//! `charset=utf-8`. Other headers already set, such as cookies, are kept.
+//!
 use std::{collections::HashMap, rc::Rc};
 
 use mrubyedge::{
@@ -27,10 +39,15 @@ use mrubyedge::{
     },
 };
 
+use crate::{
+    helpers::{content_disposition, escape_json_string},
+    streaming::{uzumibi_response_collect_body, uzumibi_response_is_streaming},
+};
+
 #[derive(Debug)]
 pub struct Response {
     pub status_code: u16,
-    pub headers: HashMap<String, String>,
+    pub headers: Vec<(String, String)>,
     pub body: Vec<u8>,
 }
 
@@ -43,7 +60,11 @@ const RESPONSE_BODY_KEY: &str = "body";
 
 const RESPONSE_STATUS_CODE_IVAR_KEY: &str = "@status_code";
 const RESPONSE_HEADERS_IVAR_KEY: &str = "@headers";
-const RESPONSE_BODY_IVAR_KEY: &str = "@body";
+pub(crate) const RESPONSE_BODY_IVAR_KEY: &str = "@body";
+/// The block given to `res.stream`
+pub(crate) const RESPONSE_STREAM_IVAR_KEY: &str = "@_stream";
+/// The class of the object the stream block is called with
+pub(crate) const RESPONSE_STREAM_WRITER_IVAR_KEY: &str = "@_stream_writer";
 
 pub(crate) fn init_uzumibi_response(vm: &mut VM) {
     let uzumibi = vm
@@ -86,10 +107,46 @@ pub(crate) fn init_uzumibi_response(vm: &mut VM) {
     );
     mrb_define_cmethod(
         vm,
-        response_class_,
+        response_class_.clone(),
         "return",
         Box::new(uzumibi_response_return),
     );
+    mrb_define_cmethod(
+        vm,
+        response_class_.clone(),
+        "json",
+        Box::new(uzumibi_response_json),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class_.clone(),
+        "text",
+        Box::new(uzumibi_response_text),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class_.clone(),
+        "html",
+        Box::new(uzumibi_response_html),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class_.clone(),
+        "send_data",
+        Box::new(uzumibi_response_send_data),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class_.clone(),
+        "redirect",
+        Box::new(uzumibi_response_redirect),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class_,
+        "no_content",
+        Box::new(uzumibi_response_no_content),
+    );
 }
 
 fn as_sym(name: impl Into<String>) -> Rc<RObject> {
@@ -139,6 +196,227 @@ fn uzumibi_response_return(
     Ok(self_obj)
 }
 
+/// res.json(value, status: 200) -> self
+fn uzumibi_response_json(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let kwargs = keyword_arguments(vm);
+    let status = builder_status(kwargs.get("status"), 200)?;
+    let self_obj = vm.getself()?;
+    let value = match args.first() {
+        Some(value) => value.clone(),
+        None => {
+            return Err(Error::ArgumentError(
+                "Expected a value to serialize".to_string(),
+            ));
+        }
+    };
+    let body = as_string(uzumibi_json_generate(vm, value)?);
+    uzumibi_response_build(
+        vm,
+        &self_obj,
+        status,
+        Some("application/json; charset=utf-8"),
+        body,
+    )?;
+    Ok(self_obj)
+}
+
+/// res.text(body, status: 200) -> self
+fn uzumibi_response_text(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_response_text_as(vm, args, "text/plain; charset=utf-8")
+}
+
+/// res.html(body, status: 200) -> self
+fn uzumibi_response_html(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_response_text_as(vm, args, "text/html; charset=utf-8")
+}
+
+fn uzumibi_response_text_as(
+    vm: &mut VM,
+    args: &[Rc<RObject>],
+    content_type: &str,
+) -> Result<Rc<RObject>, Error> {
+    let kwargs = keyword_arguments(vm);
+    let status = builder_status(kwargs.get("status"), 200)?;
+    let self_obj = vm.getself()?;
+    let body = builder_body(vm, args)?;
+    uzumibi_response_build(vm, &self_obj, status, Some(content_type), body)?;
+    Ok(self_obj)
+}
+
+/// res.send_data(data, type: "application/octet-stream", filename: nil,
+///   disposition: nil, status: 200) -> self
+///
+/// The bytes are sent as they are, without a charset. A `filename:`
+/// makes the disposition `attachment` unless `disposition: "inline"` is
+/// given.
+fn uzumibi_response_send_data(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let kwargs = keyword_arguments(vm);
+    let status = builder_status(kwargs.get("status"), 200)?;
+    let self_obj = vm.getself()?;
+    let data = builder_body(vm, args)?;
+
+    let option = |key: &str| kwargs.get(key).filter(|v| v.is_truthy()).cloned();
+    let content_type = match option("type") {
+        Some(content_type) => header_argument(vm, "type", &content_type)?,
+        None => "application/octet-stream".to_string(),
+    };
+    let filename = match option("filename") {
+        Some(filename) => Some(header_argument(vm, "filename", &filename)?),
+        None => None,
+    };
+    let disposition = match option("disposition") {
+        Some(disposition) => Some(header_argument(vm, "disposition", &disposition)?),
+        None => filename.as_ref().map(|_| "attachment".to_string()),
+    };
+    if let Some(disposition) = &disposition
+        && !["attachment", "inline"].contains(&disposition.as_str())
+    {
+        return Err(Error::ArgumentError(format!(
+            "disposition must be attachment or inline, got {}",
+            disposition
+        )));
+    }
+
+    uzumibi_response_build(vm, &self_obj, status, Some(&content_type), data)?;
+    match disposition {
+        Some(disposition) => uzumibi_response_set_header(
+            vm,
+            &self_obj,
+            "Content-Disposition",
+            &content_disposition(&disposition, filename.as_deref()),
+        )?,
+        None => uzumibi_response_remove_header(vm, &self_obj, "Content-Disposition")?,
+    }
+    Ok(self_obj)
+}
+
+/// res.redirect(location, status: 302) -> self
+///
+/// Unlike the router's `redirect`, this does not stop the route block.
+fn uzumibi_response_redirect(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let kwargs = keyword_arguments(vm);
+    let self_obj = vm.getself()?;
+    let (location, status) =
+        redirect_arguments(vm, args.first(), kwargs.get("status").or(args.get(1)))?;
+    uzumibi_response_build(vm, &self_obj, status, None, as_string(""))?;
+    uzumibi_response_set_header(vm, &self_obj, "Location", &location)?;
+    Ok(self_obj)
+}
+
+/// res.no_content -> self
+///
+/// A 204 response has no body, so it is sent without `Content-Type` or
+/// `Content-Length`.
+fn uzumibi_response_no_content(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let self_obj = vm.getself()?;
+    uzumibi_response_fill(vm, &self_obj, 204, as_string(""))?;
+    uzumibi_response_remove_header(vm, &self_obj, "Content-Type")?;
+    uzumibi_response_remove_header(vm, &self_obj, "Content-Length")?;
+    Ok(self_obj)
+}
+
+/// Keyword arguments, which must be read before any other method is
+/// called on the VM.
+pub(crate) fn keyword_arguments(vm: &mut VM) -> HashMap<String, Rc<RObject>> {
+    match vm.get_kwargs() {
+        Some(kwargs) => kwargs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect(),
+        None => HashMap::new(),
+    }
+}
+
+/// The `status:` of a builder, which must be an HTTP status code.
+fn builder_status(status: Option<&Rc<RObject>>, default: u16) -> Result<u16, Error> {
+    match status {
+        None => Ok(default),
+        Some(status) => match status.value {
+            RValue::Integer(code) if (100..=599).contains(&code) => Ok(code as u16),
+            _ => Err(Error::ArgumentError(
+                "status must be an HTTP status code".to_string(),
+            )),
+        },
+    }
+}
+
+/// The body argument of a builder as a String; `nil` is an empty body.
+fn builder_body(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    match args.first() {
+        Some(body) if matches!(body.value, RValue::String(_, _)) => Ok(body.clone()),
+        Some(body) if matches!(body.value, RValue::Nil) => Ok(as_string("")),
+        Some(body) => mrb_funcall(vm, Some(body.clone()), "to_s", &[]),
+        None => Err(Error::ArgumentError("Expected a body".to_string())),
+    }
+}
+
+/// The Location and status of a redirect, shared by `res.redirect` and
+/// the router's `redirect`. The status defaults to 302 and must be 3xx.
+pub(crate) fn redirect_arguments(
+    vm: &mut VM,
+    location: Option<&Rc<RObject>>,
+    status: Option<&Rc<RObject>>,
+) -> Result<(String, u16), Error> {
+    let status = builder_status(status, 302)?;
+    if !(300..400).contains(&status) {
+        return Err(Error::ArgumentError(format!(
+            "redirect status must be 3xx, got {}",
+            status
+        )));
+    }
+    let location = match location {
+        Some(location) if location.is_truthy() => header_argument(vm, "location", location)?,
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a location as the first argument".to_string(),
+            ));
+        }
+    };
+    Ok((location, status))
+}
+
+/// An argument that ends up in a header value, which must not contain
+/// control characters.
+fn header_argument(vm: &mut VM, name: &str, value: &Rc<RObject>) -> Result<String, Error> {
+    let value: String = match &value.value {
+        RValue::String(_, _) => value.as_ref().try_into()?,
+        _ => {
+            let value = mrb_funcall(vm, Some(value.clone()), "to_s", &[])?;
+            value.as_ref().try_into()?
+        }
+    };
+    if value.chars().any(|c| c.is_ascii_control()) {
+        return Err(Error::ArgumentError(format!(
+            "{} must not contain control characters",
+            name
+        )));
+    }
+    Ok(value)
+}
+
+/// Sets status and body with a `Content-Length` that matches the body,
+/// and the `Content-Type`, or none when `content_type` is `None`.
+fn uzumibi_response_build(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    status_code: u16,
+    content_type: Option<&str>,
+    body: Rc<RObject>,
+) -> Result<(), Error> {
+    let length = match &body.value {
+        RValue::String(s, _) => s.borrow().len(),
+        _ => 0,
+    };
+    uzumibi_response_fill(vm, response, status_code, body)?;
+    match content_type {
+        Some(content_type) => {
+            uzumibi_response_set_header(vm, response, "Content-Type", content_type)?
+        }
+        None => uzumibi_response_remove_header(vm, response, "Content-Type")?,
+    }
+    uzumibi_response_set_header(vm, response, "Content-Length", &length.to_string())
+}
+
 fn uzumibi_response_to_shared_memory(
     vm: &mut VM,
     _args: &[Rc<RObject>],
@@ -148,14 +426,8 @@ fn uzumibi_response_to_shared_memory(
         .get_ivar(RESPONSE_STATUS_CODE_IVAR_KEY)
         .as_ref()
         .try_into()?;
-    let headers: Vec<(Rc<RObject>, Rc<RObject>)> = response
-        .get_ivar(RESPONSE_HEADERS_IVAR_KEY)
-        .as_ref()
-        .try_into()?;
-    let body: String = response
-        .get_ivar(RESPONSE_BODY_IVAR_KEY)
-        .as_ref()
-        .try_into()?;
+    let headers = uzumibi_response_header_pairs(&response)?;
+    let body = uzumibi_response_body_bytes(vm, &response)?;
 
     let mut buf: Vec<u8> = Vec::with_capacity(65536);
     let mut status_code_buf = [0u8; 2];
@@ -167,10 +439,7 @@ fn uzumibi_response_to_shared_memory(
     headers_count_buf[..2].copy_from_slice(&headers_count.to_le_bytes()[..2]);
     buf.extend_from_slice(&headers_count_buf);
 
-    for (key, value) in headers.iter() {
-        let key_str: String = key.as_ref().try_into()?;
-        let value_str: String = value.as_ref().try_into()?;
-
+    for (key_str, value_str) in headers.iter() {
         let key_bytes = key_str.as_bytes();
         let key_size = key_bytes.len() as u16;
         let mut key_size_buf = [0u8; 2];
@@ -186,7 +455,7 @@ fn uzumibi_response_to_shared_memory(
         buf.extend_from_slice(value_bytes);
     }
 
-    let body_bytes = body.as_bytes();
+    let body_bytes = body.as_slice();
     let body_size = body_bytes.len() as u32;
     let mut body_size_buf = [0u8; 4];
     body_size_buf[..4].copy_from_slice(&body_size.to_le_bytes()[..4]);
@@ -200,13 +469,399 @@ fn uzumibi_response_to_shared_memory(
     Ok(memory)
 }
 
+/// The body of a response as bytes, exactly as the Ruby String holds
+/// them, so binary bodies such as images pass through unchanged.
+/// A streamed body is collected into one.
+pub fn uzumibi_response_body_bytes(vm: &mut VM, response: &Rc<RObject>) -> Result<Vec<u8>, Error> {
+    if uzumibi_response_is_streaming(response) {
+        return uzumibi_response_collect_body(vm, response);
+    }
+    let body = response.get_ivar(RESPONSE_BODY_IVAR_KEY);
+    match &body.value {
+        RValue::String(s, _) => Ok(s.borrow().to_vec()),
+        RValue::Nil => Ok(Vec::new()),
+        _ => Err(Error::RuntimeError("body must be a String".to_string())),
+    }
+}
+
+/// Serializes a Ruby object with `JSON.generate`.
+/// The JSON module is available with the `use-json` feature.
+pub(crate) fn uzumibi_json_generate(vm: &mut VM, value: Rc<RObject>) -> Result<String, Error> {
+    let json = vm.get_const_by_name("JSON").ok_or_else(|| {
+        Error::RuntimeError("JSON is not available; enable the use-json feature".to_string())
+    })?;
+    let generated = mrb_funcall(vm, Some(json), "generate", &[value])?;
+    generated.as_ref().try_into()
+}
+
+/// Turns the value returned by a route block into the response, unless
+/// the block already set the status code:
+///
+/// - String: 200 with a `text/plain` body
+/// - `[status, headers, body]`: used as is; an Array body is joined
+/// - other Hash or Array: 200 with a JSON body
+///
+/// Any other value, including the Response itself, leaves it untouched.
+pub(crate) fn uzumibi_response_coerce(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    value: Rc<RObject>,
+) -> Result<(), Error> {
+    if uzumibi_response_is_filled(response) {
+        return Ok(());
+    }
+    match &value.value {
+        RValue::String(_, _) => {
+            uzumibi_response_fill(vm, response, 200, value.clone())?;
+            uzumibi_response_set_default_header(
+                vm,
+                response,
+                "Content-Type",
+                "text/plain; charset=utf-8",
+            )?;
+        }
+        RValue::Array(arr) if is_rack_triplet(&arr.borrow()) => {
+            let triplet = arr.borrow().clone();
+            let body = match &triplet[2].value {
+                RValue::Array(_) => mrb_funcall(vm, Some(triplet[2].clone()), "join", &[])?,
+                RValue::Nil => as_string(""),
+                _ => triplet[2].clone(),
+            };
+            let headers = if triplet[1].is_truthy() {
+                triplet[1].clone()
+            } else {
+                mrb_hash_new(vm, &[])?
+            };
+            response.set_ivar(RESPONSE_STATUS_CODE_IVAR_KEY, triplet[0].clone());
+            response.set_ivar(RESPONSE_HEADERS_IVAR_KEY, headers);
+            response.set_ivar(RESPONSE_BODY_IVAR_KEY, body);
+        }
+        RValue::Array(_) | RValue::Hash(_) => {
+            let body = uzumibi_json_generate(vm, value.clone())?;
+            uzumibi_response_fill(vm, response, 200, as_string(body))?;
+            uzumibi_response_set_default_header(
+                vm,
+                response,
+                "Content-Type",
+                "application/json; charset=utf-8",
+            )?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_rack_triplet(items: &[Rc<RObject>]) -> bool {
+    items.len() == 3
+        && matches!(items[0].value, RValue::Integer(_))
+        && matches!(items[1].value, RValue::Hash(_) | RValue::Nil)
+}
+
+/// Sets status and body, keeping headers the handler already set.
+pub(crate) fn uzumibi_response_fill(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    status_code: u16,
+    body: Rc<RObject>,
+) -> Result<(), Error> {
+    response.set_ivar(
+        RESPONSE_STATUS_CODE_IVAR_KEY,
+        RObject::integer(status_code as i64).to_refcount_assigned(),
+    );
+    if response.get_ivar(RESPONSE_HEADERS_IVAR_KEY).is_falsy() {
+        let headers = mrb_hash_new(vm, &[])?;
+        response.set_ivar(RESPONSE_HEADERS_IVAR_KEY, headers);
+    }
+    response.set_ivar(RESPONSE_BODY_IVAR_KEY, body);
+    response.set_ivar(
+        RESPONSE_STREAM_IVAR_KEY,
+        RObject::nil().to_refcount_assigned(),
+    );
+    Ok(())
+}
+
+/// Sets status code, headers and body from `[status, headers, body]`,
+/// like `res.return`.
+pub(crate) fn uzumibi_response_return_values(response: &Rc<RObject>, values: &[Rc<RObject>]) {
+    response.set_ivar(
+        RESPONSE_STREAM_IVAR_KEY,
+        RObject::nil().to_refcount_assigned(),
+    );
+    for (key, value) in [
+        RESPONSE_STATUS_CODE_IVAR_KEY,
+        RESPONSE_HEADERS_IVAR_KEY,
+        RESPONSE_BODY_IVAR_KEY,
+    ]
+    .into_iter()
+    .zip(values)
+    {
+        response.set_ivar(key, value.clone());
+    }
+}
+
+/// Copies status code, headers, body and stream from one response object
+/// to another.
+pub(crate) fn uzumibi_response_replace(target: &Rc<RObject>, source: &Rc<RObject>) {
+    for key in [
+        RESPONSE_STATUS_CODE_IVAR_KEY,
+        RESPONSE_HEADERS_IVAR_KEY,
+        RESPONSE_BODY_IVAR_KEY,
+        RESPONSE_STREAM_IVAR_KEY,
+        RESPONSE_STREAM_WRITER_IVAR_KEY,
+    ] {
+        target.set_ivar(key, source.get_ivar(key));
+    }
+}
+
+/// Whether a handler has already set the status code of a response.
+pub(crate) fn uzumibi_response_is_filled(response: &Rc<RObject>) -> bool {
+    response.get_ivar(RESPONSE_STATUS_CODE_IVAR_KEY).is_truthy()
+}
+
+/// The values of a request or response header, which is a String or,
+/// when the header is sent more than once, an Array of Strings.
+pub(crate) fn uzumibi_header_values(value: &Rc<RObject>) -> Result<Vec<String>, Error> {
+    match &value.value {
+        RValue::Array(values) => values
+            .borrow()
+            .iter()
+            .map(|value| value.as_ref().try_into())
+            .collect(),
+        _ => Ok(vec![value.as_ref().try_into()?]),
+    }
+}
+
+/// Finds a response header by case-insensitive name.
+/// Returns the stored key object and the value as a String; the values
+/// of a repeated header are joined with `, `.
+pub(crate) fn uzumibi_response_get_header(
+    response: &Rc<RObject>,
+    name: &str,
+) -> Result<Option<(Rc<RObject>, String)>, Error> {
+    Ok(uzumibi_response_get_header_values(response, name)?
+        .map(|(key_obj, values)| (key_obj, values.join(", "))))
+}
+
+/// A header's stored key object and every value it is sent with.
+type HeaderValues = (Rc<RObject>, Vec<String>);
+
+/// Finds a response header by case-insensitive name, with every value
+/// it is sent with.
+pub(crate) fn uzumibi_response_get_header_values(
+    response: &Rc<RObject>,
+    name: &str,
+) -> Result<Option<HeaderValues>, Error> {
+    let headers = response.get_ivar(RESPONSE_HEADERS_IVAR_KEY);
+    if let RValue::Hash(h) = &headers.value {
+        let headers_h = h.borrow();
+        for (_, (key_obj, value_obj)) in headers_h.iter() {
+            let key: String = key_obj.as_ref().try_into()?;
+            if key.eq_ignore_ascii_case(name) {
+                return Ok(Some((key_obj.clone(), uzumibi_header_values(value_obj)?)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// All response headers as name and value pairs. A header with an Array
+/// value gives one pair per element, in Array order; the headers
+/// themselves come in the Hash's iteration order.
+pub fn uzumibi_response_header_pairs(
+    response: &Rc<RObject>,
+) -> Result<Vec<(String, String)>, Error> {
+    let headers = response.get_ivar(RESPONSE_HEADERS_IVAR_KEY);
+    let mut pairs = Vec::new();
+    if let RValue::Hash(h) = &headers.value {
+        for (_, (key_obj, value_obj)) in h.borrow().iter() {
+            let key: String = key_obj.as_ref().try_into()?;
+            for value in uzumibi_header_values(value_obj)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// Sets a response header, replacing an existing value stored under
+/// any casing of the same name.
+pub(crate) fn uzumibi_response_set_header(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    name: &str,
+    value: &str,
+) -> Result<(), Error> {
+    uzumibi_response_set_header_values(vm, response, name, vec![value.to_string()])
+}
+
+/// Sets every value of a response header, replacing an existing value
+/// stored under any casing of the same name. Several values are stored
+/// as an Array.
+pub(crate) fn uzumibi_response_set_header_values(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    name: &str,
+    mut values: Vec<String>,
+) -> Result<(), Error> {
+    let mut headers = response.get_ivar(RESPONSE_HEADERS_IVAR_KEY);
+    if headers.is_falsy() {
+        headers = mrb_hash_new(vm, &[])?;
+        response.set_ivar(RESPONSE_HEADERS_IVAR_KEY, headers.clone());
+    }
+    let key = match uzumibi_response_get_header_values(response, name)? {
+        Some((key_obj, _)) => key_obj,
+        None => as_string(name),
+    };
+    let value = if values.len() == 1 {
+        as_string(values.remove(0))
+    } else {
+        RObject::array(values.into_iter().map(as_string).collect()).to_refcount_assigned()
+    };
+    mrb_hash_set_index(headers, key, value)?;
+    Ok(())
+}
+
+/// Removes a response header stored under any casing of the name.
+/// The headers Hash is rebuilt without it.
+pub(crate) fn uzumibi_response_remove_header(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    name: &str,
+) -> Result<(), Error> {
+    let headers = response.get_ivar(RESPONSE_HEADERS_IVAR_KEY);
+    let pairs: Vec<(Rc<RObject>, Rc<RObject>)> = match &headers.value {
+        RValue::Hash(h) => h
+            .borrow()
+            .iter()
+            .map(|(_, (key, value))| (key.clone(), value.clone()))
+            .collect(),
+        _ => return Ok(()),
+    };
+    let mut kept = Vec::with_capacity(pairs.len());
+    for (key_obj, value) in pairs.iter() {
+        let key: String = key_obj.as_ref().try_into()?;
+        if !key.eq_ignore_ascii_case(name) {
+            kept.push((key_obj.clone(), value.clone()));
+        }
+    }
+    if kept.len() == pairs.len() {
+        return Ok(());
+    }
+    let headers = mrb_hash_new(vm, &[])?;
+    for (key, value) in kept {
+        mrb_hash_set_index(headers.clone(), key, value)?;
+    }
+    response.set_ivar(RESPONSE_HEADERS_IVAR_KEY, headers);
+    Ok(())
+}
+
+/// Sets a response header only when the handler did not set it.
+pub(crate) fn uzumibi_response_set_default_header(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    name: &str,
+    value: &str,
+) -> Result<(), Error> {
+    if uzumibi_response_get_header(response, name)?.is_none() {
+        uzumibi_response_set_header(vm, response, name, value)?;
+    }
+    Ok(())
+}
+
 pub(crate) fn uzumibi_return_notfound(vm: &mut VM) -> Result<Rc<RObject>, Error> {
+    uzumibi_return_plain_error(vm, 404, "Not Found")
+}
+
+/// Builds a 405 response whose `Allow` header lists the methods
+/// registered for the requested path.
+pub(crate) fn uzumibi_return_method_not_allowed(
+    vm: &mut VM,
+    allowed: &[String],
+) -> Result<Rc<RObject>, Error> {
+    let response = uzumibi_return_plain_error(vm, 405, "Method Not Allowed")?;
+    let response_headers = response.get_ivar(RESPONSE_HEADERS_IVAR_KEY);
+    mrb_hash_set_index(
+        response_headers,
+        as_string("Allow"),
+        as_string(allowed.join(", ")),
+    )?;
+    Ok(response)
+}
+
+/// Builds the automatic 204 response for an OPTIONS request to a path
+/// that has no explicit OPTIONS route.
+pub(crate) fn uzumibi_return_options(
+    vm: &mut VM,
+    allowed: &[String],
+) -> Result<Rc<RObject>, Error> {
+    let response = uzumibi_response_new(vm);
+    response.set_ivar(
+        RESPONSE_STATUS_CODE_IVAR_KEY,
+        RObject::integer(204).to_refcount_assigned(),
+    );
+    response.set_ivar(RESPONSE_BODY_IVAR_KEY, as_string(""));
+
+    let response_headers = mrb_hash_new(vm, &[])?;
+    mrb_hash_set_index(
+        response_headers.clone(),
+        as_string("Allow"),
+        as_string(allowed.join(", ")),
+    )?;
+    mrb_hash_set_index(
+        response_headers.clone(),
+        as_string("Content-Length"),
+        as_string("0"),
+    )?;
+    response.set_ivar(RESPONSE_HEADERS_IVAR_KEY, response_headers);
+
+    Ok(response)
+}
+
+/// Builds the default 500 response for an unhandled exception.
+/// The body is a JSON error envelope carrying the request id, which is
+/// also sent as `X-Request-Id`.
+pub(crate) fn uzumibi_return_internal_error(
+    vm: &mut VM,
+    request_id: &str,
+) -> Result<Rc<RObject>, Error> {
+    let response_body = format!(
+        "{{\"error\":\"Internal Server Error\",\"request_id\":\"{}\"}}",
+        escape_json_string(request_id)
+    );
+    let response = uzumibi_response_new(vm);
+    response.set_ivar(
+        RESPONSE_STATUS_CODE_IVAR_KEY,
+        RObject::integer(500).to_refcount_assigned(),
+    );
+
+    let response_headers = mrb_hash_new(vm, &[])?;
+    for (name, value) in [
+        (
+            "Content-Type",
+            "application/json; charset=utf-8".to_string(),
+        ),
+        ("Content-Length", response_body.len().to_string()),
+        ("Cache-Control", "no-cache".to_string()),
+        ("X-Request-Id", request_id.to_string()),
+    ] {
+        mrb_hash_set_index(response_headers.clone(), as_string(name), as_string(value))?;
+    }
+    response.set_ivar(RESPONSE_HEADERS_IVAR_KEY, response_headers);
+    response.set_ivar(RESPONSE_BODY_IVAR_KEY, as_string(response_body));
+
+    Ok(response)
+}
+
+fn uzumibi_return_plain_error(
+    vm: &mut VM,
+    status_code: u16,
+    response_body: &str,
+) -> Result<Rc<RObject>, Error> {
     let response = uzumibi_response_new(vm);
     response.set_ivar(
         RESPONSE_STATUS_CODE_IVAR_KEY,
-        RObject::integer(404).to_refcount_assigned(),
+        RObject::integer(status_code as i64).to_refcount_assigned(),
     );
-    let response_body = "Not Found";
     response.set_ivar(
         RESPONSE_BODY_IVAR_KEY,
         RObject::string(response_body.to_string()).to_refcount_assigned(),
@@ -0,0 +1,110 @@
+//! This module keeps the list of routes registered on Uzumibi::Router.
+//! The ArtRouter store only answers lookups, so the original patterns are
+//! recorded here as routes are defined.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.route_table() -> Array[[String, String, Symbol?, Hash[Symbol, untyped]]]
+//! ```
+//!
+//! Each entry is `[method, pattern, name, options]`. The method is
+//! upper-case, `ANY` for routes defined with `any`. `options` holds the
+//! keyword options of the definition other than `as:`. Routes of mounted
+//! routers follow, with the mount prefix applied to their patterns.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::mrb_funcall,
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RSym, RValue},
+        vm::VM,
+    },
+};
+
+use crate::mount::{join_path, uzumibi_router_mounts};
+
+const ROUTE_ENTRIES_KEY: &str = "@_route_entries";
+
+/// Records a route definition for `route_table`.
+pub(crate) fn uzumibi_router_record_route(
+    vm: &mut VM,
+    klass: &Rc<RObject>,
+    method: &str,
+    pattern: &str,
+    options: &[(String, Rc<RObject>)],
+) -> Result<(), Error> {
+    let method = if method == "*" { "ANY" } else { method };
+    let mut name = RObject::nil().to_refcount_assigned();
+    let options_hash = mrb_hash_new(vm, &[])?;
+    for (key, value) in options {
+        if key == "as" {
+            name = value.clone();
+            continue;
+        }
+        mrb_hash_set_index(
+            options_hash.clone(),
+            RObject::symbol(RSym::new(key.to_string())).to_refcount_assigned(),
+            value.clone(),
+        )?;
+    }
+    if let RValue::String(_, _) = &name.value {
+        name = mrb_funcall(vm, Some(name), "to_sym", &[])?;
+    }
+
+    let mut entries = klass.get_ivar(ROUTE_ENTRIES_KEY);
+    if entries.is_falsy() {
+        entries = RObject::array(vec![]).to_refcount_assigned();
+        klass.set_ivar(ROUTE_ENTRIES_KEY, entries.clone());
+    }
+    let entry = RObject::array(vec![
+        RObject::string(method.to_string()).to_refcount_assigned(),
+        RObject::string(pattern.to_string()).to_refcount_assigned(),
+        name,
+        options_hash,
+    ])
+    .to_refcount_assigned();
+    mrb_funcall(vm, Some(entries), "push", &[entry])?;
+    Ok(())
+}
+
+/// Collects the entries of a router class and its mounted routers.
+fn uzumibi_route_entries(
+    klass: &Rc<RObject>,
+    prefix: &str,
+    entries: &mut Vec<Rc<RObject>>,
+) -> Result<(), Error> {
+    if let RValue::Array(arr) = &klass.get_ivar(ROUTE_ENTRIES_KEY).value {
+        for entry in arr.borrow().iter() {
+            let RValue::Array(fields) = &entry.value else {
+                continue;
+            };
+            let mut fields = fields.borrow().clone();
+            if !prefix.is_empty() {
+                let pattern: String = fields[1].as_ref().try_into()?;
+                fields[1] = RObject::string(join_path(prefix, &pattern)).to_refcount_assigned();
+            }
+            entries.push(RObject::array(fields).to_refcount_assigned());
+        }
+    }
+    for (mount_prefix, router) in uzumibi_router_mounts(klass)? {
+        uzumibi_route_entries(&router, &join_path(prefix, &mount_prefix), entries)?;
+    }
+    Ok(())
+}
+
+/// route_table() -> [[method, pattern, name, options], ...]
+pub(crate) fn uzumibi_router_route_table(
+    vm: &mut VM,
+    _args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let klass = vm.getself()?;
+    let mut entries = Vec::new();
+    uzumibi_route_entries(&klass, "", &mut entries)?;
+    Ok(RObject::array(entries).to_refcount_assigned())
+}
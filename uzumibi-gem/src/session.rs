@@ -0,0 +1,704 @@
+//! This module implements cookie sessions.
+//! `init_uzumibi_session()` should be called on prelude process, after
+//! `init_uzumibi_middleware()`.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.enable_sessions(secret: String, **options) -> nil
+//!     end
+//!     class Request
+//!       def session: Hash[untyped, untyped]
+//!     end
+//!     class Session
+//!       def initialize(options: Hash[Symbol, untyped])
+//!       def call(req: Request, res: Response, app: MiddlewareChain) -> Response
+//! ```
+//!
+//! `enable_sessions secret: ...` is `use Uzumibi::Session, secret: ...`.
+//! The middleware loads `req.session` from the session cookie before the
+//! rest of the stack runs, and writes the cookie back when the session
+//! changed. The cookie holds the whole session, so no store is needed:
+//!
+//!   base64url(payload) "." base64url(HMAC-SHA256(sign key, name "=" payload))
+//!
+//! The payload is the session in a small binary format (see [`Value`])
+//! that keeps Symbols, Integers, Floats and nesting as they were. With
+//! `encrypt: true` it is encrypted with ChaCha20 first. The nonce is
+//! derived from the plaintext with HMAC, since wasm hosts may have no
+//! random source; equal sessions therefore give equal cookies, which
+//! reveals only that two sessions are the same. Both keys are derived from
+//! `secret`, which must be at least 32 bytes long.
+//!
+use std::rc::Rc;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chacha20::{
+    ChaCha20,
+    cipher::{KeyIvInit, StreamCipher},
+};
+use hmac::{Hmac, Mac};
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_class_cmethod, mrb_define_cmethod, mrb_funcall},
+        prelude::hash::{mrb_hash_new, mrb_hash_set_index},
+        value::{RObject, RSym, RValue},
+        vm::VM,
+    },
+};
+use sha2::Sha256;
+
+use crate::cookies::{CookieAttributes, EXPIRED, uzumibi_response_add_cookie};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const REQUEST_SESSION_IVAR_KEY: &str = "@session";
+const SESSION_CONFIG_IVAR_KEY: &str = "@_config";
+
+const DEFAULT_COOKIE_NAME: &str = "uzumibi.session";
+const MIN_SECRET_BYTES: usize = 32;
+/// Browsers drop cookies larger than this, name and attributes included.
+const MAX_COOKIE_BYTES: usize = 4096;
+const NONCE_BYTES: usize = 12;
+/// Nesting allowed in a session, so that a forged payload cannot exhaust
+/// the stack even with a valid signature from a leaked secret.
+const MAX_DEPTH: usize = 32;
+
+/// Settings of the Session middleware, read from its options.
+#[derive(Debug, Clone)]
+struct SessionConfig {
+    name: String,
+    sign_key: [u8; 32],
+    encrypt_key: Option<[u8; 32]>,
+    path: String,
+    domain: Option<String>,
+    max_age: Option<i64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<String>,
+}
+
+pub(crate) fn init_uzumibi_session(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let class_of = |name: &str| match uzumibi_module.get_const_by_name(name) {
+        Some(class) => match &class.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("{} must be a class", name),
+        },
+        None => panic!("{} class must be defined beforehand", name),
+    };
+    let router_class = class_of("Router");
+    let request_class = class_of("Request");
+
+    mrb_define_class_cmethod(
+        vm,
+        router_class,
+        "enable_sessions",
+        Box::new(uzumibi_router_enable_sessions),
+    );
+    mrb_define_cmethod(
+        vm,
+        request_class,
+        "session",
+        Box::new(uzumibi_request_session),
+    );
+
+    let session_class = vm.define_class("Session", None, Some(uzumibi_module));
+    mrb_define_cmethod(
+        vm,
+        session_class.clone(),
+        "initialize",
+        Box::new(uzumibi_session_initialize),
+    );
+    mrb_define_cmethod(vm, session_class, "call", Box::new(uzumibi_session_call));
+}
+
+/// enable_sessions(secret:, key: "uzumibi.session", encrypt: false, ...) -> nil
+fn uzumibi_router_enable_sessions(
+    vm: &mut VM,
+    _args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let kwargs: Vec<(String, Rc<RObject>)> = match vm.get_kwargs() {
+        Some(kwargs) => kwargs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect(),
+        None => Vec::new(),
+    };
+    let options = mrb_hash_new(vm, &[])?;
+    for (key, value) in kwargs {
+        mrb_hash_set_index(
+            options.clone(),
+            RObject::symbol(RSym::new(key)).to_refcount_assigned(),
+            value,
+        )?;
+    }
+    let session_class = vm
+        .get_const_by_name("Uzumibi")
+        .and_then(|uzumibi| match &uzumibi.value {
+            RValue::Module(m) => m.get_const_by_name("Session"),
+            _ => None,
+        })
+        .ok_or_else(|| Error::RuntimeError("Session class is not defined".to_string()))?;
+    let klass = vm.getself()?;
+    mrb_funcall(vm, Some(klass), "use", &[session_class, options])
+}
+
+/// req.session -> Hash
+fn uzumibi_request_session(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let session = vm.getself()?.get_ivar(REQUEST_SESSION_IVAR_KEY);
+    if session.is_falsy() {
+        return Err(Error::RuntimeError(
+            "Sessions are not enabled; call enable_sessions in the router".to_string(),
+        ));
+    }
+    Ok(session)
+}
+
+/// Session#initialize(options)
+///
+/// The options are checked here, so that a missing or short secret fails
+/// when the app loads rather than on the first request.
+fn uzumibi_session_initialize(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let options = match args.first() {
+        Some(options) if matches!(options.value, RValue::Hash(_)) => options.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected options with secret:".to_string(),
+            ));
+        }
+    };
+    SessionConfig::from_options(vm, &options)?;
+    vm.getself()?.set_ivar(SESSION_CONFIG_IVAR_KEY, options);
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// Session#call(req, res, app) -> res
+fn uzumibi_session_call(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (request, response, app) = match args {
+        [request, response, app, ..] => (request.clone(), response.clone(), app.clone()),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected 3 arguments: request, response, app".to_string(),
+            ));
+        }
+    };
+    let options = vm.getself()?.get_ivar(SESSION_CONFIG_IVAR_KEY);
+    let config = SessionConfig::from_options(vm, &options)?;
+
+    let cookie = uzumibi_request_cookie(vm, &request, &config.name)?;
+    let decoded = cookie
+        .as_deref()
+        .and_then(|cookie| config.open(cookie))
+        .and_then(|payload| Value::decode(&payload));
+    // The baseline is taken from the Hash itself, as its order may differ
+    // from the cookie's
+    let (session, loaded) = match decoded {
+        Some(value @ Value::Hash(_)) => {
+            let session = value.to_robject(vm)?;
+            let loaded = Value::from_robject(vm, &session, 0)?.encode();
+            (session, Some(loaded))
+        }
+        _ => (mrb_hash_new(vm, &[])?, None),
+    };
+    request.set_ivar(REQUEST_SESSION_IVAR_KEY, session);
+
+    mrb_funcall(vm, Some(app), "call", &[request.clone(), response.clone()])?;
+
+    let session = request.get_ivar(REQUEST_SESSION_IVAR_KEY);
+    let value = Value::from_robject(vm, &session, 0)?;
+    let empty = matches!(&value, Value::Hash(pairs) if pairs.is_empty());
+    let payload = value.encode();
+    if loaded.as_ref() == Some(&payload) {
+        return Ok(response);
+    }
+    let set_cookie = if empty {
+        if cookie.is_none() {
+            return Ok(response);
+        }
+        config.expired_cookie()
+    } else {
+        let set_cookie = config.cookie(&config.seal(&payload));
+        if set_cookie.len() > MAX_COOKIE_BYTES {
+            return Err(Error::RuntimeError(format!(
+                "Session cookie is {} bytes, over the {} bytes browsers keep",
+                set_cookie.len(),
+                MAX_COOKIE_BYTES
+            )));
+        }
+        set_cookie
+    };
+    uzumibi_response_add_cookie(
+        vm,
+        &response,
+        &config.name,
+        &config.attributes(),
+        &set_cookie,
+    )?;
+    Ok(response)
+}
+
+fn uzumibi_request_cookie(
+    vm: &mut VM,
+    request: &Rc<RObject>,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let cookies = mrb_funcall(vm, Some(request.clone()), "cookie", &[])?;
+    if !matches!(cookies.value, RValue::Hash(_)) {
+        return Ok(None);
+    }
+    let name = RObject::string(name.to_string()).to_refcount_assigned();
+    let cookie = mrb_funcall(vm, Some(cookies), "[]", &[name])?;
+    match &cookie.value {
+        RValue::String(_, _) => Ok(Some(cookie.as_ref().try_into()?)),
+        _ => Ok(None),
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes a key of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+impl SessionConfig {
+    fn from_options(vm: &mut VM, options: &Rc<RObject>) -> Result<Self, Error> {
+        let mut get = |key: &str| -> Result<Option<Rc<RObject>>, Error> {
+            let key = RObject::symbol(RSym::new(key.to_string())).to_refcount_assigned();
+            let value = mrb_funcall(vm, Some(options.clone()), "[]", &[key])?;
+            Ok(Some(value).filter(|v| !matches!(v.value, RValue::Nil)))
+        };
+        let string = |value: Option<Rc<RObject>>| -> Result<Option<String>, Error> {
+            match value {
+                Some(value) => match &value.value {
+                    RValue::String(_, _) => Ok(Some(value.as_ref().try_into()?)),
+                    RValue::Symbol(sym) => Ok(Some(sym.name.clone())),
+                    _ => Err(Error::ArgumentError(
+                        "session options must be Strings".to_string(),
+                    )),
+                },
+                None => Ok(None),
+            }
+        };
+
+        let secret = match get("secret")? {
+            Some(secret) => match &secret.value {
+                RValue::String(s, _) => s.borrow().to_vec(),
+                _ => return Err(Error::ArgumentError("secret must be a String".to_string())),
+            },
+            None => {
+                return Err(Error::ArgumentError(
+                    "secret is required for sessions".to_string(),
+                ));
+            }
+        };
+        if secret.len() < MIN_SECRET_BYTES {
+            return Err(Error::ArgumentError(format!(
+                "secret must be at least {} bytes",
+                MIN_SECRET_BYTES
+            )));
+        }
+        let encrypt = get("encrypt")?.is_some_and(|v| v.is_truthy());
+        let max_age = match get("max_age")? {
+            Some(max_age) => match max_age.value {
+                RValue::Integer(seconds) => Some(seconds),
+                _ => {
+                    return Err(Error::ArgumentError(
+                        "max_age must be a number of seconds".to_string(),
+                    ));
+                }
+            },
+            None => None,
+        };
+        let secure = get("secure")?.is_some_and(|v| v.is_truthy());
+        let http_only = get("http_only")?.is_none_or(|v| v.is_truthy());
+        let name = string(get("key")?)?.unwrap_or_else(|| DEFAULT_COOKIE_NAME.to_string());
+        let path = string(get("path")?)?.unwrap_or_else(|| "/".to_string());
+        let domain = string(get("domain")?)?;
+        let same_site = match get("same_site")? {
+            Some(v) if v.is_falsy() => None,
+            v => Some(string(v)?.unwrap_or_else(|| "Lax".to_string())),
+        };
+
+        Ok(SessionConfig {
+            name,
+            sign_key: hmac_sha256(&secret, &[b"uzumibi.session.sign"]),
+            encrypt_key: encrypt.then(|| hmac_sha256(&secret, &[b"uzumibi.session.encrypt"])),
+            path,
+            domain,
+            max_age,
+            secure,
+            http_only,
+            same_site,
+        })
+    }
+
+    /// Signs, and encrypts if configured, a payload into a cookie value.
+    fn seal(&self, payload: &[u8]) -> String {
+        let payload = match &self.encrypt_key {
+            Some(key) => {
+                let nonce: [u8; NONCE_BYTES] = hmac_sha256(key, &[payload])[..NONCE_BYTES]
+                    .try_into()
+                    .expect("HMAC-SHA256 is longer than the nonce");
+                let mut sealed = payload.to_vec();
+                ChaCha20::new(key.into(), &nonce.into()).apply_keystream(&mut sealed);
+                [&nonce[..], &sealed].concat()
+            }
+            None => payload.to_vec(),
+        };
+        let encoded = URL_SAFE_NO_PAD.encode(payload);
+        let tag = hmac_sha256(
+            &self.sign_key,
+            &[self.name.as_bytes(), b"=", encoded.as_bytes()],
+        );
+        format!("{}.{}", encoded, URL_SAFE_NO_PAD.encode(tag))
+    }
+
+    /// The payload of a cookie value, or None when it was not sealed with
+    /// this secret and name.
+    fn open(&self, cookie: &str) -> Option<Vec<u8>> {
+        let (encoded, tag) = cookie.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        let mut mac = HmacSha256::new_from_slice(&self.sign_key).ok()?;
+        mac.update(self.name.as_bytes());
+        mac.update(b"=");
+        mac.update(encoded.as_bytes());
+        // Compares in constant time
+        mac.verify_slice(&tag).ok()?;
+
+        let payload = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+        match &self.encrypt_key {
+            Some(key) => {
+                if payload.len() < NONCE_BYTES {
+                    return None;
+                }
+                let (nonce, sealed) = payload.split_at(NONCE_BYTES);
+                let nonce: [u8; NONCE_BYTES] = nonce.try_into().ok()?;
+                let mut opened = sealed.to_vec();
+                ChaCha20::new(key.into(), &nonce.into()).apply_keystream(&mut opened);
+                Some(opened)
+            }
+            None => Some(payload),
+        }
+    }
+
+    fn attributes(&self) -> CookieAttributes {
+        CookieAttributes {
+            path: Some(self.path.clone()),
+            domain: self.domain.clone(),
+            expires: None,
+            max_age: self.max_age,
+            secure: self.secure,
+            http_only: self.http_only,
+            same_site: self.same_site.clone(),
+        }
+    }
+
+    fn cookie(&self, value: &str) -> String {
+        format!("{}={}{}", self.name, value, self.attributes().format())
+    }
+
+    fn expired_cookie(&self) -> String {
+        let attributes = CookieAttributes {
+            expires: Some(EXPIRED.to_string()),
+            max_age: Some(0),
+            ..self.attributes()
+        };
+        format!("{}={}", self.name, attributes.format())
+    }
+}
+
+/// A Ruby value that can be kept in a session.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(Vec<u8>),
+    Symbol(String),
+    Array(Vec<Value>),
+    Hash(Vec<(Value, Value)>),
+}
+
+impl Value {
+    fn from_robject(vm: &mut VM, value: &Rc<RObject>, depth: usize) -> Result<Self, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::ArgumentError(
+                "session is nested too deeply".to_string(),
+            ));
+        }
+        Ok(match &value.value {
+            RValue::Nil => Value::Nil,
+            RValue::Bool(b) => Value::Bool(*b),
+            RValue::Integer(n) => Value::Integer(*n),
+            RValue::Float(f) => Value::Float(*f),
+            RValue::String(s, _) => Value::String(s.borrow().to_vec()),
+            RValue::Symbol(sym) => Value::Symbol(sym.name.clone()),
+            RValue::Array(items) => {
+                let items = items.borrow().clone();
+                let mut values = Vec::with_capacity(items.len());
+                for item in items.iter() {
+                    values.push(Value::from_robject(vm, item, depth + 1)?);
+                }
+                Value::Array(values)
+            }
+            RValue::Hash(h) => {
+                let pairs: Vec<_> = h.borrow().values().cloned().collect();
+                let mut values = Vec::with_capacity(pairs.len());
+                for (key, value) in pairs.iter() {
+                    values.push((
+                        Value::from_robject(vm, key, depth + 1)?,
+                        Value::from_robject(vm, value, depth + 1)?,
+                    ));
+                }
+                Value::Hash(values)
+            }
+            _ => {
+                let inspected = mrb_funcall(vm, Some(value.clone()), "inspect", &[])?;
+                let inspected: String = inspected.as_ref().try_into()?;
+                return Err(Error::ArgumentError(format!(
+                    "cannot store {} in the session; use nil, true, false, Integer, Float, String, Symbol, Array or Hash",
+                    inspected
+                )));
+            }
+        })
+    }
+
+    fn to_robject(&self, vm: &mut VM) -> Result<Rc<RObject>, Error> {
+        Ok(match self {
+            Value::Nil => RObject::nil().to_refcount_assigned(),
+            Value::Bool(b) => RObject::boolean(*b).to_refcount_assigned(),
+            Value::Integer(n) => RObject::integer(*n).to_refcount_assigned(),
+            Value::Float(f) => RObject::float(*f).to_refcount_assigned(),
+            Value::String(s) => RObject::string_from_vec(s.clone()).to_refcount_assigned(),
+            Value::Symbol(name) => RObject::symbol(RSym::new(name.clone())).to_refcount_assigned(),
+            Value::Array(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(item.to_robject(vm)?);
+                }
+                RObject::array(values).to_refcount_assigned()
+            }
+            Value::Hash(pairs) => {
+                let hash = mrb_hash_new(vm, &[])?;
+                for (key, value) in pairs {
+                    let key = key.to_robject(vm)?;
+                    let value = value.to_robject(vm)?;
+                    mrb_hash_set_index(hash.clone(), key, value)?;
+                }
+                hash
+            }
+        })
+    }
+
+    /// Encodes a value as one tag byte followed by its contents. Lengths
+    /// and numbers are little-endian; strings are a u32 length and bytes,
+    /// Arrays and Hashes a u32 count and their items or key-value pairs.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        let bytes = |buf: &mut Vec<u8>, tag: u8, bytes: &[u8]| {
+            buf.push(tag);
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(bytes);
+        };
+        match self {
+            Value::Nil => buf.push(b'0'),
+            Value::Bool(true) => buf.push(b'T'),
+            Value::Bool(false) => buf.push(b'F'),
+            Value::Integer(n) => {
+                buf.push(b'i');
+                buf.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Float(f) => {
+                buf.push(b'f');
+                buf.extend_from_slice(&f.to_le_bytes());
+            }
+            Value::String(s) => bytes(buf, b'"', s),
+            Value::Symbol(name) => bytes(buf, b':', name.as_bytes()),
+            Value::Array(items) => {
+                buf.push(b'[');
+                buf.extend_from_slice(&(items.len() as u32).to_le_bytes());
+                for item in items {
+                    item.encode_into(buf);
+                }
+            }
+            Value::Hash(pairs) => {
+                buf.push(b'{');
+                buf.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+                for (key, value) in pairs {
+                    key.encode_into(buf);
+                    value.encode_into(buf);
+                }
+            }
+        }
+    }
+
+    /// Decodes a whole payload, or None when it is malformed.
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let mut rest = payload;
+        let value = Value::decode_from(&mut rest, 0)?;
+        rest.is_empty().then_some(value)
+    }
+
+    fn decode_from(rest: &mut &[u8], depth: usize) -> Option<Self> {
+        fn take<'a>(rest: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if rest.len() < n {
+                return None;
+            }
+            let (taken, remaining) = rest.split_at(n);
+            *rest = remaining;
+            Some(taken)
+        }
+        fn take_u32(rest: &mut &[u8]) -> Option<usize> {
+            Some(u32::from_le_bytes(take(rest, 4)?.try_into().ok()?) as usize)
+        }
+
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let tag = take(rest, 1)?[0];
+        Some(match tag {
+            b'0' => Value::Nil,
+            b'T' => Value::Bool(true),
+            b'F' => Value::Bool(false),
+            b'i' => Value::Integer(i64::from_le_bytes(take(rest, 8)?.try_into().ok()?)),
+            b'f' => Value::Float(f64::from_le_bytes(take(rest, 8)?.try_into().ok()?)),
+            b'"' => {
+                let len = take_u32(rest)?;
+                Value::String(take(rest, len)?.to_vec())
+            }
+            b':' => {
+                let len = take_u32(rest)?;
+                Value::Symbol(String::from_utf8(take(rest, len)?.to_vec()).ok()?)
+            }
+            b'[' => {
+                let count = take_u32(rest)?;
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    items.push(Value::decode_from(rest, depth + 1)?);
+                }
+                Value::Array(items)
+            }
+            b'{' => {
+                let count = take_u32(rest)?;
+                let mut pairs = Vec::new();
+                for _ in 0..count {
+                    let key = Value::decode_from(rest, depth + 1)?;
+                    let value = Value::decode_from(rest, depth + 1)?;
+                    pairs.push((key, value));
+                }
+                Value::Hash(pairs)
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(encrypt: bool) -> SessionConfig {
+        let secret = [7u8; 32];
+        SessionConfig {
+            name: "uzumibi.session".to_string(),
+            sign_key: hmac_sha256(&secret, &[b"uzumibi.session.sign"]),
+            encrypt_key: encrypt.then(|| hmac_sha256(&secret, &[b"uzumibi.session.encrypt"])),
+            path: "/".to_string(),
+            domain: None,
+            max_age: Some(3600),
+            secure: true,
+            http_only: true,
+            same_site: Some("Lax".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2
+        let tag = hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        let hex: String = tag.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        for encrypt in [false, true] {
+            let config = config(encrypt);
+            let sealed = config.seal(b"user=alice");
+            assert_eq!(config.open(&sealed).as_deref(), Some(&b"user=alice"[..]));
+            assert_eq!(
+                sealed.contains(&URL_SAFE_NO_PAD.encode(b"user=alice")),
+                !encrypt
+            );
+
+            // A changed payload, tag or cookie name is rejected
+            let (payload, tag) = sealed.rsplit_once('.').unwrap();
+            let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode(b"user=admin"), tag);
+            assert_eq!(config.open(&forged), None);
+            assert_eq!(config.open(&format!("{}.AAAA", payload)), None);
+            assert_eq!(config.open(payload), None);
+            let renamed = SessionConfig {
+                name: "other".to_string(),
+                ..config.clone()
+            };
+            assert_eq!(renamed.open(&sealed), None);
+        }
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let value = Value::Hash(vec![
+            (Value::Symbol("user_id".to_string()), Value::Integer(42)),
+            (
+                Value::String(b"flash".to_vec()),
+                Value::Array(vec![
+                    Value::Nil,
+                    Value::Bool(true),
+                    Value::Float(1.5),
+                    Value::String(vec![0, 255]),
+                ]),
+            ),
+        ]);
+        assert_eq!(Value::decode(&value.encode()), Some(value.clone()));
+
+        let mut trailing = value.encode();
+        trailing.push(b'0');
+        assert_eq!(Value::decode(&trailing), None);
+        assert_eq!(Value::decode(&value.encode()[..5]), None);
+        assert_eq!(Value::decode(b"[\xff\xff\xff\xff"), None);
+    }
+
+    #[test]
+    fn test_cookie_attributes() {
+        let config = config(false);
+        assert_eq!(
+            config.cookie("v"),
+            "uzumibi.session=v; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(
+            config.expired_cookie(),
+            "uzumibi.session=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0; Secure; HttpOnly; SameSite=Lax"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! This module implements Server-Sent Events on top of streamed responses.
+//! `init_uzumibi_sse()` should be called on prelude process, after
+//! `init_uzumibi_streaming()`.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Response
+//!       def sse(heartbeat: Integer | false | nil) { (EventStream) -> untyped } -> self
+//!     end
+//!     class EventStream < StreamWriter
+//!       def event(name: String?, data: untyped, id: String | Integer | nil, retry: Integer?) -> self
+//!       def comment(text: String) -> self
+//!       def heartbeat() -> self
+//! ```
+//!
+//! `res.sse` sets `Content-Type: text/event-stream`, turns off caching
+//! and proxy buffering (`Cache-Control: no-cache`, `X-Accel-Buffering: no`)
+//! and streams the block. Each call on the EventStream writes one complete
+//! event as one chunk. A Hash or Array `data:` goes through
+//! `JSON.generate`, and multi-line data becomes several `data:` lines.
+//!
+//! `heartbeat:` is the interval in seconds (15 by default, `false` to turn
+//! off) at which hosts that can write while the app is busy send a
+//! [`HEARTBEAT`] comment; see [`uzumibi_response_heartbeat_interval`].
+//! These heartbeats only keep a quiet connection open. The block still
+//! holds the VM until it returns, and notices a closed connection only
+//! when it writes.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_cmethod, mrb_funcall},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    response::{uzumibi_json_generate, uzumibi_response_set_header},
+    streaming::{uzumibi_response_set_stream, uzumibi_stream_write},
+};
+
+/// The comment written as a heartbeat.
+pub const HEARTBEAT: &[u8] = b": heartbeat\n\n";
+
+const DEFAULT_HEARTBEAT_SECONDS: i64 = 15;
+const HEARTBEAT_IVAR_KEY: &str = "@_heartbeat";
+
+pub(crate) fn init_uzumibi_sse(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let class_of = |name: &str| match uzumibi_module.get_const_by_name(name) {
+        Some(class) => match &class.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("{} must be a class", name),
+        },
+        None => panic!("{} class must be defined beforehand", name),
+    };
+    let response_class = class_of("Response");
+    let writer_class = class_of("StreamWriter");
+
+    mrb_define_cmethod(vm, response_class, "sse", Box::new(uzumibi_response_sse));
+
+    let event_stream_class =
+        vm.define_class("EventStream", Some(writer_class), Some(uzumibi_module));
+    mrb_define_cmethod(
+        vm,
+        event_stream_class.clone(),
+        "event",
+        Box::new(uzumibi_event_stream_event),
+    );
+    mrb_define_cmethod(
+        vm,
+        event_stream_class.clone(),
+        "comment",
+        Box::new(uzumibi_event_stream_comment),
+    );
+    mrb_define_cmethod(
+        vm,
+        event_stream_class,
+        "heartbeat",
+        Box::new(uzumibi_event_stream_heartbeat),
+    );
+}
+
+/// res.sse(heartbeat: 15) { |stream| ... } -> self
+fn uzumibi_response_sse(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let heartbeat = vm
+        .get_kwargs()
+        .and_then(|kwargs| kwargs.get("heartbeat").cloned());
+    let block = match args.last() {
+        Some(block) if matches!(block.value, RValue::Proc(_)) => block.clone(),
+        _ => {
+            return Err(Error::ArgumentError("Expected a block for sse".to_string()));
+        }
+    };
+    let heartbeat = match heartbeat {
+        None => DEFAULT_HEARTBEAT_SECONDS,
+        Some(seconds) => match &seconds.value {
+            RValue::Integer(n) if *n >= 0 => *n,
+            _ if seconds.is_falsy() => 0,
+            _ => {
+                return Err(Error::ArgumentError(
+                    "heartbeat must be a number of seconds or false".to_string(),
+                ));
+            }
+        },
+    };
+
+    let response = vm.getself()?;
+    for (name, value) in [
+        ("Content-Type", "text/event-stream; charset=utf-8"),
+        ("Cache-Control", "no-cache"),
+        ("X-Accel-Buffering", "no"),
+    ] {
+        uzumibi_response_set_header(vm, &response, name, value)?;
+    }
+    response.set_ivar(
+        HEARTBEAT_IVAR_KEY,
+        RObject::integer(heartbeat).to_refcount_assigned(),
+    );
+    let event_stream_class = vm
+        .get_const_by_name("Uzumibi")
+        .and_then(|uzumibi| match &uzumibi.value {
+            RValue::Module(m) => m.get_const_by_name("EventStream"),
+            _ => None,
+        })
+        .ok_or_else(|| Error::RuntimeError("EventStream class is not defined".to_string()))?;
+    uzumibi_response_set_stream(vm, &response, block, Some(event_stream_class))?;
+    Ok(response)
+}
+
+/// The heartbeat interval of an SSE response in seconds, or None when the
+/// response is not an event stream or heartbeats are off.
+pub fn uzumibi_response_heartbeat_interval(response: &Rc<RObject>) -> Option<u64> {
+    match response.get_ivar(HEARTBEAT_IVAR_KEY).value {
+        RValue::Integer(seconds) if seconds > 0 => Some(seconds as u64),
+        _ => None,
+    }
+}
+
+/// stream.event(name = nil, data:, id: nil, retry: nil) -> self
+fn uzumibi_event_stream_event(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let (data, id, retry) = match vm.get_kwargs() {
+        Some(kwargs) => (
+            kwargs.get("data").cloned(),
+            kwargs.get("id").cloned(),
+            kwargs.get("retry").cloned(),
+        ),
+        None => (None, None, None),
+    };
+    let [data, id, retry] = [data, id, retry].map(|value| value.filter(|v| v.is_truthy()));
+
+    let name = match args.first() {
+        Some(name) if name.is_truthy() => Some(uzumibi_to_s(vm, name.clone())?),
+        _ => None,
+    };
+    let data = match data {
+        Some(data) => match &data.value {
+            RValue::String(_, _) => data.as_ref().try_into()?,
+            RValue::Hash(_) | RValue::Array(_) => uzumibi_json_generate(vm, data.clone())?,
+            _ => uzumibi_to_s(vm, data.clone())?,
+        },
+        None => String::new(),
+    };
+    let id = match id {
+        Some(id) => Some(uzumibi_to_s(vm, id)?),
+        None => None,
+    };
+    let retry = match retry {
+        Some(retry) => match &retry.value {
+            RValue::Integer(ms) if *ms >= 0 => Some(*ms as u64),
+            _ => {
+                return Err(Error::ArgumentError(
+                    "retry must be a number of milliseconds".to_string(),
+                ));
+            }
+        },
+        None => None,
+    };
+
+    let event = format_event(name.as_deref(), &data, id.as_deref(), retry);
+    uzumibi_stream_write(event.as_bytes())?;
+    vm.getself()
+}
+
+/// stream.comment(text) -> self
+fn uzumibi_event_stream_comment(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let text = match args.first() {
+        Some(text) => uzumibi_to_s(vm, text.clone())?,
+        None => {
+            return Err(Error::ArgumentError(
+                "Expected 1 argument: text".to_string(),
+            ));
+        }
+    };
+    uzumibi_stream_write(format_comment(&text).as_bytes())?;
+    vm.getself()
+}
+
+/// stream.heartbeat -> self
+fn uzumibi_event_stream_heartbeat(
+    vm: &mut VM,
+    _args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    uzumibi_stream_write(HEARTBEAT)?;
+    vm.getself()
+}
+
+fn uzumibi_to_s(vm: &mut VM, value: Rc<RObject>) -> Result<String, Error> {
+    let value = mrb_funcall(vm, Some(value), "to_s", &[])?;
+    value.as_ref().try_into()
+}
+
+/// Splits text into lines at CRLF, CR or LF, as the event stream parser does.
+fn lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while let Some(i) = rest.find(['\r', '\n']) {
+        lines.push(&rest[..i]);
+        let skip = if rest[i..].starts_with("\r\n") { 2 } else { 1 };
+        rest = &rest[i + skip..];
+    }
+    lines.push(rest);
+    lines
+}
+
+/// Formats one event. Line breaks in the name and id would end the field
+/// early, so they are dropped.
+fn format_event(name: Option<&str>, data: &str, id: Option<&str>, retry: Option<u64>) -> String {
+    let mut event = String::new();
+    if let Some(name) = name {
+        event.push_str(&format!("event: {}\n", lines(name).concat()));
+    }
+    if let Some(id) = id {
+        event.push_str(&format!("id: {}\n", lines(id).concat()));
+    }
+    if let Some(retry) = retry {
+        event.push_str(&format!("retry: {}\n", retry));
+    }
+    for line in lines(data) {
+        event.push_str(&format!("data: {}\n", line));
+    }
+    event.push('\n');
+    event
+}
+
+fn format_comment(text: &str) -> String {
+    let mut comment = String::new();
+    for line in lines(text) {
+        comment.push_str(&format!(": {}\n", line));
+    }
+    comment.push('\n');
+    comment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_event() {
+        assert_eq!(
+            format_event(Some("update"), "{\"n\":1}", Some("1"), None),
+            "event: update\nid: 1\ndata: {\"n\":1}\n\n"
+        );
+        assert_eq!(
+            format_event(None, "", None, Some(3000)),
+            "retry: 3000\ndata: \n\n"
+        );
+        assert_eq!(
+            format_event(None, "a\r\nb\rc\nd", None, None),
+            "data: a\ndata: b\ndata: c\ndata: d\n\n"
+        );
+        assert_eq!(
+            format_event(Some("up\ndate"), "x", Some("1\r\n2"), None),
+            "event: update\nid: 12\ndata: x\n\n"
+        );
+    }
+
+    #[test]
+    fn test_format_comment() {
+        assert_eq!(format_comment("keep"), ": keep\n\n");
+        assert_eq!(format_comment("a\nb"), ": a\n: b\n\n");
+    }
+}
@@ -0,0 +1,294 @@
+//! This module implements streamed response bodies.
+//! `init_uzumibi_streaming()` should be called on prelude process.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Response
+//!       def stream() { (StreamWriter) -> untyped } -> self
+//!       def streaming?() -> bool
+//!     end
+//!     class StreamWriter
+//!       def <<(chunk: String) -> self
+//!       def write(chunk: String) -> Integer
+//! ```
+//!
+//! A response streams its body when `res.stream { |out| ... }` was called
+//! or when `res.body` is an Array or another Enumerable instead of a
+//! String. Hosts that can write incrementally call
+//! [`uzumibi_response_stream`] once the status and headers are sent; every
+//! `out << chunk` then reaches the client as it is written. An Enumerable
+//! body is converted with `to_a` and written one element at a time.
+//! Other hosts, Cloudflare Workers included, buffer the body: the block
+//! runs to completion and the chunks are sent as one body, joined by
+//! `to_shared_memory` or by the host.
+//!
+use std::{cell::RefCell, rc::Rc};
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_cmethod, mrb_funcall},
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::response::{
+    RESPONSE_BODY_IVAR_KEY, RESPONSE_STREAM_IVAR_KEY, RESPONSE_STREAM_WRITER_IVAR_KEY,
+    uzumibi_response_fill, uzumibi_response_is_filled,
+};
+
+/// Receives the chunks of a streamed body.
+pub type ChunkSink = Box<dyn FnMut(&[u8]) -> Result<(), Error>>;
+
+thread_local! {
+    // The sink of the body being streamed. `StreamWriter#<<` is defined
+    // as a plain method, so it reaches the host through here.
+    static CHUNK_SINK: RefCell<Option<ChunkSink>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn init_uzumibi_streaming(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let response_class = match uzumibi_module.get_const_by_name("Response") {
+        Some(response) => match &response.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("Response must be a class"),
+        },
+        None => panic!("Response class must be defined beforehand"),
+    };
+
+    mrb_define_cmethod(
+        vm,
+        response_class.clone(),
+        "stream",
+        Box::new(uzumibi_response_stream_block),
+    );
+    mrb_define_cmethod(
+        vm,
+        response_class,
+        "streaming?",
+        Box::new(uzumibi_response_streaming_p),
+    );
+
+    let writer_class = vm.define_class("StreamWriter", None, Some(uzumibi_module));
+    mrb_define_cmethod(
+        vm,
+        writer_class.clone(),
+        "<<",
+        Box::new(uzumibi_stream_writer_push),
+    );
+    mrb_define_cmethod(
+        vm,
+        writer_class,
+        "write",
+        Box::new(uzumibi_stream_writer_write),
+    );
+}
+
+/// res.stream { |out| ... } -> self
+fn uzumibi_response_stream_block(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let block = match args.last() {
+        Some(block) if matches!(block.value, RValue::Proc(_)) => block.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a block for stream".to_string(),
+            ));
+        }
+    };
+    let response = vm.getself()?;
+    uzumibi_response_set_stream(vm, &response, block, None)?;
+    Ok(response)
+}
+
+/// Makes a response stream its body with `block`, which is called with an
+/// instance of `writer_class` (`Uzumibi::StreamWriter` when None).
+pub(crate) fn uzumibi_response_set_stream(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    block: Rc<RObject>,
+    writer_class: Option<Rc<RObject>>,
+) -> Result<(), Error> {
+    if !uzumibi_response_is_filled(response) {
+        uzumibi_response_fill(
+            vm,
+            response,
+            200,
+            RObject::string("".to_string()).to_refcount_assigned(),
+        )?;
+    }
+    response.set_ivar(RESPONSE_STREAM_IVAR_KEY, block);
+    response.set_ivar(
+        RESPONSE_STREAM_WRITER_IVAR_KEY,
+        writer_class.unwrap_or_else(|| RObject::nil().to_refcount_assigned()),
+    );
+    Ok(())
+}
+
+/// res.streaming? -> bool
+fn uzumibi_response_streaming_p(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let response = vm.getself()?;
+    Ok(RObject::boolean(uzumibi_response_is_streaming(&response)).to_refcount_assigned())
+}
+
+fn uzumibi_stream_chunk(vm: &mut VM, args: &[Rc<RObject>]) -> Result<usize, Error> {
+    let chunk = match args.first() {
+        Some(chunk) => chunk.clone(),
+        None => {
+            return Err(Error::ArgumentError(
+                "Expected 1 argument: chunk".to_string(),
+            ));
+        }
+    };
+    let bytes = uzumibi_chunk_bytes(vm, chunk)?;
+    uzumibi_stream_write(&bytes)?;
+    Ok(bytes.len())
+}
+
+/// Hands one chunk to the sink of the body being streamed.
+pub(crate) fn uzumibi_stream_write(bytes: &[u8]) -> Result<(), Error> {
+    // An empty chunk would end a chunked body on the wire
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    CHUNK_SINK.with(|sink| match sink.borrow_mut().as_mut() {
+        Some(sink) => sink(bytes),
+        None => Err(Error::RuntimeError(
+            "Stream is closed; write chunks inside res.stream".to_string(),
+        )),
+    })
+}
+
+/// out << chunk -> out
+fn uzumibi_stream_writer_push(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    uzumibi_stream_chunk(vm, args)?;
+    vm.getself()
+}
+
+/// out.write(chunk) -> Integer
+fn uzumibi_stream_writer_write(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let written = uzumibi_stream_chunk(vm, args)?;
+    Ok(RObject::integer(written as i64).to_refcount_assigned())
+}
+
+/// The bytes of a chunk; anything other than a String goes through `to_s`.
+fn uzumibi_chunk_bytes(vm: &mut VM, chunk: Rc<RObject>) -> Result<Vec<u8>, Error> {
+    let chunk = match &chunk.value {
+        RValue::String(_, _) => chunk,
+        _ => mrb_funcall(vm, Some(chunk), "to_s", &[])?,
+    };
+    match &chunk.value {
+        RValue::String(s, _) => Ok(s.borrow().to_vec()),
+        _ => Err(Error::RuntimeError("chunk must be a String".to_string())),
+    }
+}
+
+/// Whether the body of a response is streamed rather than one String.
+pub fn uzumibi_response_is_streaming(response: &Rc<RObject>) -> bool {
+    if response.get_ivar(RESPONSE_STREAM_IVAR_KEY).is_truthy() {
+        return true;
+    }
+    !matches!(
+        response.get_ivar(RESPONSE_BODY_IVAR_KEY).value,
+        RValue::String(_, _) | RValue::Nil
+    )
+}
+
+/// Writes the streamed body of a response to `sink`, chunk by chunk.
+/// Afterwards the response holds an empty String body, so it can still
+/// be packed with `to_shared_memory` for the status and headers.
+pub fn uzumibi_response_stream(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+    sink: ChunkSink,
+) -> Result<(), Error> {
+    let block = response.get_ivar(RESPONSE_STREAM_IVAR_KEY);
+    let writer_class = response.get_ivar(RESPONSE_STREAM_WRITER_IVAR_KEY);
+    let body = response.get_ivar(RESPONSE_BODY_IVAR_KEY);
+    response.set_ivar(
+        RESPONSE_STREAM_IVAR_KEY,
+        RObject::nil().to_refcount_assigned(),
+    );
+    response.set_ivar(
+        RESPONSE_BODY_IVAR_KEY,
+        RObject::string("".to_string()).to_refcount_assigned(),
+    );
+
+    // Keep the sink of an enclosing stream, if any, for when this one ends
+    let outer = CHUNK_SINK.with(|current| current.borrow_mut().replace(sink));
+    let result = uzumibi_write_chunks(vm, block, writer_class, body);
+    CHUNK_SINK.with(|current| *current.borrow_mut() = outer);
+    result
+}
+
+fn uzumibi_write_chunks(
+    vm: &mut VM,
+    block: Rc<RObject>,
+    writer_class: Rc<RObject>,
+    body: Rc<RObject>,
+) -> Result<(), Error> {
+    if block.is_truthy() {
+        let writer_class = if writer_class.is_truthy() {
+            writer_class
+        } else {
+            uzumibi_stream_writer_class(vm)?
+        };
+        let writer = mrb_funcall(vm, Some(writer_class), "new", &[])?;
+        mrb_funcall(vm, Some(block), "call", &[writer])?;
+        return Ok(());
+    }
+    let chunks = match &body.value {
+        RValue::Array(arr) => arr.borrow().clone(),
+        RValue::String(_, _) => vec![body.clone()],
+        RValue::Nil => Vec::new(),
+        _ => match &mrb_funcall(vm, Some(body.clone()), "to_a", &[])?.value {
+            RValue::Array(arr) => arr.borrow().clone(),
+            _ => {
+                return Err(Error::RuntimeError(
+                    "body must be a String or an Enumerable".to_string(),
+                ));
+            }
+        },
+    };
+    for chunk in chunks {
+        uzumibi_stream_chunk(vm, &[chunk])?;
+    }
+    Ok(())
+}
+
+fn uzumibi_stream_writer_class(vm: &mut VM) -> Result<Rc<RObject>, Error> {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .ok_or_else(|| Error::RuntimeError("Uzumibi module is not defined".to_string()))?;
+    match &uzumibi.value {
+        RValue::Module(m) => m.get_const_by_name("StreamWriter"),
+        _ => None,
+    }
+    .ok_or_else(|| Error::RuntimeError("StreamWriter class is not defined".to_string()))
+}
+
+/// Runs the stream of a response and returns the chunks joined, for hosts
+/// that send the body in one piece.
+pub fn uzumibi_response_collect_body(
+    vm: &mut VM,
+    response: &Rc<RObject>,
+) -> Result<Vec<u8>, Error> {
+    let collected = Rc::new(RefCell::new(Vec::new()));
+    let buffer = collected.clone();
+    uzumibi_response_stream(
+        vm,
+        response,
+        Box::new(move |chunk| {
+            buffer.borrow_mut().extend_from_slice(chunk);
+            Ok(())
+        }),
+    )?;
+    Ok(collected.take())
+}
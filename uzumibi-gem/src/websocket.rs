@@ -0,0 +1,491 @@
+//! This module implements WebSocket routes.
+//! `init_uzumibi_websocket()` should be called on prelude process.
+//! Signatures are as follows:
+//!
+//! ```rbs
+//! @rbs!
+//!   module Uzumibi
+//!     class Router
+//!       def self.websocket(path: String, **options) { (WebSocket, Request) -> untyped } -> String
+//!     end
+//!     class WebSocket
+//!       def request: Request
+//!       def state: Hash[untyped, untyped]
+//!       def on_open() { (WebSocket) -> untyped } -> nil
+//!       def on_message() { (String, WebSocket) -> untyped } -> nil
+//!       def on_close() { (Integer, String, WebSocket) -> untyped } -> nil
+//!       def send(data: String, binary: bool) -> nil
+//!       def close(code: Integer, reason: String) -> nil
+//! ```
+//!
+//! `websocket` defines a GET route. An upgrade request runs the block,
+//! which registers callbacks on the WebSocket, and gets a 101 response;
+//! any other request gets 426. The host then does the handshake and feeds
+//! the connection's events into the VM one at a time with
+//! [`uzumibi_websocket_open`] and [`uzumibi_websocket_receive`]. Both
+//! return the messages queued by `ws.send` and `ws.close` meanwhile, for
+//! the host to write to the connection.
+//!
+//! The WebSocket object lives as long as the connection, and `ws.state`
+//! is a Hash that keeps per-connection state between events. Each
+//! callback gets the WebSocket as its last argument. Locals captured by
+//! the callbacks are not reliable: when one route block creates several
+//! blocks, mruby/edge keeps the captured values only for the last one.
+//!
+use std::rc::Rc;
+
+use mrubyedge::{
+    Error,
+    yamrb::{
+        helpers::{mrb_define_class_cmethod, mrb_define_cmethod, mrb_funcall},
+        prelude::hash::mrb_hash_new,
+        value::{RObject, RValue},
+        vm::VM,
+    },
+};
+
+use crate::{
+    init::{uzumibi_route_options, uzumibi_router_add_route},
+    request::uzumibi_request_header,
+    response::{uzumibi_response_fill, uzumibi_response_set_header},
+};
+
+const WEBSOCKET_ROUTE_KEY: &str = "@_websocket_route";
+const RESPONSE_WEBSOCKET_KEY: &str = "@_websocket";
+const WEBSOCKET_REQUEST_KEY: &str = "@request";
+const ON_OPEN_KEY: &str = "@_on_open";
+const ON_MESSAGE_KEY: &str = "@_on_message";
+const ON_CLOSE_KEY: &str = "@_on_close";
+const OUTBOX_KEY: &str = "@_outbox";
+const STATE_KEY: &str = "@_state";
+
+const MESSAGE_TEXT: u8 = 1;
+const MESSAGE_BINARY: u8 = 2;
+const MESSAGE_CLOSE: u8 = 3;
+
+/// A message on a WebSocket connection, in either direction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Close(u16, String),
+}
+
+impl WebSocketMessage {
+    /// Packs messages for hosts that pass them through linear memory:
+    ///   u16 LE count
+    ///   (u8 kind, u32 LE size, bytes) * count
+    /// where kind is 1 for text, 2 for binary and 3 for close. The bytes
+    /// of a close message are the u16 LE code followed by the reason.
+    pub fn pack_all(messages: &[WebSocketMessage]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(messages.len() as u16).to_le_bytes());
+        for message in messages {
+            let (kind, bytes) = match message {
+                WebSocketMessage::Text(text) => (MESSAGE_TEXT, text.as_bytes().to_vec()),
+                WebSocketMessage::Binary(data) => (MESSAGE_BINARY, data.clone()),
+                WebSocketMessage::Close(code, reason) => {
+                    let mut bytes = code.to_le_bytes().to_vec();
+                    bytes.extend_from_slice(reason.as_bytes());
+                    (MESSAGE_CLOSE, bytes)
+                }
+            };
+            buf.push(kind);
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&bytes);
+        }
+        buf
+    }
+
+    /// Reads one message of the given kind, as packed by `pack_all`.
+    pub fn unpack(kind: u8, bytes: &[u8]) -> Result<Self, Error> {
+        match kind {
+            MESSAGE_TEXT => Ok(WebSocketMessage::Text(
+                String::from_utf8_lossy(bytes).into_owned(),
+            )),
+            MESSAGE_BINARY => Ok(WebSocketMessage::Binary(bytes.to_vec())),
+            MESSAGE_CLOSE if bytes.len() >= 2 => Ok(WebSocketMessage::Close(
+                u16::from_le_bytes([bytes[0], bytes[1]]),
+                String::from_utf8_lossy(&bytes[2..]).into_owned(),
+            )),
+            MESSAGE_CLOSE => Ok(WebSocketMessage::Close(1005, String::new())),
+            _ => Err(Error::ArgumentError(format!(
+                "Unknown WebSocket message kind: {}",
+                kind
+            ))),
+        }
+    }
+}
+
+pub(crate) fn init_uzumibi_websocket(vm: &mut VM) {
+    let uzumibi = vm
+        .get_const_by_name("Uzumibi")
+        .expect("Uzumibi module must be defined beforehand");
+    let uzumibi_module = match &uzumibi.as_ref().value {
+        RValue::Module(m) => m.clone(),
+        _ => panic!("Uzumibi must be a module"),
+    };
+    let router_class = match uzumibi_module.get_const_by_name("Router") {
+        Some(router) => match &router.value {
+            RValue::Class(c) => c.clone(),
+            _ => panic!("Router must be a class"),
+        },
+        None => panic!("Router class must be defined beforehand"),
+    };
+    mrb_define_class_cmethod(
+        vm,
+        router_class,
+        "websocket",
+        Box::new(uzumibi_router_websocket),
+    );
+
+    let websocket_class = vm.define_class("WebSocket", None, Some(uzumibi_module));
+    mrb_define_cmethod(
+        vm,
+        websocket_class.clone(),
+        "request",
+        Box::new(uzumibi_websocket_request),
+    );
+    mrb_define_cmethod(
+        vm,
+        websocket_class.clone(),
+        "state",
+        Box::new(uzumibi_websocket_state),
+    );
+    for (name, key) in [
+        ("on_open", ON_OPEN_KEY),
+        ("on_message", ON_MESSAGE_KEY),
+        ("on_close", ON_CLOSE_KEY),
+    ] {
+        mrb_define_cmethod(
+            vm,
+            websocket_class.clone(),
+            name,
+            Box::new(move |vm: &mut VM, args: &[Rc<RObject>]| {
+                uzumibi_websocket_set_callback(vm, key, args)
+            }),
+        );
+    }
+    mrb_define_cmethod(
+        vm,
+        websocket_class.clone(),
+        "send",
+        Box::new(uzumibi_websocket_send),
+    );
+    mrb_define_cmethod(
+        vm,
+        websocket_class,
+        "close",
+        Box::new(uzumibi_websocket_close),
+    );
+}
+
+/// websocket(path, **options) { |ws, req| ... } -> String
+/// The options are those of `get`, such as `as:`.
+fn uzumibi_router_websocket(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let options = uzumibi_route_options(vm);
+    let block = match args {
+        [_, block] if matches!(block.value, RValue::Proc(_)) => block.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected 2 arguments: path, block".to_string(),
+            ));
+        }
+    };
+    block.set_ivar(
+        WEBSOCKET_ROUTE_KEY,
+        RObject::boolean(true).to_refcount_assigned(),
+    );
+    uzumibi_router_add_route(vm, "GET", args, &options)
+}
+
+/// Whether a route handler was defined with `websocket`.
+pub(crate) fn uzumibi_is_websocket_route(route: &Rc<RObject>) -> bool {
+    route.get_ivar(WEBSOCKET_ROUTE_KEY).is_truthy()
+}
+
+/// Runs a WebSocket route. An upgrade request gets a 101 response holding
+/// the WebSocket; anything else gets 426.
+pub(crate) fn uzumibi_run_websocket_route(
+    vm: &mut VM,
+    route: Rc<RObject>,
+    request: &Rc<RObject>,
+    response: &Rc<RObject>,
+) -> Result<(), Error> {
+    let upgrade = uzumibi_request_header(request, "upgrade")?;
+    if !upgrade.is_some_and(|value| value.eq_ignore_ascii_case("websocket")) {
+        let body = "Upgrade Required";
+        uzumibi_response_fill(
+            vm,
+            response,
+            426,
+            RObject::string(body.to_string()).to_refcount_assigned(),
+        )?;
+        for (name, value) in [
+            ("Upgrade", "websocket"),
+            ("Content-Type", "text/plain; charset=utf-8"),
+        ] {
+            uzumibi_response_set_header(vm, response, name, value)?;
+        }
+        return Ok(());
+    }
+
+    let websocket = uzumibi_websocket_new(vm)?;
+    websocket.set_ivar(WEBSOCKET_REQUEST_KEY, request.clone());
+    mrb_funcall(
+        vm,
+        Some(route),
+        "call",
+        &[websocket.clone(), request.clone()],
+    )?;
+
+    uzumibi_response_fill(
+        vm,
+        response,
+        101,
+        RObject::string("".to_string()).to_refcount_assigned(),
+    )?;
+    for (name, value) in [("Upgrade", "websocket"), ("Connection", "Upgrade")] {
+        uzumibi_response_set_header(vm, response, name, value)?;
+    }
+    response.set_ivar(RESPONSE_WEBSOCKET_KEY, websocket);
+    Ok(())
+}
+
+fn uzumibi_websocket_new(vm: &mut VM) -> Result<Rc<RObject>, Error> {
+    let websocket_class = vm
+        .get_const_by_name("Uzumibi")
+        .and_then(|uzumibi| match &uzumibi.value {
+            RValue::Module(m) => m.get_const_by_name("WebSocket"),
+            _ => None,
+        })
+        .ok_or_else(|| Error::RuntimeError("WebSocket class is not defined".to_string()))?;
+    mrb_funcall(vm, Some(websocket_class), "new", &[])
+}
+
+/// ws.request -> Request
+fn uzumibi_websocket_request(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    Ok(vm.getself()?.get_ivar(WEBSOCKET_REQUEST_KEY))
+}
+
+/// ws.state -> Hash
+fn uzumibi_websocket_state(vm: &mut VM, _args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let websocket = vm.getself()?;
+    let state = websocket.get_ivar(STATE_KEY);
+    if state.is_truthy() {
+        return Ok(state);
+    }
+    let state = mrb_hash_new(vm, &[])?;
+    websocket.set_ivar(STATE_KEY, state.clone());
+    Ok(state)
+}
+
+fn uzumibi_websocket_set_callback(
+    vm: &mut VM,
+    key: &str,
+    args: &[Rc<RObject>],
+) -> Result<Rc<RObject>, Error> {
+    let block = match args.last() {
+        Some(block) if matches!(block.value, RValue::Proc(_)) => block.clone(),
+        _ => {
+            return Err(Error::ArgumentError(
+                "Expected a block for the callback".to_string(),
+            ));
+        }
+    };
+    vm.getself()?.set_ivar(key, block);
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+fn uzumibi_websocket_push(vm: &mut VM, message: Rc<RObject>) -> Result<(), Error> {
+    let websocket = vm.getself()?;
+    let mut outbox = websocket.get_ivar(OUTBOX_KEY);
+    if outbox.is_falsy() {
+        outbox = RObject::array(vec![]).to_refcount_assigned();
+        websocket.set_ivar(OUTBOX_KEY, outbox.clone());
+    }
+    mrb_funcall(vm, Some(outbox), "push", &[message])?;
+    Ok(())
+}
+
+/// ws.send(data, binary: false) -> nil
+fn uzumibi_websocket_send(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let binary = vm
+        .get_kwargs()
+        .and_then(|kwargs| kwargs.get("binary").cloned())
+        .is_some_and(|binary| binary.is_truthy());
+    let data = match args.first() {
+        Some(data) if matches!(data.value, RValue::String(_, _)) => data.clone(),
+        Some(data) => mrb_funcall(vm, Some(data.clone()), "to_s", &[])?,
+        None => {
+            return Err(Error::ArgumentError(
+                "Expected 1 argument: data".to_string(),
+            ));
+        }
+    };
+    let kind = if binary { MESSAGE_BINARY } else { MESSAGE_TEXT };
+    let message = RObject::array(vec![
+        RObject::integer(kind as i64).to_refcount_assigned(),
+        data,
+    ])
+    .to_refcount_assigned();
+    uzumibi_websocket_push(vm, message)?;
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// ws.close(code = 1000, reason = "") -> nil
+fn uzumibi_websocket_close(vm: &mut VM, args: &[Rc<RObject>]) -> Result<Rc<RObject>, Error> {
+    let code = match args.first() {
+        Some(code) => code.clone(),
+        None => RObject::integer(1000).to_refcount_assigned(),
+    };
+    let reason = match args.get(1) {
+        Some(reason) => mrb_funcall(vm, Some(reason.clone()), "to_s", &[])?,
+        None => RObject::string("".to_string()).to_refcount_assigned(),
+    };
+    let message = RObject::array(vec![
+        RObject::integer(MESSAGE_CLOSE as i64).to_refcount_assigned(),
+        reason,
+        code,
+    ])
+    .to_refcount_assigned();
+    uzumibi_websocket_push(vm, message)?;
+    Ok(RObject::nil().to_refcount_assigned())
+}
+
+/// The WebSocket of a response to an upgrade request, if the route
+/// accepted it.
+pub fn uzumibi_response_websocket(response: &Rc<RObject>) -> Option<Rc<RObject>> {
+    let websocket = response.get_ivar(RESPONSE_WEBSOCKET_KEY);
+    let switching = matches!(
+        response.get_ivar("@status_code").value,
+        RValue::Integer(101)
+    );
+    (switching && websocket.is_truthy()).then_some(websocket)
+}
+
+/// Takes the messages queued by `ws.send` and `ws.close`.
+fn uzumibi_websocket_take_outbox(websocket: &Rc<RObject>) -> Result<Vec<WebSocketMessage>, Error> {
+    let outbox = websocket.get_ivar(OUTBOX_KEY);
+    websocket.set_ivar(OUTBOX_KEY, RObject::nil().to_refcount_assigned());
+    let RValue::Array(items) = &outbox.value else {
+        return Ok(Vec::new());
+    };
+    let mut messages = Vec::new();
+    for item in items.borrow().iter() {
+        let RValue::Array(fields) = &item.value else {
+            continue;
+        };
+        let fields = fields.borrow();
+        let bytes = match &fields[1].value {
+            RValue::String(s, _) => s.borrow().to_vec(),
+            _ => Vec::new(),
+        };
+        let message = match fields[0].value {
+            RValue::Integer(kind) if kind == MESSAGE_CLOSE as i64 => {
+                let code: i64 = match fields[2].value {
+                    RValue::Integer(code) => code,
+                    _ => 1000,
+                };
+                WebSocketMessage::Close(code as u16, String::from_utf8_lossy(&bytes).into_owned())
+            }
+            RValue::Integer(kind) if kind == MESSAGE_BINARY as i64 => {
+                WebSocketMessage::Binary(bytes)
+            }
+            // Text that is not valid UTF-8 can only go out as binary
+            _ => match String::from_utf8(bytes) {
+                Ok(text) => WebSocketMessage::Text(text),
+                Err(e) => WebSocketMessage::Binary(e.into_bytes()),
+            },
+        };
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+fn uzumibi_websocket_callback(
+    vm: &mut VM,
+    websocket: &Rc<RObject>,
+    key: &str,
+    args: &[Rc<RObject>],
+) -> Result<Vec<WebSocketMessage>, Error> {
+    let callback = websocket.get_ivar(key);
+    if callback.is_truthy() {
+        let mut args = args.to_vec();
+        args.push(websocket.clone());
+        mrb_funcall(vm, Some(callback), "call", &args)?;
+    }
+    uzumibi_websocket_take_outbox(websocket)
+}
+
+/// Runs `on_open` once the handshake is done. The returned messages
+/// include those sent from the route block.
+pub fn uzumibi_websocket_open(
+    vm: &mut VM,
+    websocket: &Rc<RObject>,
+) -> Result<Vec<WebSocketMessage>, Error> {
+    uzumibi_websocket_callback(vm, websocket, ON_OPEN_KEY, &[])
+}
+
+/// Runs `on_message` for a text or binary message, or `on_close` for a
+/// close. Binary messages reach Ruby as Strings of their bytes.
+pub fn uzumibi_websocket_receive(
+    vm: &mut VM,
+    websocket: &Rc<RObject>,
+    message: WebSocketMessage,
+) -> Result<Vec<WebSocketMessage>, Error> {
+    match message {
+        WebSocketMessage::Text(text) => {
+            let text = RObject::string(text).to_refcount_assigned();
+            uzumibi_websocket_callback(vm, websocket, ON_MESSAGE_KEY, &[text])
+        }
+        WebSocketMessage::Binary(data) => {
+            let data = RObject::string_from_vec(data).to_refcount_assigned();
+            uzumibi_websocket_callback(vm, websocket, ON_MESSAGE_KEY, &[data])
+        }
+        WebSocketMessage::Close(code, reason) => {
+            let args = [
+                RObject::integer(code as i64).to_refcount_assigned(),
+                RObject::string(reason).to_refcount_assigned(),
+            ];
+            uzumibi_websocket_callback(vm, websocket, ON_CLOSE_KEY, &args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_and_unpack_messages() {
+        let packed = WebSocketMessage::pack_all(&[
+            WebSocketMessage::Text("hi".to_string()),
+            WebSocketMessage::Binary(vec![0, 255]),
+            WebSocketMessage::Close(1000, "bye".to_string()),
+        ]);
+        assert_eq!(
+            packed,
+            [
+                &[3, 0][..],
+                &[1, 2, 0, 0, 0, b'h', b'i'],
+                &[2, 2, 0, 0, 0, 0, 255],
+                &[3, 5, 0, 0, 0, 0xe8, 0x03, b'b', b'y', b'e'],
+            ]
+            .concat()
+        );
+        assert_eq!(
+            WebSocketMessage::unpack(3, &[0xe8, 0x03, b'b', b'y', b'e']).unwrap(),
+            WebSocketMessage::Close(1000, "bye".to_string())
+        );
+        assert_eq!(
+            WebSocketMessage::unpack(3, &[]).unwrap(),
+            WebSocketMessage::Close(1005, String::new())
+        );
+        assert_eq!(
+            WebSocketMessage::unpack(2, &[0, 255]).unwrap(),
+            WebSocketMessage::Binary(vec![0, 255])
+        );
+        assert!(WebSocketMessage::unpack(9, &[]).is_err());
+    }
+}
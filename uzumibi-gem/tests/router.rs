@@ -0,0 +1,1477 @@
+use std::{cell::RefCell, rc::Rc};
+
+use mrubyedge::yamrb::{
+    helpers::mrb_funcall,
+    value::{RObject, RValue},
+    vm::VM,
+};
+use uzumibi_gem::{
+    isolation::VmSnapshot,
+    response::uzumibi_response_header_pairs,
+    sse::uzumibi_response_heartbeat_interval,
+    streaming::{uzumibi_response_is_streaming, uzumibi_response_stream},
+    websocket::{
+        WebSocketMessage, uzumibi_response_websocket, uzumibi_websocket_open,
+        uzumibi_websocket_receive,
+    },
+};
+
+extern crate mruby_compiler2_sys;
+extern crate mrubyedge;
+extern crate uzumibi_gem;
+
+const PRELUDE: &str = r#"
+def dispatch(app, method, path, headers = {})
+  req = Uzumibi::Request.new
+  req.method = method
+  req.path = path
+  req.headers = headers
+  app.set_request(req)
+  app.start_request
+end
+"#;
+
+fn run_script(code: &str) -> Result<String, mrubyedge::Error> {
+    let (_, ret) = open_vm(code)?;
+    ret.as_ref().try_into()
+}
+
+/// Compiles and runs a script, returning the VM and the script's value.
+fn open_vm(code: &str) -> Result<(VM, Rc<RObject>), mrubyedge::Error> {
+    let script = format!("{}\n{}", PRELUDE, code);
+    let mrb_bin = unsafe {
+        mruby_compiler2_sys::MRubyCompiler2Context::new()
+            .compile(&script)
+            .map_err(|e| {
+                mrubyedge::Error::RuntimeError(format!("Failed to compile script: {}", e))
+            })?
+    };
+    let mut rite = mrubyedge::rite::load(&mrb_bin)
+        .map_err(|e| mrubyedge::Error::RuntimeError(format!("Failed to load rite: {}", e)))?;
+    let mut vm = VM::open(&mut rite);
+    uzumibi_gem::init::init_uzumibi(&mut vm);
+    let ret = vm
+        .run()
+        .map_err(|e| mrubyedge::Error::RuntimeError(format!("Failed to run script: {}", e)))?;
+    Ok((vm, ret))
+}
+
+#[test]
+fn test_patch_route() -> Result<(), mrubyedge::Error> {
+    let code = r#"
+    class App < Uzumibi::Router
+      get "/users/:id" do |req, res|
+        res.return(200, {}, "GET " + req.params[:id])
+      end
+
+      patch "/users/:id" do |req, res|
+        res.return(200, {}, "PATCH " + req.params[:id])
+      end
+    end
+    dispatch(App.new, "PATCH", "/users/1").body
+    "#;
+    assert_eq!(run_script(code)?, "PATCH 1");
+    Ok(())
+}
+
+#[test]
+fn test_unknown_method_does_not_fall_back_to_get() -> Result<(), mrubyedge::Error> {
+    let code = r#"
+    class App < Uzumibi::Router
+      get "/users/:id" do |req, res|
+        res.return(200, {}, "GET")
+      end
+    end
+    dispatch(App.new, "PURGE", "/users/1").status_code.to_s
+    "#;
+    assert_eq!(run_script(code)?, "405");
+    Ok(())
+}
+
+#[test]
+fn test_route_with_multiple_methods() -> Result<(), mrubyedge::Error> {
+    let code = r#"
+    class App < Uzumibi::Router
+      route %w[GET POST], "/form" do |req, res|
+        res.return(200, {}, req.method)
+      end
+
+      route :purge, "/cache" do |req, res|
+        res.return(200, {}, "purged")
+      end
+    end
+    app = App.new
+    [
+      dispatch(app, "GET", "/form").body,
+      dispatch(app, "POST", "/form").body,
+      dispatch(app, "PURGE", "/cache").body,
+    ].join(",")
+    "#;
+    assert_eq!(run_script(code)?, "GET,POST,purged");
+    Ok(())
+}
+
+#[test]
+fn test_any_route() -> Result<(), mrubyedge::Error> {
+    let code = r#"
+    class App < Uzumibi::Router
+      get "/ping" do |req, res|
+        res.return(200, {}, "get")
+      end
+
+      any "/ping" do |req, res|
+        res.return(200, {}, "any " + req.method)
+      end
+    end
+    app = App.new
+    [
+      dispatch(app, "GET", "/ping").body,
+      dispatch(app, "DELETE", "/ping").body,
+    ].join(",")
+    "#;
+    assert_eq!(run_script(code)?, "get,any DELETE");
+    Ok(())
+}
+
+#[test]
+fn test_method_not_allowed() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/items/:id" do |req, res|
+        res.return(200, {}, "item")
+      end
+
+      delete "/items/:id" do |req, res|
+        res.return(204, {}, "")
+      end
+    end
+    res = dispatch(App.new, "POST", "/items/3")
+    "#{res.status_code} #{res.headers["Allow"]}"
+    "##;
+    assert_eq!(run_script(code)?, "405 HEAD, GET, DELETE");
+    Ok(())
+}
+
+#[test]
+fn test_not_found_when_no_method_matches() -> Result<(), mrubyedge::Error> {
+    let code = r#"
+    class App < Uzumibi::Router
+      get "/items" do |req, res|
+        res.return(200, {}, "items")
+      end
+    end
+    dispatch(App.new, "POST", "/missing").status_code.to_s
+    "#;
+    assert_eq!(run_script(code)?, "404");
+    Ok(())
+}
+
+#[test]
+fn test_automatic_options() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/items/:id" do |req, res|
+        res.return(200, {}, "item")
+      end
+
+      patch "/items/:id" do |req, res|
+        res.return(200, {}, "patched")
+      end
+    end
+    res = dispatch(App.new, "OPTIONS", "/items/3")
+    "#{res.status_code} #{res.headers["Allow"]}"
+    "##;
+    assert_eq!(run_script(code)?, "204 HEAD, GET, PATCH, OPTIONS");
+    Ok(())
+}
+
+#[test]
+fn test_cors_preflight() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      cors origins: ["https://app.example"], max_age: 600
+
+      patch "/items/:id" do |req, res|
+        res.return(200, {}, "patched")
+      end
+    end
+    res = dispatch(App.new, "OPTIONS", "/items/3", {
+      "origin" => "https://app.example",
+      "access-control-request-method" => "PATCH",
+      "access-control-request-headers" => "content-type",
+    })
+    [
+      res.status_code,
+      res.headers["Access-Control-Allow-Origin"],
+      res.headers["Access-Control-Allow-Methods"],
+      res.headers["Access-Control-Allow-Headers"],
+      res.headers["Access-Control-Max-Age"],
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "204|https://app.example|PATCH, OPTIONS|content-type|600"
+    );
+
+    // A preflight is answered before a catch-all `any` route; a plain
+    // OPTIONS request still reaches it
+    let catch_all = r##"
+    class App < Uzumibi::Router
+      cors origins: ["https://app.example"]
+
+      patch "/items/:id" do |req, res|
+        res.return(200, {}, "patched")
+      end
+
+      any "/*" do |req, res|
+        res.return(200, {}, "any #{req.method}")
+      end
+    end
+    app = App.new
+    preflight = dispatch(app, "OPTIONS", "/items/3", {
+      "origin" => "https://app.example",
+      "access-control-request-method" => "PATCH",
+    })
+    plain = dispatch(app, "OPTIONS", "/items/3")
+    [
+      preflight.status_code,
+      preflight.headers["Access-Control-Allow-Methods"],
+      plain.body,
+    ].join("|")
+    "##;
+    assert_eq!(run_script(catch_all)?, "204|PATCH, OPTIONS|any OPTIONS");
+    Ok(())
+}
+
+#[test]
+fn test_cors_headers_on_response() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      cors origins: ["https://app.example"], credentials: true
+
+      get "/items" do |req, res|
+        res.return(200, { "Vary" => "Accept" }, "items")
+      end
+    end
+    app = App.new
+    allowed = dispatch(app, "GET", "/items", { "Origin" => "https://app.example" })
+    denied = dispatch(app, "GET", "/items", { "Origin" => "https://evil.example" })
+    [
+      allowed.headers["Access-Control-Allow-Origin"],
+      allowed.headers["Access-Control-Allow-Credentials"],
+      allowed.headers["Vary"],
+      denied.headers["Access-Control-Allow-Origin"].inspect,
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "https://app.example|true|Accept, Origin|nil"
+    );
+
+    // Credentials with any origin would let every site read responses
+    let wildcard = r##"
+    class App < Uzumibi::Router
+      cors origins: "*", credentials: true
+    end
+    "##;
+    assert!(run_script(wildcard).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_before_and_after_filters() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      before do |req, res|
+        res.headers = { "x-trace" => "before" }
+      end
+
+      after do |req, res|
+        res.headers["x-trace"] = res.headers["x-trace"] + ",after"
+      end
+
+      get "/items" do |req, res|
+        res.status_code = 200
+        res.headers["x-trace"] = res.headers["x-trace"] + ",route"
+        res.body = "items"
+      end
+    end
+    res = dispatch(App.new, "GET", "/items")
+    "#{res.status_code} #{res.headers["x-trace"]}"
+    "##;
+    assert_eq!(run_script(code)?, "200 before,route,after");
+    Ok(())
+}
+
+#[test]
+fn test_before_filter_short_circuit() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      before "/admin/*" do |req, res|
+        unless req.headers["authorization"]
+          res.return(401, {}, "unauthorized")
+        end
+      end
+
+      get "/admin/stats" do |req, res|
+        res.return(200, {}, "stats")
+      end
+
+      get "/public" do |req, res|
+        res.return(200, {}, "public")
+      end
+    end
+    app = App.new
+    [
+      dispatch(app, "GET", "/admin/stats").body,
+      dispatch(app, "GET", "/admin/stats", { "authorization" => "token" }).body,
+      dispatch(app, "GET", "/public").body,
+    ].join(",")
+    "##;
+    assert_eq!(run_script(code)?, "unauthorized,stats,public");
+    Ok(())
+}
+
+#[test]
+fn test_middleware_wraps_route() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class Trace
+      def initialize(options)
+        @name = options[:name]
+      end
+
+      def call(req, res, app)
+        app.call(req, res)
+        trace = res.headers["x-trace"]
+        res.headers["x-trace"] = trace ? "#{@name},#{trace}" : @name
+        res
+      end
+    end
+
+    class Gate
+      def call(req, res, app)
+        if req.path == "/blocked"
+          res.return(403, {}, "blocked")
+        else
+          app.call(req, res)
+        end
+      end
+    end
+
+    class App < Uzumibi::Router
+      use Trace, name: "outer"
+      use Trace, name: "inner"
+      use Gate
+
+      get "/items" do |req, res|
+        res.return(200, {}, "items")
+      end
+    end
+    app = App.new
+    ok = dispatch(app, "GET", "/items")
+    blocked = dispatch(app, "GET", "/blocked")
+    missing = dispatch(app, "GET", "/missing")
+    [
+      "#{ok.status_code} #{ok.body} #{ok.headers["x-trace"]}",
+      "#{blocked.status_code} #{blocked.body}",
+      "#{missing.status_code} #{missing.headers["x-trace"]}",
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "200 items outer,inner|403 blocked|404 outer,inner"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_builtin_middlewares() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      use Uzumibi::Middleware::Timing
+      use Uzumibi::Middleware::DefaultHeaders, {
+        "X-Frame-Options" => "DENY",
+        "Cache-Control" => "no-store",
+      }
+
+      get "/items" do |req, res|
+        res.return(200, { "Cache-Control" => "max-age=60" }, "items")
+      end
+    end
+    res = dispatch(App.new, "GET", "/items")
+    [
+      res.headers["X-Frame-Options"],
+      res.headers["Cache-Control"],
+      res.headers["Server-Timing"].include?("app;dur="),
+    ].join("|")
+    "##;
+    assert_eq!(run_script(code)?, "DENY|max-age=60|true");
+    Ok(())
+}
+
+#[test]
+fn test_request_logger() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    $logged = []
+    def debug_console(message)
+      raise "logger down" if $fail_logger
+      $logged << message
+    end
+
+    class App < Uzumibi::Router
+      use Uzumibi::Middleware::RequestLogger
+
+      get "/items" do |req, res|
+        res.return(200, {}, "items")
+      end
+    end
+    app = App.new
+    ok = dispatch(app, "GET", "/items")
+    $fail_logger = true
+    failed = dispatch(app, "GET", "/items")
+    [$logged.join(","), ok.status_code, failed.status_code].join("|")
+    "##;
+    // An error in debug_console is not mistaken for a missing method
+    assert_eq!(run_script(code)?, "GET /items -> 200|200|500");
+    Ok(())
+}
+
+#[test]
+fn test_error_handler() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      error ArgumentError do |e, req, res|
+        body = e.message.end_with?("bad id") ? "invalid: bad id" : e.message
+        res.return(422, { "content-type" => "text/plain" }, body)
+      end
+
+      get "/items/:id" do |req, res|
+        raise ArgumentError, "bad id"
+      end
+    end
+    res = dispatch(App.new, "GET", "/items/x")
+    "#{res.status_code} #{res.body}"
+    "##;
+    assert_eq!(run_script(code)?, "422 invalid: bad id");
+    Ok(())
+}
+
+#[test]
+fn test_error_handler_user_class() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class NotPermitted < StandardError
+    end
+
+    class App < Uzumibi::Router
+      error ArgumentError do |e, req, res|
+        res.return(422, {}, "invalid")
+      end
+
+      error NotPermitted do |e, req, res|
+        res.return(403, {}, e.is_a?(NotPermitted) ? "forbidden" : "wrong class")
+      end
+
+      get "/secret" do |req, res|
+        raise NotPermitted, "no access"
+      end
+    end
+    res = dispatch(App.new, "GET", "/secret")
+    "#{res.status_code} #{res.body}"
+    "##;
+    assert_eq!(run_script(code)?, "403 forbidden");
+    Ok(())
+}
+
+#[test]
+fn test_default_internal_error() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      rescue_from ArgumentError do |e, req, res|
+        res.return(422, {}, "invalid")
+      end
+
+      get "/boom" do |req, res|
+        raise "boom"
+      end
+    end
+    res = dispatch(App.new, "GET", "/boom", { "x-request-id" => "req-1" })
+    [
+      res.status_code,
+      res.headers["Content-Type"],
+      res.headers["X-Request-Id"],
+      res.body,
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        r#"500|application/json; charset=utf-8|req-1|{"error":"Internal Server Error","request_id":"req-1"}"#
+    );
+    Ok(())
+}
+
+#[test]
+fn test_custom_not_found() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      not_found do |req, res|
+        res.headers = { "content-type" => "application/json" }
+        res.body = "{\"error\":\"no route for #{req.path}\"}"
+      end
+
+      get "/items" do |req, res|
+        res.return(200, {}, "items")
+      end
+    end
+    app = App.new
+    get = dispatch(app, "GET", "/missing")
+    head = dispatch(app, "HEAD", "/missing")
+    [
+      "#{get.status_code} #{get.body}",
+      "#{head.status_code} #{head.headers["content-type"]} [#{head.body}]",
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        r#"404 {"error":"no route for /missing"}|404 application/json []"#
+    );
+    Ok(())
+}
+
+#[test]
+fn test_namespace_and_mount() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class Admin < Uzumibi::Router
+      get "/" do |req, res|
+        res.return(200, {}, "admin index")
+      end
+
+      get "/users/:id" do |req, res|
+        res.return(200, {}, "admin user " + req.params[:id] + " at " + req.path)
+      end
+    end
+
+    class App < Uzumibi::Router
+      namespace "/api" do
+        namespace "/v1" do
+          get "/items/:id" do |req, res|
+            res.return(200, {}, "v1 item " + req.params[:id])
+          end
+        end
+      end
+
+      mount Admin, at: "/admin"
+
+      get "/items/:id" do |req, res|
+        res.return(200, {}, "item " + req.params[:id])
+      end
+    end
+    app = App.new
+    [
+      dispatch(app, "GET", "/api/v1/items/1").body,
+      dispatch(app, "GET", "/items/2").body,
+      dispatch(app, "GET", "/admin").body,
+      dispatch(app, "GET", "/admin/users/3").body,
+      dispatch(app, "GET", "/administrator").status_code,
+    ].join(",")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "v1 item 1,item 2,admin index,admin user 3 at /users/3,404"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_named_routes() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class Files < Uzumibi::Router
+      get "/*", as: :file do |req, res|
+        res.return(200, {}, "file")
+      end
+    end
+
+    class App < Uzumibi::Router
+      namespace "/api" do
+        get "/users/:id", as: :user do |req, res|
+          res.return(200, {}, req.url_for(:user, id: req.params[:id], tab: "posts"))
+        end
+      end
+
+      mount Files, at: "/files"
+    end
+    res = dispatch(App.new, "GET", "/api/users/7", { "host" => "example.com" })
+    [
+      App.path_for(:user, id: 42),
+      App.path_for(:user, { id: "a b", page: 2 }),
+      App.path_for(:file, "*": "css/site.css"),
+      res.body,
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "/api/users/42|/api/users/a%20b?page=2|/files/css/site.css|https://example.com/api/users/7?tab=posts"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_route_return_values() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/text" do |req, res|
+        "hello"
+      end
+
+      get "/triplet" do |req, res|
+        [201, { "x-kind" => "triplet" }, ["a", "b"]]
+      end
+
+      get "/json" do |req, res|
+        { "ok" => true }
+      end
+
+      get "/mutated" do |req, res|
+        res.status_code = 202
+        res.headers = {}
+        res.body = "mutated"
+      end
+    end
+    app = App.new
+    text = dispatch(app, "GET", "/text")
+    triplet = dispatch(app, "GET", "/triplet")
+    json = dispatch(app, "GET", "/json")
+    mutated = dispatch(app, "GET", "/mutated")
+    [
+      "#{text.status_code} #{text.headers["Content-Type"]} #{text.body}",
+      "#{triplet.status_code} #{triplet.headers["x-kind"]} #{triplet.body}",
+      "#{json.status_code} #{json.headers["Content-Type"]} #{json.body}",
+      "#{mutated.status_code} #{mutated.body}",
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        r#"200 text/plain; charset=utf-8 hello|201 triplet ab|200 application/json; charset=utf-8 {"ok":true}|202 mutated"#
+    );
+    Ok(())
+}
+
+#[test]
+fn test_halt_and_redirect() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      before "/admin/*" do |req, res|
+        halt 401, "unauthorized", { "www-authenticate" => "Bearer" } unless req.headers["authorization"]
+      end
+
+      get "/admin/stats" do |req, res|
+        "stats"
+      end
+
+      get "/old" do |req, res|
+        redirect "/new", 301
+        "unreachable"
+      end
+
+      get "/items/:id" do |req, res|
+        halt 422 if req.params[:id] == "0"
+        "item"
+      end
+    end
+    app = App.new
+    denied = dispatch(app, "GET", "/admin/stats")
+    moved = dispatch(app, "GET", "/old")
+    invalid = dispatch(app, "GET", "/items/0")
+    [
+      "#{denied.status_code} #{denied.body} #{denied.headers["www-authenticate"]}",
+      dispatch(app, "GET", "/admin/stats", { "authorization" => "token" }).body,
+      "#{moved.status_code} #{moved.headers["Location"]} [#{moved.body}]",
+      "#{invalid.status_code} [#{invalid.body}]",
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "401 unauthorized Bearer|stats|301 /new []|422 []"
+    );
+
+    // The Location is checked the same way as in res.redirect
+    let injected = r##"
+    class App < Uzumibi::Router
+      get "/inject" do |req, res|
+        redirect "/next\r\nSet-Cookie: a=b"
+      end
+    end
+    res = dispatch(App.new, "GET", "/inject")
+    "#{res.status_code} #{res.headers["Location"].inspect}"
+    "##;
+    assert_eq!(run_script(injected)?, "500 nil");
+
+    // The pending halt lives on the request, so there is none outside one
+    let outside = r##"
+    class App < Uzumibi::Router
+    end
+    App.halt 418
+    "##;
+    assert!(run_script(outside).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_route_table() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class Admin < Uzumibi::Router
+      get "/stats" do |req, res|
+        "stats"
+      end
+    end
+
+    class App < Uzumibi::Router
+      get "/users/:id", as: :user do |req, res|
+        "user"
+      end
+
+      head "/ping" do |req, res|
+        res
+      end
+
+      any "/echo" do |req, res|
+        "echo"
+      end
+
+      mount Admin, at: "/admin"
+    end
+    App.route_table.map do |(method, pattern, name, options)|
+      "#{method} #{pattern} #{name.inspect} #{options.size}"
+    end.join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "GET /users/:id :user 0|HEAD /ping nil 0|ANY /echo nil 0|GET /admin/stats nil 0"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_host_and_header_constraints() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      host "api.example.com" do
+        get "/" do |req, res|
+          "api"
+        end
+      end
+
+      constraints subdomain: "admin" do
+        get "/" do |req, res|
+          "admin"
+        end
+      end
+
+      get "/" do |req, res|
+        "www"
+      end
+
+      get "/data", constraints: { accept: ->(v) { v.to_s.include?("json") } } do |req, res|
+        "json"
+      end
+
+      get "/data", constraints: { x_api_version: ["2", "3"] } do |req, res|
+        "v2"
+      end
+
+      get "/api-only", constraints: { host: "api.example.com" } do |req, res|
+        "api only"
+      end
+    end
+    app = App.new
+    [
+      dispatch(app, "GET", "/", { "host" => "API.example.com:8787" }).body,
+      dispatch(app, "GET", "/", { "host" => "admin.example.com" }).body,
+      dispatch(app, "GET", "/", { "host" => "www.example.com" }).body,
+      dispatch(app, "GET", "/data", { "accept" => "application/json" }).body,
+      dispatch(app, "GET", "/data", { "x-api-version" => "3" }).body,
+      dispatch(app, "GET", "/data").status_code,
+      dispatch(app, "GET", "/api-only", { "host" => "www.example.com" }).status_code,
+      App.route_table.last[3][:constraints][:host],
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "api|admin|www|json|v2|404|404|api.example.com"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_respond_to() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/report" do |req, res|
+        respond_to(req) do |f|
+          f.json { { "rows" => 2 } }
+          f.html { "<p>2 rows</p>" }
+          f.text { "2 rows" }
+        end
+      end
+
+      get "/filled" do |req, res|
+        res.headers = { "vary" => "Origin" }
+        respond_to(req, res) do |f|
+          f.text { "plain" }
+        end
+      end
+    end
+    app = App.new
+    describe = ->(r) { "#{r.status_code} #{r.headers["Content-Type"]} #{r.headers["Vary"]} #{r.body}" }
+    [
+      describe.call(dispatch(app, "GET", "/report")),
+      describe.call(dispatch(app, "GET", "/report", { "accept" => "text/html,application/xml;q=0.9,*/*;q=0.8" })),
+      describe.call(dispatch(app, "GET", "/report", { "accept" => "application/json;q=0.2, text/*;q=0.5" })),
+      describe.call(dispatch(app, "GET", "/report", { "accept" => "image/png" })),
+      "#{dispatch(app, "GET", "/filled").headers["vary"]}",
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        [
+            r#"200 application/json; charset=utf-8 Accept {"rows":2}"#,
+            "200 text/html; charset=utf-8 Accept <p>2 rows</p>",
+            "200 text/html; charset=utf-8 Accept <p>2 rows</p>",
+            "406 text/plain; charset=utf-8 Accept Not Acceptable",
+            "Origin, Accept",
+        ]
+        .join("|")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_request_env() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class Auth
+      def call(req, res, app)
+        req.env[:user] = req.headers["x-user"] if req.headers["x-user"]
+        app.call(req, res)
+      end
+    end
+
+    class App < Uzumibi::Router
+      use Auth
+
+      before do |req, res|
+        req.context[:seen] = (req.env[:seen] || 0) + 1
+      end
+
+      error do |e, req, res|
+        res.return(500, {}, "failed for #{req.env[:user]}")
+      end
+
+      get "/me" do |req, res|
+        "#{req.env[:user] || "guest"} #{req.env[:seen]} #{req.env.size}"
+      end
+
+      get "/boom" do |req, res|
+        raise "boom"
+      end
+    end
+    app = App.new
+    [
+      dispatch(app, "GET", "/me", { "x-user" => "alice" }).body,
+      dispatch(app, "GET", "/me").body,
+      dispatch(app, "GET", "/boom", { "x-user" => "bob" }).body,
+    ].join("|")
+    "##;
+    assert_eq!(run_script(code)?, "alice 1 2|guest 1 1|failed for bob");
+    Ok(())
+}
+
+#[test]
+fn test_vm_snapshot_restores_state() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/count" do |req, res|
+        @count ||= 0
+        @count += 1
+        $seen << req.path
+        "#{@count} #{$seen.size}"
+      end
+
+      def self.count(app)
+        dispatch(app, "GET", "/count").body
+      end
+    end
+    $seen = []
+    $APP = App.new
+    "##;
+    let count = |vm: &mut VM| -> Result<String, mrubyedge::Error> {
+        let app = vm.globals.get("$APP").cloned().expect("$APP is set");
+        let klass = vm.get_const_by_name("App").expect("App is defined");
+        let body = mrb_funcall(vm, Some(klass), "count", &[app])?;
+        body.as_ref().try_into()
+    };
+
+    let (mut vm, _) = open_vm(code)?;
+    let snapshot = VmSnapshot::capture(&mut vm)?.expect("isolation is on by default");
+    assert_eq!(count(&mut vm)?, "1 1");
+    assert_eq!(count(&mut vm)?, "2 2");
+    snapshot.restore(&mut vm);
+    assert_eq!(count(&mut vm)?, "1 1");
+    snapshot.restore(&mut vm);
+    assert_eq!(count(&mut vm)?, "1 1");
+
+    let (mut vm, _) = open_vm(&format!("{}\nApp.isolate_requests false", code))?;
+    assert!(VmSnapshot::capture(&mut vm)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_streamed_body() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/export.csv" do |req, res|
+        res.headers = { "Content-Type" => "text/csv" }
+        res.stream do |out|
+          out << "id,name\n"
+          3.times { |i| out << "#{i},user#{i}\n" }
+          out.write("")
+        end
+      end
+
+      get "/tokens" do |req, res|
+        res.status_code = 200
+        res.headers = {}
+        res.body = ["Hello", ", ", "world"]
+        res
+      end
+
+      get "/halted" do |req, res|
+        res.stream { |out| out << "never" }
+        halt 403, "Forbidden"
+      end
+
+      def self.fetch(app, method, path)
+        dispatch(app, method, path)
+      end
+    end
+    $APP = App.new
+    "##;
+    let (mut vm, _) = open_vm(code)?;
+    let mut chunks_of = |method: &str, path: &str| -> Result<Vec<String>, mrubyedge::Error> {
+        let app = vm.globals.get("$APP").cloned().expect("$APP is set");
+        let klass = vm.get_const_by_name("App").expect("App is defined");
+        let args = [
+            app,
+            RObject::string(method.to_string()).to_refcount_assigned(),
+            RObject::string(path.to_string()).to_refcount_assigned(),
+        ];
+        let response = mrb_funcall(&mut vm, Some(klass), "fetch", &args)?;
+        if !uzumibi_response_is_streaming(&response) {
+            let body = mrb_funcall(&mut vm, Some(response), "body", &[])?;
+            return Ok(vec![body.as_ref().try_into()?]);
+        }
+        let chunks = Rc::new(RefCell::new(Vec::new()));
+        let sink = chunks.clone();
+        uzumibi_response_stream(
+            &mut vm,
+            &response,
+            Box::new(move |chunk| {
+                sink.borrow_mut()
+                    .push(String::from_utf8_lossy(chunk).into_owned());
+                Ok(())
+            }),
+        )?;
+        assert!(!uzumibi_response_is_streaming(&response));
+        Ok(chunks.take())
+    };
+
+    assert_eq!(
+        chunks_of("GET", "/export.csv")?,
+        vec!["id,name\n", "0,user0\n", "1,user1\n", "2,user2\n"]
+    );
+    assert_eq!(chunks_of("GET", "/tokens")?, vec!["Hello", ", ", "world"]);
+    assert_eq!(chunks_of("HEAD", "/export.csv")?, vec![""]);
+    assert_eq!(chunks_of("GET", "/halted")?, vec!["Forbidden"]);
+    Ok(())
+}
+
+#[test]
+fn test_server_sent_events() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/events" do |req, res|
+        res.sse do |stream|
+          stream.event("update", data: "line 1\nline 2", id: 1)
+          stream.event(data: "plain")
+          stream.comment("tick")
+          stream.heartbeat
+          stream.event("done", data: "", retry: 5000)
+        end
+      end
+
+      get "/quiet" do |req, res|
+        res.sse(heartbeat: false) { |stream| stream.event(data: "x") }
+      end
+    end
+    app = App.new
+    res = dispatch(app, "GET", "/events")
+    $events = res
+    $quiet = dispatch(app, "GET", "/quiet")
+    [
+      res.headers["Content-Type"],
+      res.headers["Cache-Control"],
+      res.headers["X-Accel-Buffering"],
+      res.status_code,
+    ].join("|")
+    "##;
+    let (mut vm, ret) = open_vm(code)?;
+    let head: String = ret.as_ref().try_into()?;
+    assert_eq!(head, "text/event-stream; charset=utf-8|no-cache|no|200");
+
+    let response = vm.globals.get("$events").cloned().expect("$events is set");
+    assert_eq!(uzumibi_response_heartbeat_interval(&response), Some(15));
+    let chunks = Rc::new(RefCell::new(Vec::new()));
+    let sink = chunks.clone();
+    uzumibi_response_stream(
+        &mut vm,
+        &response,
+        Box::new(move |chunk| {
+            sink.borrow_mut()
+                .push(String::from_utf8_lossy(chunk).into_owned());
+            Ok(())
+        }),
+    )?;
+    assert_eq!(
+        chunks.take(),
+        vec![
+            "event: update\nid: 1\ndata: line 1\ndata: line 2\n\n",
+            "data: plain\n\n",
+            ": tick\n\n",
+            ": heartbeat\n\n",
+            "event: done\nretry: 5000\ndata: \n\n",
+        ]
+    );
+
+    let quiet = vm.globals.get("$quiet").cloned().expect("$quiet is set");
+    assert!(uzumibi_response_is_streaming(&quiet));
+    assert_eq!(uzumibi_response_heartbeat_interval(&quiet), None);
+    Ok(())
+}
+
+#[test]
+fn test_websocket() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      websocket "/ws" do |ws, req|
+        ws.state[:count] = 0
+        ws.send("welcome #{req.path}")
+        ws.on_open { |ws| ws.send("open") }
+        ws.on_message do |msg, ws|
+          ws.state[:count] += 1
+          if msg == "bye"
+            ws.close(4000, "done")
+          else
+            ws.send("#{ws.state[:count]}: #{msg}")
+          end
+        end
+        ws.on_close { |code, reason, ws| $closed = "#{code} #{reason} #{ws.request.path}" }
+      end
+    end
+    app = App.new
+    $upgrade = dispatch(app, "GET", "/ws", { "upgrade" => "WebSocket" })
+    plain = dispatch(app, "GET", "/ws")
+    [
+      $upgrade.status_code,
+      $upgrade.headers["Upgrade"],
+      $upgrade.headers["Connection"],
+      plain.status_code,
+      plain.headers["Upgrade"],
+      plain.body,
+    ].join("|")
+    "##;
+    let (mut vm, ret) = open_vm(code)?;
+    let head: String = ret.as_ref().try_into()?;
+    assert_eq!(head, "101|websocket|Upgrade|426|websocket|Upgrade Required");
+
+    let response = vm
+        .globals
+        .get("$upgrade")
+        .cloned()
+        .expect("$upgrade is set");
+    let ws = uzumibi_response_websocket(&response).expect("the route accepted the upgrade");
+    assert_eq!(
+        uzumibi_websocket_open(&mut vm, &ws)?,
+        vec![
+            WebSocketMessage::Text("welcome /ws".to_string()),
+            WebSocketMessage::Text("open".to_string()),
+        ]
+    );
+    assert_eq!(
+        uzumibi_websocket_receive(&mut vm, &ws, WebSocketMessage::Text("hi".to_string()))?,
+        vec![WebSocketMessage::Text("1: hi".to_string())]
+    );
+    assert_eq!(
+        uzumibi_websocket_receive(&mut vm, &ws, WebSocketMessage::Binary(b"yo".to_vec()))?,
+        vec![WebSocketMessage::Text("2: yo".to_string())]
+    );
+    assert_eq!(
+        uzumibi_websocket_receive(&mut vm, &ws, WebSocketMessage::Text("bye".to_string()))?,
+        vec![WebSocketMessage::Close(4000, "done".to_string())]
+    );
+    assert!(
+        uzumibi_websocket_receive(
+            &mut vm,
+            &ws,
+            WebSocketMessage::Close(1000, "ok".to_string())
+        )?
+        .is_empty()
+    );
+    let closed: String = vm
+        .globals
+        .get("$closed")
+        .cloned()
+        .expect("on_close ran")
+        .as_ref()
+        .try_into()?;
+    assert_eq!(closed, "1000 ok /ws");
+    Ok(())
+}
+
+#[test]
+fn test_set_and_delete_cookies() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/set" do |req, res|
+        res.set_cookie("theme", "dark mode; v=2", max_age: 60, http_only: true)
+        res.set_cookie("lang", "ja", path: "/docs", domain: "example.com",
+          expires: 0, secure: true, same_site: :none)
+        res.set_cookie("theme", "light")
+        res.delete_cookie("old")
+        "ok"
+      end
+
+      get "/read" do |req, res|
+        req.cookie["theme"]
+      end
+
+      get "/invalid" do |req, res|
+        res.set_cookie("bad name", "x")
+        "ok"
+      end
+
+      get "/insecure" do |req, res|
+        res.set_cookie("a", "b", same_site: "None")
+        "ok"
+      end
+    end
+
+    app = App.new
+    set = dispatch(app, "GET", "/set")
+    read = dispatch(app, "GET", "/read", { "Cookie" => "theme=dark%20mode%3B%20v=2; lang=ja" })
+    [
+      set.headers["Set-Cookie"].join("\n"),
+      read.body,
+      dispatch(app, "GET", "/invalid").status_code,
+      dispatch(app, "GET", "/insecure").status_code,
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        [
+            "lang=ja; Path=/docs; Domain=example.com; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Secure; SameSite=None",
+            "theme=light; Path=/",
+            "old=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0|dark mode; v=2|500|500",
+        ]
+        .join("\n")
+    );
+
+    // Hosts send each cookie as its own header
+    let (_vm, app) = open_vm(
+        r##"
+    class App < Uzumibi::Router
+      get "/" do |req, res|
+        res.set_cookie("a", "1")
+        res.set_cookie("b", "2")
+        "ok"
+      end
+    end
+    dispatch(App.new, "GET", "/")
+    "##,
+    )?;
+    let headers = uzumibi_response_header_pairs(&app)?;
+    assert_eq!(
+        headers
+            .iter()
+            .filter(|(key, _)| key == "Set-Cookie")
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<_>>(),
+        ["a=1; Path=/", "b=2; Path=/"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_repeated_headers() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/" do |req, res|
+        res.headers = { "Link" => ["</app.css>; rel=preload", "</app.js>; rel=preload"] }
+        res.headers["Vary"] = ["Accept-Encoding", "Origin"]
+        [
+          req.headers["accept"],
+          req.header_values("Accept").inspect,
+          req.cookie["a"] + req.cookie["b"],
+        ].join("|")
+      end
+    end
+    dispatch(App.new, "GET", "/", {
+      "accept" => ["text/html", "application/json;q=0.9"],
+      "cookie" => ["a=1", "b=2"],
+    })
+    "##;
+    let (_vm, res) = open_vm(code)?;
+    let body: String = res.get_ivar("@body").as_ref().try_into()?;
+    assert_eq!(
+        body,
+        r#"text/html, application/json;q=0.9|["text/html", "application/json;q=0.9"]|12"#
+    );
+    // Hash order between headers is not fixed; values of one header are
+    let mut pairs = uzumibi_response_header_pairs(&res)?;
+    pairs.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        pairs,
+        [
+            ("Content-Type", "text/plain; charset=utf-8"),
+            ("Link", "</app.css>; rel=preload"),
+            ("Link", "</app.js>; rel=preload"),
+            ("Vary", "Accept-Encoding"),
+            ("Vary", "Origin"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+    );
+
+    // The request wire format keeps repeated headers in order
+    let mut buf = b"GET\0\0\0".to_vec();
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(b"/");
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    buf.extend_from_slice(&3u16.to_le_bytes());
+    for (key, value) in [("x-a", "1"), ("X-B", "2"), ("X-A", "3")] {
+        buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    let request = uzumibi_gem::request::Request::new_from_buffer(&buf);
+    assert_eq!(request.header_values("x-a"), ["1", "3"]);
+    assert_eq!(request.header("X-b"), Some("2"));
+    Ok(())
+}
+
+#[test]
+fn test_signed_sessions() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      enable_sessions secret: "0123456789abcdef0123456789abcdef", max_age: 3600
+
+      get "/login" do |req, res|
+        req.session[:user_id] = 42
+        req.session["roles"] = [:admin, "ops"]
+        "ok"
+      end
+
+      get "/me" do |req, res|
+        "#{req.session[:user_id].inspect} #{req.session["roles"].inspect}"
+      end
+
+      get "/logout" do |req, res|
+        req.session.clear
+        "bye"
+      end
+    end
+
+    class Sealed < Uzumibi::Router
+      enable_sessions secret: "0123456789abcdef0123456789abcdef", key: "s", encrypt: true,
+        secure: true, same_site: "Strict"
+      get "/" do |req, res|
+        req.session[:secret] = "plaintext"
+        "ok"
+      end
+    end
+
+    def cookie_of(res)
+      res.headers["Set-Cookie"].to_s.split(";").first
+    end
+
+    app = App.new
+    login = dispatch(app, "GET", "/login")
+    cookie = cookie_of(login)
+    me = dispatch(app, "GET", "/me", { "cookie" => cookie })
+    tampered = dispatch(app, "GET", "/me", { "cookie" => cookie + "A" })
+    logout = dispatch(app, "GET", "/logout", { "cookie" => cookie })
+    anonymous = dispatch(app, "GET", "/me")
+
+    sealed = dispatch(Sealed.new, "GET", "/")
+    [
+      login.headers["Set-Cookie"].include?("; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax"),
+      me.body,
+      me.headers["Set-Cookie"].inspect,
+      tampered.body,
+      logout.headers["Set-Cookie"].start_with?("uzumibi.session=; Path=/; Expires="),
+      anonymous.headers["Set-Cookie"].inspect,
+      sealed.headers["Set-Cookie"].start_with?("s="),
+      sealed.headers["Set-Cookie"].include?("; Secure; HttpOnly; SameSite=Strict"),
+    ].join("|")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        "true|42 [:admin, \"ops\"]|nil|nil nil|true|nil|true|true"
+    );
+
+    let short_secret = r##"
+    class App < Uzumibi::Router
+      enable_sessions secret: "short"
+    end
+    "##;
+    assert!(run_script(short_secret).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_binary_body_round_trip() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      post "/echo" do |req, res|
+        res.return(200, { "content-type" => "application/octet-stream" }, req.raw_body)
+      end
+    end
+    App.new
+    "##;
+    let (mut vm, app) = open_vm(code)?;
+
+    // Every byte value, including NUL and invalid UTF-8 sequences
+    let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut body: Vec<u8> = (0..=255).collect();
+    body.extend((0..4096).map(|_| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as u8
+    }));
+
+    // Request wire format -> Uzumibi::Request
+    let mut buf = b"POST\0\0".to_vec();
+    buf.extend_from_slice(&5u16.to_le_bytes());
+    buf.extend_from_slice(b"/echo");
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    for (key, value) in [("content-type", "application/octet-stream")] {
+        buf.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&body);
+    let request = uzumibi_gem::request::Request::new_from_buffer(&buf);
+    assert_eq!(request.body, body);
+    let request = request.into_robject(&mut vm);
+
+    // Uzumibi::Response -> response wire format
+    mrb_funcall(&mut vm, Some(app.clone()), "set_request", &[request])?;
+    let memory = mrb_funcall(
+        &mut vm,
+        Some(app),
+        "start_request_and_return_shared_memory",
+        &[],
+    )?;
+    let packed = match &memory.value {
+        RValue::SharedMemory(sm) => sm.borrow().memory.as_ref().to_vec(),
+        _ => panic!("expected SharedMemory"),
+    };
+    let header_count = u16::from_le_bytes([packed[2], packed[3]]);
+    let mut offset = 4;
+    for _ in 0..header_count * 2 {
+        offset += 2 + u16::from_le_bytes([packed[offset], packed[offset + 1]]) as usize;
+    }
+    let body_size = u32::from_le_bytes(packed[offset..offset + 4].try_into().unwrap()) as usize;
+    assert_eq!(body_size, body.len());
+    assert_eq!(&packed[offset + 4..offset + 4 + body_size], body.as_slice());
+    Ok(())
+}
+
+#[test]
+fn test_response_builders() -> Result<(), mrubyedge::Error> {
+    let code = r##"
+    class App < Uzumibi::Router
+      get "/text" do |req, res|
+        res.text("héllo", status: 201)
+      end
+
+      get "/html" do |req, res|
+        res.headers = { "content-type" => "text/plain", "X-Kept" => "1" }
+        res.html("<p>hi</p>")
+      end
+
+      get "/data" do |req, res|
+        res.send_data("PNG", type: "image/png", filename: "a b.png")
+      end
+
+      get "/inline" do |req, res|
+        res.send_data("%PDF", type: "application/pdf", disposition: "inline")
+      end
+
+      get "/moved" do |req, res|
+        res.redirect("/new", status: 301)
+      end
+
+      delete "/item" do |req, res|
+        res.text("gone")
+        res.no_content
+      end
+    end
+
+    def summary(res)
+      [
+        res.status_code,
+        res.headers["Content-Type"] || res.headers["content-type"],
+        res.headers["Content-Length"],
+        res.headers["Content-Disposition"],
+        res.headers["Location"],
+        res.headers["X-Kept"],
+      ].join("|")
+    end
+
+    app = App.new
+    [
+      summary(dispatch(app, "GET", "/text")),
+      summary(dispatch(app, "GET", "/html")),
+      summary(dispatch(app, "GET", "/data")),
+      summary(dispatch(app, "GET", "/inline")),
+      summary(dispatch(app, "GET", "/moved")),
+      summary(dispatch(app, "DELETE", "/item")),
+    ].join("\n")
+    "##;
+    assert_eq!(
+        run_script(code)?,
+        [
+            "201|text/plain; charset=utf-8|6|||",
+            "200|text/html; charset=utf-8|9|||1",
+            "200|image/png|3|attachment; filename=\"a b.png\"||",
+            "200|application/pdf|4|inline||",
+            "301||0||/new|",
+            "204|||||",
+        ]
+        .join("\n")
+    );
+
+    let bad_status = r##"
+    class App < Uzumibi::Router
+      get "/" do |req, res|
+        res.redirect("/", status: 200)
+      end
+    end
+    dispatch(App.new, "GET", "/").status_code.to_s
+    "##;
+    assert_eq!(run_script(bad_status)?, "500");
+    Ok(())
+}